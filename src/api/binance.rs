@@ -1,4 +1,5 @@
 #![allow(non_snake_case, dead_code)]
+use crate::api::rate_limit;
 use crate::api::{Market, ConceptualOrder};
 use anyhow::Result;
 use chrono::Utc;
@@ -52,51 +53,243 @@ pub async fn signed_request(
 
 	let url = format!("{}?{}&signature={}", endpoint_str, query_string, signature);
 
-	let r = match http_method {
-		HttpMethod::GET => client.get(&url).send().await?,
-		HttpMethod::POST => client.post(&url).send().await?,
-		_ => panic!("Not implemented"),
+	let method = match http_method {
+		HttpMethod::GET => reqwest::Method::GET,
+		HttpMethod::POST => reqwest::Method::POST,
+		HttpMethod::PUT => reqwest::Method::PUT,
+		HttpMethod::DELETE => reqwest::Method::DELETE,
 	};
-	Ok(r)
+	let request = client.request(method, &url).build()?;
+	rate_limit::send(&client, request).await
 }
 
 /// All the iteractions with submitting orders use this
-pub enum BinanceOrder {
+pub enum BinanceOrderType {
 	Market,
 	Limit,
+	StopMarket,
 	StopLoss,
 	StopLossLimit,
 	TakeProfit,
 	TakeProfitLimit,
 	LimitMaker,
 }
-impl From<ConceptualOrder> for BinanceOrder {
-	fn from(order_type: ConceptualOrder) -> Self {
-		match order_type {
-			ConceptualOrder::Market(_) => unimplemented!(),
-			ConceptualOrder::Limit(_) => unimplemented!(),
-			ConceptualOrder::StopMarket(_) => unimplemented!(),
-			//OrderType::StopLimit(_) => unimplemented!(),
-			//OrderType::TakeProfit(_) => unimplemented!(),
-			//OrderType::TakeProfitLimit(_) => unimplemented!(),
-			//OrderType::LimitMaker(_) => unimplemented!(),
+impl ToString for BinanceOrderType {
+	fn to_string(&self) -> String {
+		match self {
+			BinanceOrderType::Market => "MARKET".to_string(),
+			BinanceOrderType::Limit => "LIMIT".to_string(),
+			BinanceOrderType::StopMarket => "STOP_MARKET".to_string(),
+			BinanceOrderType::StopLoss => "STOP_LOSS".to_string(),
+			BinanceOrderType::StopLossLimit => "STOP_LOSS_LIMIT".to_string(),
+			BinanceOrderType::TakeProfit => "TAKE_PROFIT".to_string(),
+			BinanceOrderType::TakeProfitLimit => "TAKE_PROFIT_LIMIT".to_string(),
+			BinanceOrderType::LimitMaker => "LIMIT_MAKER".to_string(),
 		}
 	}
 }
-impl ToString for BinanceOrder {
-	fn to_string(&self) -> String {
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeInForce {
+	Gtc,
+	Ioc,
+	/// Post-only; expires instead of taking.
+	Gtx,
+}
+impl std::fmt::Display for TimeInForce {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		match self {
-			BinanceOrder::Market => "MARKET".to_string(),
-			BinanceOrder::Limit => "LIMIT".to_string(),
-			BinanceOrder::StopLoss => "STOP_LOSS".to_string(),
-			BinanceOrder::StopLossLimit => "STOP_LOSS_LIMIT".to_string(),
-			BinanceOrder::TakeProfit => "TAKE_PROFIT".to_string(),
-			BinanceOrder::TakeProfitLimit => "TAKE_PROFIT_LIMIT".to_string(),
-			BinanceOrder::LimitMaker => "LIMIT_MAKER".to_string(),
+			TimeInForce::Gtc => write!(f, "GTC"),
+			TimeInForce::Ioc => write!(f, "IOC"),
+			TimeInForce::Gtx => write!(f, "GTX"),
 		}
 	}
 }
 
+/// Trading rules of a futures symbol, from the filters in exchangeInfo.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SymbolRules {
+	pub tick_size: f64,
+	pub step_size: f64,
+	/// Quantity step of MARKET orders, which can be coarser.
+	pub market_step_size: f64,
+	pub min_qty: f64,
+	pub min_notional: f64,
+	price_decimals: usize,
+	qty_decimals: usize,
+}
+impl SymbolRules {
+	fn from_filters(filters: &[Value]) -> Result<Self> {
+		let field = |filter_type: &str, name: &str| -> Option<String> {
+			filters
+				.iter()
+				.find(|f| f.get("filterType").and_then(|t| t.as_str()) == Some(filter_type))
+				.and_then(|f| f.get(name))
+				.and_then(|v| v.as_str())
+				.map(|v| v.to_owned())
+		};
+		let tick = field("PRICE_FILTER", "tickSize").ok_or_else(|| anyhow::anyhow!("No PRICE_FILTER.tickSize"))?;
+		let step = field("LOT_SIZE", "stepSize").ok_or_else(|| anyhow::anyhow!("No LOT_SIZE.stepSize"))?;
+		let market_step = field("MARKET_LOT_SIZE", "stepSize").unwrap_or_else(|| step.clone());
+		Ok(Self {
+			tick_size: tick.parse()?,
+			step_size: step.parse()?,
+			market_step_size: market_step.parse()?,
+			min_qty: field("LOT_SIZE", "minQty").unwrap_or_else(|| "0".to_owned()).parse()?,
+			min_notional: field("MIN_NOTIONAL", "notional").unwrap_or_else(|| "0".to_owned()).parse()?,
+			price_decimals: decimals(&tick),
+			qty_decimals: decimals(&step).max(decimals(&market_step)),
+		})
+	}
+
+	/// Quantities are only ever rounded down, so that what is sent never exceeds what was asked for.
+	pub fn floor_qty(&self, qty: f64, market: bool) -> f64 {
+		let step = if market { self.market_step_size } else { self.step_size };
+		self.clean_qty(((qty / step) + 1e-9).floor() * step)
+	}
+
+	/// Rounds to the tick in the direction that is never worse for the order's `side`: down for buys, up for sells.
+	pub fn passive_price(&self, price: f64, side: &Side) -> f64 {
+		let ticks = price / self.tick_size;
+		let ticks = match side {
+			Side::Buy => (ticks + 1e-9).floor(),
+			Side::Sell => (ticks - 1e-9).ceil(),
+		};
+		self.clean_price(ticks * self.tick_size)
+	}
+
+	pub fn nearest_price(&self, price: f64) -> f64 {
+		self.clean_price((price / self.tick_size).round() * self.tick_size)
+	}
+
+	pub fn format_price(&self, price: f64) -> String {
+		format!("{:.*}", self.price_decimals, price)
+	}
+
+	pub fn format_qty(&self, qty: f64) -> String {
+		format!("{:.*}", self.qty_decimals, qty)
+	}
+
+	pub fn qty_decimals(&self) -> usize {
+		self.qty_decimals
+	}
+
+	// multiples of a step carry float noise otherwise
+	fn clean_price(&self, price: f64) -> f64 {
+		self.format_price(price).parse().unwrap()
+	}
+
+	fn clean_qty(&self, qty: f64) -> f64 {
+		self.format_qty(qty).parse().unwrap()
+	}
+}
+
+/// Decimal places of a step as Binance writes it, ex: `"0.00100000"` -> 3.
+fn decimals(step: &str) -> usize {
+	match step.split_once('.') {
+		Some((_, fraction)) => fraction.trim_end_matches('0').len(),
+		None => 0,
+	}
+}
+
+/// Order exactly as it is sent to `/fapi/v1/order`, already rounded to the symbol's rules.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BinanceOrder {
+	pub symbol: String,
+	pub side: Side,
+	pub position_side: PositionSide,
+	pub order_type: String,
+	pub quantity: f64,
+	pub price: Option<f64>,
+	pub stop_price: Option<f64>,
+	pub time_in_force: Option<TimeInForce>,
+	pub reduce_only: bool,
+}
+impl BinanceOrder {
+	/// `reference_price` is what slippage of market orders is bounded against; these are sent as plain MARKET without it.
+	/// `maximum_slippage_percent` is taken as a fraction of it, 1.0 and over meaning unbounded.
+	/// Stops are sent reduce-only unless `shared_leg`: opposite positions on one one-way leg, where what closes one of them adds to the other.
+	pub fn translate(order: &ConceptualOrder, position_side: PositionSide, rules: &SymbolRules, reference_price: Option<f64>, shared_leg: bool) -> Result<Self> {
+		let mut translated = Self {
+			symbol: order.symbol().to_string(),
+			side: order.side().clone(),
+			position_side,
+			order_type: BinanceOrderType::Market.to_string(),
+			quantity: 0.0,
+			price: None,
+			stop_price: None,
+			time_in_force: None,
+			reduce_only: false,
+		};
+		match order {
+			ConceptualOrder::Market(m) => match reference_price {
+				Some(reference) if m.maximum_slippage_percent < 1.0 => {
+					let bound = match m.side {
+						Side::Buy => reference * (1.0 + m.maximum_slippage_percent),
+						Side::Sell => reference * (1.0 - m.maximum_slippage_percent),
+					};
+					translated.order_type = BinanceOrderType::Limit.to_string();
+					translated.price = Some(rules.passive_price(bound, &m.side));
+					translated.time_in_force = Some(TimeInForce::Ioc);
+					translated.quantity = rules.floor_qty(m.qty_notional, false);
+				}
+				_ => translated.quantity = rules.floor_qty(m.qty_notional, true),
+			},
+			ConceptualOrder::Limit(l) => {
+				translated.order_type = BinanceOrderType::Limit.to_string();
+				translated.price = Some(rules.passive_price(l.price, &l.side));
+				translated.time_in_force = Some(if l.limit_only { TimeInForce::Gtx } else { TimeInForce::Gtc });
+				translated.quantity = rules.floor_qty(l.qty_notional, false);
+			}
+			ConceptualOrder::StopMarket(s) => {
+				translated.order_type = BinanceOrderType::StopMarket.to_string();
+				translated.stop_price = Some(rules.nearest_price(s.price));
+				translated.reduce_only = !shared_leg;
+				translated.quantity = rules.floor_qty(s.qty_notional, true);
+			}
+		}
+
+		if translated.quantity < rules.min_qty || translated.quantity <= 0.0 {
+			anyhow::bail!("{} is under the minimum quantity of {} for {}", order.notional(), rules.min_qty, translated.symbol);
+		}
+		// not enforced on reduce-only orders
+		if let Some(price) = translated.price.or(translated.stop_price).or(reference_price) {
+			if !translated.reduce_only && translated.quantity * price < rules.min_notional {
+				anyhow::bail!("{:.2} USDT is under the minimum notional of {} for {}", translated.quantity * price, rules.min_notional, translated.symbol);
+			}
+		}
+		Ok(translated)
+	}
+
+	pub fn to_params(&self, rules: &SymbolRules) -> HashMap<&'static str, String> {
+		let mut params = HashMap::<&'static str, String>::new();
+		params.insert("symbol", self.symbol.clone());
+		params.insert("side", self.side.to_string());
+		params.insert("type", self.order_type.clone());
+		params.insert("quantity", rules.format_qty(self.quantity));
+		if let Some(price) = self.price {
+			params.insert("price", rules.format_price(price));
+		}
+		if let Some(stop_price) = self.stop_price {
+			params.insert("stopPrice", rules.format_price(stop_price));
+		}
+		if let Some(time_in_force) = self.time_in_force {
+			params.insert("timeInForce", time_in_force.to_string());
+		}
+		match self.position_side {
+			// not accepted in hedge mode, where the leg alone makes it reducing
+			PositionSide::Both =>
+				if self.reduce_only {
+					params.insert("reduceOnly", "true".to_owned());
+				},
+			leg => {
+				params.insert("positionSide", leg.to_string());
+			}
+		}
+		params
+	}
+}
+
 pub async fn get_balance(key: String, secret: String, market: Market) -> Result<f64> {
 	let params = HashMap::<&str, String>::new();
 	match market {
@@ -154,7 +347,7 @@ pub async fn futures_price(asset: &str) -> Result<f64> {
 	params.insert("symbol", symbol.to_string());
 
 	let client = reqwest::Client::new();
-	let r = client.get(url).json(&params).send().await?;
+	let r = rate_limit::send(&client, client.get(url).json(&params).build()?).await?;
 	//let r_json: serde_json::Value = r.json().await?;
 	//let price = r_json.get("price").unwrap().as_str().unwrap().parse::<f64>()?;
 	// for some reason, can't sumbit with the symbol, so effectively requesting all for now
@@ -172,36 +365,152 @@ pub async fn futures_price(asset: &str) -> Result<f64> {
 	Ok(price)
 }
 
-pub async fn get_futures_positions(key: String, secret: String) -> Result<HashMap<String, f64>> {
+pub async fn futures_mark_price(asset: &str) -> Result<f64> {
+	let symbol = format!("{}USDT", asset.to_uppercase());
+	let base_url = Market::BinanceFutures.get_base_url();
+	let mut url = base_url.join("/fapi/v1/premiumIndex")?;
+	url.query_pairs_mut().append_pair("symbol", &symbol);
+
+	let r = rate_limit::get(url).await?;
+	let premium_index: Value = r.json().await?;
+	let mark_price = premium_index
+		.get("markPrice")
+		.and_then(|p| p.as_str())
+		.ok_or_else(|| anyhow::anyhow!("No markPrice in response for {}", symbol))?
+		.parse::<f64>()?;
+	Ok(mark_price)
+}
+
+/// Whether the account holds one net position per symbol, or a separate long and short.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PositionMode {
+	OneWay,
+	Hedge,
+}
+
+/// Leg of the exchange position an order goes to. Always `Both` in one-way mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum PositionSide {
+	Both,
+	Long,
+	Short,
+}
+impl PositionSide {
+	/// Leg that a position entered with `side` lives on.
+	pub fn of_position(mode: PositionMode, side: &Side) -> Self {
+		match (mode, side) {
+			(PositionMode::OneWay, _) => PositionSide::Both,
+			(PositionMode::Hedge, Side::Buy) => PositionSide::Long,
+			(PositionMode::Hedge, Side::Sell) => PositionSide::Short,
+		}
+	}
+}
+impl std::fmt::Display for PositionSide {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			PositionSide::Both => write!(f, "BOTH"),
+			PositionSide::Long => write!(f, "LONG"),
+			PositionSide::Short => write!(f, "SHORT"),
+		}
+	}
+}
+
+pub async fn get_position_mode(key: String, secret: String) -> Result<PositionMode> {
+	#[derive(Deserialize)]
+	struct DualSidePosition {
+		dualSidePosition: bool,
+	}
+	let url = Market::BinanceFutures.get_base_url().join("/fapi/v1/positionSide/dual")?;
+	let r = signed_request(HttpMethod::GET, url.as_str(), HashMap::new(), key, secret).await?;
+	let mode: DualSidePosition = r.json().await?;
+	Ok(match mode.dualSidePosition {
+		true => PositionMode::Hedge,
+		false => PositionMode::OneWay,
+	})
+}
+
+/// Signed `positionAmt` of every leg, keyed by symbol and the leg. Shorts are negative in both modes.
+pub async fn get_futures_positions(key: String, secret: String) -> Result<HashMap<(String, PositionSide), f64>> {
 	let url = FuturesAllPositionsResponse::get_url();
 
 	let r = signed_request(HttpMethod::GET, url.as_str(), HashMap::new(), key, secret).await?;
 	let positions: Vec<FuturesAllPositionsResponse> = r.json().await?;
 
-	let mut positions_map = HashMap::<String, f64>::new();
+	let mut positions_map = HashMap::<(String, PositionSide), f64>::new();
 	for position in positions {
-		let symbol = position.symbol.clone();
+		let leg = (position.symbol.clone(), position.positionSide);
 		let qty = position.positionAmt.parse::<f64>()?;
-		positions_map.entry(symbol).and_modify(|e| *e += qty).or_insert(qty);
+		positions_map.entry(leg).and_modify(|e| *e += qty).or_insert(qty);
 	}
 	Ok(positions_map)
 }
 
+/// All currently open orders on futures, across all symbols.
+pub async fn get_futures_open_orders(key: String, secret: String) -> Result<Vec<FuturesPositionResponse>> {
+	let base_url = Market::BinanceFutures.get_base_url();
+	let url = base_url.join("/fapi/v1/openOrders")?;
+
+	let r = signed_request(HttpMethod::GET, url.as_str(), HashMap::new(), key, secret).await?;
+	let orders: Vec<FuturesPositionResponse> = r.json().await?;
+	Ok(orders)
+}
+
 pub async fn futures_quantity_precision(coin: &str) -> Result<usize> {
 	let base_url = Market::BinanceFutures.get_base_url();
 	let url = base_url.join("/fapi/v1/exchangeInfo")?;
 	let symbol_str = format!("{}USDT", coin.to_uppercase());
 
-	let r = reqwest::get(url).await?;
+	let r = rate_limit::get(url).await?;
 	let futures_exchange_info: FuturesExchangeInfo = r.json().await?;
 	let symbol_info = futures_exchange_info.symbols.iter().find(|x| x.symbol == symbol_str).unwrap();
 
 	Ok(symbol_info.quantityPrecision)
 }
 
+/// Hands the request-weight and order-count limits of futures over to the rate limiter.
+pub async fn load_futures_rate_limits() -> Result<()> {
+	let url = Market::BinanceFutures.get_base_url().join("/fapi/v1/exchangeInfo")?;
+	let r = rate_limit::get(url.clone()).await?;
+	let futures_exchange_info: FuturesExchangeInfo = r.json().await?;
+	rate_limit::limiter().set_limits(url.host_str().unwrap_or_default(), &futures_exchange_info.rateLimits);
+	Ok(())
+}
+
+pub async fn futures_symbol_rules(symbol: &str) -> Result<SymbolRules> {
+	let base_url = Market::BinanceFutures.get_base_url();
+	let url = base_url.join("/fapi/v1/exchangeInfo")?;
+
+	let r = rate_limit::get(url).await?;
+	let futures_exchange_info: FuturesExchangeInfo = r.json().await?;
+	let symbol_info = futures_exchange_info
+		.symbols
+		.iter()
+		.find(|x| x.symbol == symbol)
+		.ok_or_else(|| anyhow::anyhow!("No {} in exchangeInfo", symbol))?;
+
+	SymbolRules::from_filters(&symbol_info.filters)
+}
+
+/// Submits an already translated order, returns the order id.
+pub async fn post_binance_order(key: String, secret: String, order: &BinanceOrder, rules: &SymbolRules) -> Result<i64> {
+	let url = FuturesPositionResponse::get_url();
+	let r = signed_request(HttpMethod::POST, url.as_str(), order.to_params(rules), key, secret).await?.error_for_status()?;
+	let response: FuturesPositionResponse = r.json().await?;
+	Ok(response.orderId)
+}
+
 /// submits an order, if successful, returns the order id
 //TODO!!: make the symbol be from utils \
-pub async fn post_futures_order(key: String, secret: String, order_type: String, symbol: String, side: Side, quantity: f64) -> Result<i64> {
+pub async fn post_futures_order(
+	key: String,
+	secret: String,
+	order_type: String,
+	symbol: String,
+	side: Side,
+	position_side: PositionSide,
+	quantity: f64,
+) -> Result<i64> {
 	let url = FuturesPositionResponse::get_url();
 
 	let mut params = HashMap::<&str, String>::new();
@@ -209,12 +518,281 @@ pub async fn post_futures_order(key: String, secret: String, order_type: String,
 	params.insert("side", side.to_string());
 	params.insert("type", order_type);
 	params.insert("quantity", format!("{}", quantity));
+	if position_side != PositionSide::Both {
+		params.insert("positionSide", position_side.to_string());
+	}
 
 	let r = signed_request(HttpMethod::POST, url.as_str(), params, key, secret).await?;
 	let response: FuturesPositionResponse = r.json().await?;
 	Ok(response.orderId)
 }
 
+/// Closes whatever is held on the leg with a market order. `position_amt` is signed, as in `get_futures_positions`.
+pub async fn close_futures_position(key: String, secret: String, symbol: String, position_side: PositionSide, position_amt: f64) -> Result<i64> {
+	let url = FuturesPositionResponse::get_url();
+	let side = if position_amt > 0.0 { Side::Sell } else { Side::Buy };
+
+	let mut params = HashMap::<&str, String>::new();
+	params.insert("symbol", symbol);
+	params.insert("side", side.to_string());
+	params.insert("type", "MARKET".to_owned());
+	params.insert("quantity", format!("{}", position_amt.abs()));
+	match position_side {
+		// not accepted in hedge mode, where the leg alone makes it reducing
+		PositionSide::Both => params.insert("reduceOnly", "true".to_owned()),
+		leg => params.insert("positionSide", leg.to_string()),
+	};
+
+	let r = signed_request(HttpMethod::POST, url.as_str(), params, key, secret).await?;
+	let response: FuturesPositionResponse = r.json().await?;
+	Ok(response.orderId)
+}
+
+/// Only LIMIT orders can be modified. `quantity` is the new total of the order, filled part included.
+pub async fn modify_futures_order(key: String, secret: String, symbol: String, order_id: i64, side: Side, quantity: String, price: String) -> Result<()> {
+	let url = FuturesPositionResponse::get_url();
+
+	let mut params = HashMap::<&str, String>::new();
+	params.insert("symbol", symbol);
+	params.insert("orderId", format!("{}", order_id));
+	params.insert("side", side.to_string());
+	params.insert("quantity", quantity);
+	params.insert("price", price);
+
+	signed_request(HttpMethod::PUT, url.as_str(), params, key, secret).await?.error_for_status()?;
+	Ok(())
+}
+
+pub async fn cancel_futures_order(key: String, secret: String, symbol: String, order_id: i64) -> Result<()> {
+	let url = FuturesPositionResponse::get_url();
+
+	let mut params = HashMap::<&str, String>::new();
+	params.insert("symbol", symbol);
+	params.insert("orderId", format!("{}", order_id));
+
+	signed_request(HttpMethod::DELETE, url.as_str(), params, key, secret).await?.error_for_status()?;
+	Ok(())
+}
+
+pub async fn cancel_all_futures_orders(key: String, secret: String, symbol: String) -> Result<()> {
+	let base_url = Market::BinanceFutures.get_base_url();
+	let url = base_url.join("/fapi/v1/allOpenOrders")?;
+
+	let mut params = HashMap::<&str, String>::new();
+	params.insert("symbol", symbol);
+
+	signed_request(HttpMethod::DELETE, url.as_str(), params, key, secret).await?.error_for_status()?;
+	Ok(())
+}
+
+/// Summed over all assets of the futures wallet.
+pub async fn get_futures_unrealised_pnl(key: String, secret: String) -> Result<f64> {
+	let url = Market::BinanceFutures.get_base_url().join("fapi/v2/balance")?;
+	let r = signed_request(HttpMethod::GET, url.as_str(), HashMap::new(), key, secret).await?;
+	let asset_balances: Vec<FuturesBalance> = r.json().await?;
+
+	let mut unrealised = 0.0;
+	for asset in asset_balances {
+		unrealised += asset.crossUnPnl.parse::<f64>()?;
+	}
+	Ok(unrealised)
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LeverageBracket {
+	#[serde(rename = "initialLeverage")]
+	pub initial_leverage: u32,
+	/// Position notional, in USDT, up to which the bracket applies.
+	#[serde(rename = "notionalCap")]
+	pub notional_cap: f64,
+	#[serde(rename = "notionalFloor")]
+	pub notional_floor: f64,
+	#[serde(rename = "maintMarginRatio")]
+	pub maint_margin_ratio: f64,
+}
+
+pub async fn get_leverage_brackets(key: String, secret: String, symbol: String) -> Result<Vec<LeverageBracket>> {
+	#[derive(Deserialize)]
+	struct SymbolBrackets {
+		brackets: Vec<LeverageBracket>,
+	}
+	let url = Market::BinanceFutures.get_base_url().join("/fapi/v1/leverageBracket")?;
+	let mut params = HashMap::<&str, String>::new();
+	params.insert("symbol", symbol);
+
+	let r = signed_request(HttpMethod::GET, url.as_str(), params, key, secret).await?;
+	let mut symbols: Vec<SymbolBrackets> = r.json().await?;
+	match symbols.pop() {
+		Some(s) => Ok(s.brackets),
+		None => Err(anyhow::anyhow!("No leverage brackets returned")),
+	}
+}
+
+pub async fn set_leverage(key: String, secret: String, symbol: String, leverage: u32) -> Result<()> {
+	let url = Market::BinanceFutures.get_base_url().join("/fapi/v1/leverage")?;
+	let mut params = HashMap::<&str, String>::new();
+	params.insert("symbol", symbol);
+	params.insert("leverage", format!("{}", leverage));
+
+	signed_request(HttpMethod::POST, url.as_str(), params, key, secret).await?.error_for_status()?;
+	Ok(())
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum MarginType {
+	Isolated,
+	#[default]
+	Cross,
+}
+impl std::fmt::Display for MarginType {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			MarginType::Isolated => write!(f, "ISOLATED"),
+			MarginType::Cross => write!(f, "CROSSED"),
+		}
+	}
+}
+
+/// Setting the type the symbol already has is not an error.
+pub async fn set_margin_type(key: String, secret: String, symbol: String, margin_type: MarginType) -> Result<()> {
+	let url = Market::BinanceFutures.get_base_url().join("/fapi/v1/marginType")?;
+	let mut params = HashMap::<&str, String>::new();
+	params.insert("symbol", symbol);
+	params.insert("marginType", margin_type.to_string());
+
+	let r = signed_request(HttpMethod::POST, url.as_str(), params, key, secret).await?;
+	if r.status().is_success() {
+		return Ok(());
+	}
+	let body: Value = r.json().await?;
+	match body.get("code").and_then(|c| c.as_i64()) {
+		// "No need to change margin type."
+		Some(-4046) => Ok(()),
+		_ => Err(anyhow::anyhow!("Failed to set margin type: {}", body)),
+	}
+}
+
+/// Summed over both legs, in USDT.
+pub async fn get_futures_symbol_notional(key: String, secret: String, symbol: String) -> Result<f64> {
+	let url = FuturesAllPositionsResponse::get_url();
+	let mut params = HashMap::<&str, String>::new();
+	params.insert("symbol", symbol);
+
+	let r = signed_request(HttpMethod::GET, url.as_str(), params, key, secret).await?;
+	let positions: Vec<FuturesAllPositionsResponse> = r.json().await?;
+	let mut notional = 0.0;
+	for position in positions {
+		notional += position.notional.parse::<f64>()?.abs();
+	}
+	Ok(notional)
+}
+
+/// Maker and taker rates of the account on a futures symbol, as fractions.
+pub async fn futures_commission_rate(key: String, secret: String, symbol: String) -> Result<(f64, f64)> {
+	#[derive(Deserialize)]
+	struct CommissionRate {
+		makerCommissionRate: String,
+		takerCommissionRate: String,
+	}
+	let url = Market::BinanceFutures.get_base_url().join("/fapi/v1/commissionRate")?;
+	let mut params = HashMap::<&str, String>::new();
+	params.insert("symbol", symbol);
+
+	let r = signed_request(HttpMethod::GET, url.as_str(), params, key, secret).await?.error_for_status()?;
+	let rate: CommissionRate = r.json().await?;
+	Ok((rate.makerCommissionRate.parse()?, rate.takerCommissionRate.parse()?))
+}
+
+/// Whether futures fees are paid in BNB, at a discount.
+pub async fn futures_fee_burn(key: String, secret: String) -> Result<bool> {
+	#[derive(Deserialize)]
+	struct FeeBurn {
+		feeBurn: bool,
+	}
+	let url = Market::BinanceFutures.get_base_url().join("/fapi/v1/feeBurn")?;
+	let r = signed_request(HttpMethod::GET, url.as_str(), HashMap::new(), key, secret).await?.error_for_status()?;
+	let fee_burn: FeeBurn = r.json().await?;
+	Ok(fee_burn.feeBurn)
+}
+
+/// Spot rates of the account on a symbol, as fractions. Tax is already included.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpotCommission {
+	pub maker: f64,
+	pub taker: f64,
+	/// Share taken off when paying in BNB, if that is enabled for both the account and the symbol.
+	pub bnb_discount: Option<f64>,
+}
+
+pub async fn spot_symbol_commission(key: String, secret: String, symbol: String) -> Result<SpotCommission> {
+	#[derive(Deserialize)]
+	struct Rates {
+		maker: String,
+		taker: String,
+	}
+	#[derive(Deserialize)]
+	struct Discount {
+		enabledForAccount: bool,
+		enabledForSymbol: bool,
+		discount: String,
+	}
+	#[derive(Deserialize)]
+	struct AccountCommission {
+		standardCommission: Rates,
+		taxCommission: Rates,
+		discount: Discount,
+	}
+	let url = Market::BinanceSpot.get_base_url().join("/api/v3/account/commission")?;
+	let mut params = HashMap::<&str, String>::new();
+	params.insert("symbol", symbol);
+
+	let r = signed_request(HttpMethod::GET, url.as_str(), params, key, secret).await?.error_for_status()?;
+	let c: AccountCommission = r.json().await?;
+	Ok(SpotCommission {
+		maker: c.standardCommission.maker.parse::<f64>()? + c.taxCommission.maker.parse::<f64>()?,
+		taker: c.standardCommission.taker.parse::<f64>()? + c.taxCommission.taker.parse::<f64>()?,
+		bnb_discount: match c.discount.enabledForAccount && c.discount.enabledForSymbol {
+			true => Some(c.discount.discount.parse()?),
+			false => None,
+		},
+	})
+}
+
+/// Account-wide spot maker and taker rates, for symbols whose own could not be had.
+pub async fn spot_account_commission(key: String, secret: String) -> Result<(f64, f64)> {
+	let url = Market::BinanceSpot.get_base_url().join("/api/v3/account")?;
+	let r = signed_request(HttpMethod::GET, url.as_str(), HashMap::new(), key, secret).await?.error_for_status()?;
+	let account_details: SpotAccountDetails = r.json().await?;
+	Ok((account_details.commissionRates.maker.parse()?, account_details.commissionRates.taker.parse()?))
+}
+
+/// Single execution on a futures order, as `/fapi/v1/userTrades` has it.
+#[derive(Clone, Debug, Deserialize)]
+pub struct FuturesTrade {
+	pub id: i64,
+	pub orderId: i64,
+	pub side: String,
+	pub price: String,
+	pub qty: String,
+	pub commission: String,
+	pub commissionAsset: String,
+	pub maker: bool,
+	pub time: i64,
+}
+
+/// Executions of the order, oldest first.
+pub async fn futures_order_trades(key: String, secret: String, symbol: String, order_id: i64) -> Result<Vec<FuturesTrade>> {
+	let url = Market::BinanceFutures.get_base_url().join("/fapi/v1/userTrades")?;
+	let mut params = HashMap::<&str, String>::new();
+	params.insert("symbol", symbol);
+	params.insert("orderId", order_id.to_string());
+
+	let r = signed_request(HttpMethod::GET, url.as_str(), params, key, secret).await?.error_for_status()?;
+	let trades: Vec<FuturesTrade> = r.json().await?;
+	Ok(trades)
+}
+
 /// Normally, the only cases where the return from this poll is going to be _reacted_ to, is when response.status == OrderStatus::Filled or an error is returned.
 pub async fn poll_futures_order(key: String, secret: String, order_id: i64, symbol: String) -> Result<FuturesPositionResponse> {
 	let url = FuturesPositionResponse::get_url();
@@ -236,7 +814,7 @@ pub async fn poll_futures_order(key: String, secret: String, order_id: i64, symb
 //? What if in cases when the struct is shared, I just implement market_specific commands to retrieve the url?
 // Trying this out now. So far so good.
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum OrderStatus {
 	#[serde(rename = "NEW")]
 	New,
@@ -370,31 +948,14 @@ struct FuturesExchangeInfo {
 	timezone: String,
 }
 #[derive(Debug, Deserialize, Serialize)]
-struct RateLimit {
-	interval: String,
-	intervalNum: u32,
-	limit: u32,
-	rateLimitType: String,
-}
-
-// the thing with multiplying orders due to weird limits should be here.
-//#[derive(Debug, Deserialize, Serialize)]
-//#[allow(non_snake_case)]
-//struct SymbolFilter {
-//	filterType: String,
-//	maxPrice: String,
-//	minPrice: String,
-//	tickSize: String,
-//	maxQty: String,
-//	minQty: String,
-//	stepSize: String,
-//	limit: u32,
-//	notional: String,
-//	multiplierUp: String,
-//	multiplierDown: String,
-//	multiplierDecimal: u32,
-//}
+pub struct RateLimit {
+	pub interval: String,
+	pub intervalNum: u32,
+	pub limit: u32,
+	pub rateLimitType: String,
+}
 
+// filters are read into `SymbolRules`
 #[derive(Debug, Deserialize, Serialize)]
 struct FuturesSymbol {
 	symbol: String,
@@ -439,7 +1000,7 @@ struct FuturesAllPositionsResponse {
 	isolatedWallet: String,
 	symbol: String,
 	unRealizedProfit: String,
-	positionSide: Value, // is "BOTH" in standard (non-hedge mode) requests, because designed by fucking morons. Apparently we now have negative values in `positionAmt`, if short.
+	positionSide: PositionSide, // is "BOTH" in standard (non-hedge mode) requests, because designed by fucking morons. Apparently we now have negative values in `positionAmt`, if short.
 	updateTime: i64,
 }
 impl FuturesAllPositionsResponse {
@@ -472,3 +1033,130 @@ pub struct ResponseKline {
 	ignore: String,
 }
 //,}}}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::order_types::{ConceptualLimit, ConceptualMarket, ConceptualStopMarket, ProtocolOrderId};
+
+	fn rules() -> SymbolRules {
+		let filters: Vec<Value> = serde_json::from_str(
+			r#"[
+				{"filterType": "PRICE_FILTER", "tickSize": "0.10"},
+				{"filterType": "LOT_SIZE", "stepSize": "0.001", "minQty": "0.001"},
+				{"filterType": "MARKET_LOT_SIZE", "stepSize": "0.01"},
+				{"filterType": "MIN_NOTIONAL", "notional": "100"}
+			]"#,
+		)
+		.unwrap();
+		SymbolRules::from_filters(&filters).unwrap()
+	}
+
+	fn stop() -> ConceptualOrder {
+		ConceptualOrder::StopMarket(ConceptualStopMarket {
+			id: ProtocolOrderId::new("test".to_owned(), uuid::Uuid::new_v4()),
+			maximum_slippage_percent: 1.0,
+			symbol: "BTC-USDT-BinanceFutures".parse().unwrap(),
+			side: Side::Sell,
+			price: 60_000.0,
+			qty_notional: 0.01,
+		})
+	}
+
+	fn id() -> ProtocolOrderId {
+		ProtocolOrderId::new("test".to_owned(), uuid::Uuid::new_v4())
+	}
+
+	fn market(side: Side, qty_notional: f64, maximum_slippage_percent: f64) -> ConceptualOrder {
+		ConceptualOrder::Market(ConceptualMarket {
+			id: id(),
+			maximum_slippage_percent,
+			symbol: "BTC-USDT-BinanceFutures".parse().unwrap(),
+			side,
+			qty_notional,
+		})
+	}
+
+	fn limit(side: Side, price: f64, qty_notional: f64, limit_only: bool) -> ConceptualOrder {
+		ConceptualOrder::Limit(ConceptualLimit {
+			id: id(),
+			symbol: "BTC-USDT-BinanceFutures".parse().unwrap(),
+			side,
+			price,
+			qty_notional,
+			limit_only,
+		})
+	}
+
+	#[test]
+	fn rounding_never_works_against_the_order() {
+		let rules = rules();
+		assert_eq!(rules.floor_qty(0.0129, false), 0.012);
+		assert_eq!(rules.floor_qty(0.0129, true), 0.01);
+		// float noise of an exact multiple is not a step less
+		assert_eq!(rules.floor_qty(0.3, false), 0.3);
+
+		assert_eq!(rules.passive_price(60_000.05, &Side::Buy), 60_000.0);
+		assert_eq!(rules.passive_price(60_000.05, &Side::Sell), 60_000.1);
+		assert_eq!(rules.passive_price(60_000.1, &Side::Buy), 60_000.1);
+		assert_eq!(rules.passive_price(60_000.1, &Side::Sell), 60_000.1);
+
+		assert_eq!(rules.nearest_price(60_000.04), 60_000.0);
+		assert_eq!(rules.nearest_price(60_000.06), 60_000.1);
+	}
+
+	#[test]
+	fn limits_are_post_only_if_asked() {
+		let maker = BinanceOrder::translate(&limit(Side::Buy, 60_000.05, 0.0129, true), PositionSide::Both, &rules(), None, false).unwrap();
+		assert_eq!(maker.time_in_force, Some(TimeInForce::Gtx));
+		assert_eq!(maker.price, Some(60_000.0));
+		assert_eq!(maker.quantity, 0.012);
+
+		let resting = BinanceOrder::translate(&limit(Side::Sell, 60_000.05, 0.01, false), PositionSide::Both, &rules(), None, false).unwrap();
+		assert_eq!(resting.time_in_force, Some(TimeInForce::Gtc));
+		assert_eq!(resting.price, Some(60_000.1));
+	}
+
+	#[test]
+	fn bounded_market_orders_are_ioc_limits_at_the_bound() {
+		let buy = BinanceOrder::translate(&market(Side::Buy, 0.0129, 0.01), PositionSide::Both, &rules(), Some(60_000.0), false).unwrap();
+		assert_eq!(buy.order_type, "LIMIT");
+		assert_eq!(buy.time_in_force, Some(TimeInForce::Ioc));
+		assert_eq!(buy.price, Some(60_600.0));
+		// limit step, not the market one
+		assert_eq!(buy.quantity, 0.012);
+
+		let sell = BinanceOrder::translate(&market(Side::Sell, 0.01, 0.01), PositionSide::Both, &rules(), Some(60_000.0), false).unwrap();
+		assert_eq!(sell.price, Some(59_400.0));
+
+		for (order, reference) in [(market(Side::Buy, 0.0129, 1.0), Some(60_000.0)), (market(Side::Buy, 0.0129, 0.01), None)] {
+			let plain = BinanceOrder::translate(&order, PositionSide::Both, &rules(), reference, false).unwrap();
+			assert_eq!(plain.order_type, "MARKET");
+			assert_eq!((plain.price, plain.time_in_force), (None, None));
+			assert_eq!(plain.quantity, 0.01);
+		}
+	}
+
+	#[test]
+	fn too_small_orders_are_refused() {
+		// under minQty
+		assert!(BinanceOrder::translate(&limit(Side::Buy, 60_000.0, 0.0009, false), PositionSide::Both, &rules(), None, false).is_err());
+		// rounded to nothing on the coarser market step
+		assert!(BinanceOrder::translate(&market(Side::Buy, 0.009, 1.0), PositionSide::Both, &rules(), Some(60_000.0), false).is_err());
+		// 60 USDT, under minNotional
+		assert!(BinanceOrder::translate(&limit(Side::Buy, 60_000.0, 0.001, false), PositionSide::Both, &rules(), None, false).is_err());
+		assert!(BinanceOrder::translate(&market(Side::Buy, 0.001, 0.01), PositionSide::Both, &rules(), Some(60_000.0), false).is_err());
+		assert!(BinanceOrder::translate(&limit(Side::Buy, 60_000.0, 0.002, false), PositionSide::Both, &rules(), None, false).is_ok());
+	}
+
+	#[test]
+	fn stops_are_reduce_only_unless_the_leg_is_shared() {
+		let own = BinanceOrder::translate(&stop(), PositionSide::Both, &rules(), None, false).unwrap();
+		assert!(own.reduce_only);
+		assert_eq!(own.to_params(&rules()).get("reduceOnly").map(|s| s.as_str()), Some("true"));
+
+		let shared = BinanceOrder::translate(&stop(), PositionSide::Both, &rules(), None, true).unwrap();
+		assert!(!shared.reduce_only);
+		assert!(!shared.to_params(&rules()).contains_key("reduceOnly"));
+	}
+}
@@ -0,0 +1,196 @@
+use crate::api::order_types::ConceptualOrder;
+
+/// What is to be done to get from the live orders to the target ones. Indexes refer to the slices given to `plan`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+	/// Live order already expresses the target one.
+	Keep { order_id: i64, target: usize },
+	/// Live order is to be modified in place into the target one; price, quantity or both.
+	Amend { order_id: i64, target: usize },
+	Cancel { order_id: i64 },
+	Place { target: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiffParams {
+	/// Quantities equal when rounded to this many decimals are the same.
+	pub qty_precision: usize,
+	/// Relative difference in price under which a live order is left where it is.
+	pub price_tolerance: f64,
+	/// Whether the exchange can modify stop orders; limit ones are always assumed to be modifiable.
+	pub amend_stops: bool,
+}
+
+/// Fewest actions that turn `live` into `target`. Live quantities are what is left of them, not the original sizes.
+/// Returned ordered as: keeps, cancels, amends, places; so that margin is freed before it is needed again.
+pub fn plan(live: &[(i64, ConceptualOrder)], target: &[ConceptualOrder], params: &DiffParams) -> Vec<Action> {
+	let mut live_left: Vec<usize> = (0..live.len()).collect();
+	let mut target_left: Vec<usize> = (0..target.len()).collect();
+
+	let mut keeps = Vec::new();
+	target_left.retain(|&t| {
+		let found = live_left
+			.iter()
+			.position(|&l| same_kind(&live[l].1, &target[t]) && close_price(&live[l].1, &target[t], params) && same_qty(&live[l].1, &target[t], params));
+		match found {
+			Some(i) => {
+				keeps.push(Action::Keep {
+					order_id: live[live_left.remove(i)].0,
+					target: t,
+				});
+				false
+			}
+			None => true,
+		}
+	});
+
+	// closest in price first, so that an order moving a little is not swapped with a neighbour
+	let mut amends = Vec::new();
+	target_left.retain(|&t| {
+		let found = live_left
+			.iter()
+			.enumerate()
+			.filter(|(_, &l)| same_kind(&live[l].1, &target[t]) && amendable(&target[t], params))
+			.min_by(|(_, &a), (_, &b)| price_distance(&live[a].1, &target[t]).total_cmp(&price_distance(&live[b].1, &target[t])))
+			.map(|(i, _)| i);
+		match found {
+			Some(i) => {
+				amends.push(Action::Amend {
+					order_id: live[live_left.remove(i)].0,
+					target: t,
+				});
+				false
+			}
+			None => true,
+		}
+	});
+
+	let cancels = live_left.into_iter().map(|l| Action::Cancel { order_id: live[l].0 });
+	let places = target_left.into_iter().map(|t| Action::Place { target: t });
+	keeps.into_iter().chain(cancels).chain(amends).chain(places).collect()
+}
+
+/// Same type and side, and the same flags; only price and size can differ.
+fn same_kind(a: &ConceptualOrder, b: &ConceptualOrder) -> bool {
+	match (a, b) {
+		(ConceptualOrder::Limit(a), ConceptualOrder::Limit(b)) => a.side == b.side && a.limit_only == b.limit_only,
+		(ConceptualOrder::StopMarket(a), ConceptualOrder::StopMarket(b)) => a.side == b.side,
+		(ConceptualOrder::Market(a), ConceptualOrder::Market(b)) => a.side == b.side,
+		_ => false,
+	}
+}
+
+fn amendable(order: &ConceptualOrder, params: &DiffParams) -> bool {
+	match order {
+		ConceptualOrder::Limit(_) => true,
+		ConceptualOrder::StopMarket(_) => params.amend_stops,
+		ConceptualOrder::Market(_) => false,
+	}
+}
+
+fn price_distance(a: &ConceptualOrder, b: &ConceptualOrder) -> f64 {
+	match (a.price(), b.price()) {
+		(Ok(a), Ok(b)) => (a - b).abs() / b.abs(),
+		_ => 0.0,
+	}
+}
+
+fn close_price(a: &ConceptualOrder, b: &ConceptualOrder, params: &DiffParams) -> bool {
+	price_distance(a, b) <= params.price_tolerance
+}
+
+fn same_qty(a: &ConceptualOrder, b: &ConceptualOrder, params: &DiffParams) -> bool {
+	let factor = 10_f64.powi(params.qty_precision as i32);
+	(a.notional() * factor).round() == (b.notional() * factor).round()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::order_types::{ConceptualLimit, ConceptualStopMarket, ProtocolOrderId};
+	use uuid::Uuid;
+	use v_utils::trades::Side;
+
+	const PARAMS: DiffParams = DiffParams {
+		qty_precision: 3,
+		price_tolerance: 0.001,
+		amend_stops: false,
+	};
+
+	fn limit(side: Side, price: f64, qty_notional: f64) -> ConceptualOrder {
+		ConceptualOrder::Limit(ConceptualLimit {
+			id: ProtocolOrderId::new("test".to_owned(), Uuid::new_v4()),
+			symbol: "BTC-USDT-BinanceFutures".parse().unwrap(),
+			side,
+			price,
+			qty_notional,
+			limit_only: false,
+		})
+	}
+
+	fn stop(side: Side, price: f64, qty_notional: f64) -> ConceptualOrder {
+		ConceptualOrder::StopMarket(ConceptualStopMarket {
+			id: ProtocolOrderId::new("test".to_owned(), Uuid::new_v4()),
+			maximum_slippage_percent: 1.0,
+			symbol: "BTC-USDT-BinanceFutures".parse().unwrap(),
+			side,
+			price,
+			qty_notional,
+		})
+	}
+
+	#[test]
+	fn keeps_what_is_within_tolerance() {
+		let live = [(1, limit(Side::Buy, 100.0, 0.5))];
+		let target = [limit(Side::Buy, 100.05, 0.5004)];
+		assert_eq!(plan(&live, &target, &PARAMS), vec![Action::Keep { order_id: 1, target: 0 }]);
+	}
+
+	#[test]
+	fn amends_in_place() {
+		let live = [(1, limit(Side::Buy, 100.0, 0.5))];
+		let target = [limit(Side::Buy, 101.0, 0.7)];
+		assert_eq!(plan(&live, &target, &PARAMS), vec![Action::Amend { order_id: 1, target: 0 }]);
+	}
+
+	#[test]
+	fn amends_the_closest_in_price() {
+		let live = [(1, limit(Side::Buy, 90.0, 0.5)), (2, limit(Side::Buy, 100.0, 0.5))];
+		let target = [limit(Side::Buy, 99.0, 0.5)];
+		assert_eq!(
+			plan(&live, &target, &PARAMS),
+			vec![Action::Cancel { order_id: 1 }, Action::Amend { order_id: 2, target: 0 }]
+		);
+	}
+
+	#[test]
+	fn replaces_on_a_change_of_side_or_type() {
+		let live = [(1, limit(Side::Buy, 100.0, 0.5)), (2, stop(Side::Sell, 90.0, 0.5))];
+		let target = [limit(Side::Sell, 100.0, 0.5), limit(Side::Sell, 90.0, 0.5)];
+		assert_eq!(
+			plan(&live, &target, &PARAMS),
+			vec![
+				Action::Cancel { order_id: 1 },
+				Action::Cancel { order_id: 2 },
+				Action::Place { target: 0 },
+				Action::Place { target: 1 }
+			]
+		);
+	}
+
+	#[test]
+	fn stops_are_only_amended_where_the_exchange_can() {
+		let live = [(1, stop(Side::Sell, 90.0, 0.5))];
+		let target = [stop(Side::Sell, 92.0, 0.5)];
+		assert_eq!(plan(&live, &target, &PARAMS), vec![Action::Cancel { order_id: 1 }, Action::Place { target: 0 }]);
+		let params = DiffParams { amend_stops: true, ..PARAMS };
+		assert_eq!(plan(&live, &target, &params), vec![Action::Amend { order_id: 1, target: 0 }]);
+	}
+
+	#[test]
+	fn places_targets_with_no_live_counterpart() {
+		let target = [limit(Side::Buy, 100.0, 0.5), stop(Side::Sell, 90.0, 0.5)];
+		assert_eq!(plan(&[], &target, &PARAMS), vec![Action::Place { target: 0 }, Action::Place { target: 1 }]);
+		assert_eq!(plan(&[(1, limit(Side::Buy, 100.0, 0.5))], &[], &PARAMS), vec![Action::Cancel { order_id: 1 }]);
+	}
+}
@@ -0,0 +1,390 @@
+use crate::api::binance::{self, BinanceOrder, OrderStatus, SymbolRules};
+use crate::api::diff::{self, Action, DiffParams};
+use crate::api::fees::FeeModel;
+use crate::api::hub::Leg;
+use crate::api::netting::NettedOrder;
+use crate::api::order_book::OrderBooks;
+use crate::api::order_types::ConceptualOrder;
+use crate::api::user_data::UserDataEvent;
+use crate::config::AppConfig;
+use crate::reconciler::ExposureBook;
+use crate::stops::StopMode;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use tokio::select;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, error, info, warn};
+use v_utils::trades::Side;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ExecutionConfig {
+	/// Relative price change under which a resting order is left where it is. Keeps ratcheting stops from re-placing on every tick.
+	pub price_tolerance: f64,
+	/// Number of IOC limits a slippage-bounded market order is split into, each priced closer to the bound.
+	pub ioc_steps: u32,
+	/// How many times a position sends a market order again after the exchange did not fill all of it.
+	pub unfilled_retries: u32,
+	/// How stops are expressed for positions that don't specify it themselves.
+	pub stop_mode: StopMode,
+	/// In `hybrid` stop mode, how much further from the synthetic stop the native one is, as a fraction of the price.
+	pub disaster_stop_distance: f64,
+}
+impl Default for ExecutionConfig {
+	fn default() -> Self {
+		Self {
+			price_tolerance: 0.0005,
+			ioc_steps: 3,
+			unfilled_retries: 3,
+			stop_mode: StopMode::default(),
+			disaster_stop_distance: 0.02,
+		}
+	}
+}
+
+/// What the hub asks of an exchange.
+#[derive(Debug, Clone)]
+pub enum ExecutorCommand {
+	/// Resting orders the leg should have; whatever else the executor has on it is cancelled.
+	/// `shared` if positions of both sides, of this process or any other, are on the one-way leg; none of its orders may then be reduce-only, as the exchange only holds their net.
+	Sync { leg: Leg, orders: Vec<NettedOrder>, shared: bool },
+	/// Market order, to be sent once.
+	Execute { leg: Leg, order: NettedOrder },
+}
+
+/// What an exchange tells back to the hub, always in terms of the netted order it was given.
+#[derive(Debug, Clone)]
+pub enum ExecutionReport {
+	Fill {
+		order: NettedOrder,
+		last_qty: f64,
+		last_price: f64,
+		commission_usdt: f64,
+		t: DateTime<Utc>,
+	},
+	/// Part of a market order that is never going to be filled. `why` is set if the exchange had a go at it, as opposed to it being rounded away or too small to be sent.
+	Unfilled { order: NettedOrder, qty: f64, why: Option<String> },
+}
+
+#[derive(Debug)]
+struct LiveOrder {
+	leg: Leg,
+	order: NettedOrder,
+	/// As placed, after rounding.
+	qty: f64,
+	executed: f64,
+	/// Cancel was requested, but fills can still come in until the exchange confirms.
+	cancelling: bool,
+	reduce_only: bool,
+	/// Step of the IOC ladder, and the price it is bounded against. Only for slippage-bounded market orders.
+	ladder: Option<(u32, f64)>,
+}
+impl LiveOrder {
+	fn is_market(&self) -> bool {
+		matches!(self.order.order, ConceptualOrder::Market(_))
+	}
+}
+
+/// Keeps the engine's orders on Binance futures in line with what the hub wants, and reports their fills.
+#[derive(Debug)]
+pub struct BinanceFuturesExecutor {
+	key: String,
+	secret: String,
+	config: ExecutionConfig,
+	books: OrderBooks,
+	fees: FeeModel,
+	/// Every order that is live is registered in it, so that the reconciler knows it is ours.
+	exposure: ExposureBook,
+	live: HashMap<i64, LiveOrder>,
+	/// As of the last sync of each leg.
+	shared_legs: HashSet<Leg>,
+	/// By exchange symbol.
+	rules: HashMap<String, SymbolRules>,
+	tx_reports: mpsc::UnboundedSender<ExecutionReport>,
+}
+impl BinanceFuturesExecutor {
+	pub fn new(config: &AppConfig, books: OrderBooks, fees: FeeModel, exposure: ExposureBook, tx_reports: mpsc::UnboundedSender<ExecutionReport>) -> Self {
+		Self {
+			key: config.binance.full_key.clone(),
+			secret: config.binance.full_secret.clone(),
+			config: config.execution.clone(),
+			books,
+			fees,
+			exposure,
+			live: HashMap::new(),
+			shared_legs: HashSet::new(),
+			rules: HashMap::new(),
+			tx_reports,
+		}
+	}
+
+	pub fn spawn(self, user_data: broadcast::Receiver<UserDataEvent>) -> mpsc::UnboundedSender<ExecutorCommand> {
+		let (tx, rx) = mpsc::unbounded_channel();
+		tokio::spawn(self.run(rx, user_data));
+		tx
+	}
+
+	async fn run(mut self, mut rx: mpsc::UnboundedReceiver<ExecutorCommand>, mut user_data: broadcast::Receiver<UserDataEvent>) {
+		loop {
+			select! {
+				command = rx.recv() => match command {
+					Some(ExecutorCommand::Sync { leg, orders, shared }) => self.sync(leg, orders, shared).await,
+					Some(ExecutorCommand::Execute { leg, order }) => self.place(leg, order).await,
+					None => break,
+				},
+				event = user_data.recv() => match event {
+					Ok(event) => self.on_user_data(event).await,
+					Err(broadcast::error::RecvError::Lagged(n)) => error!("Executor missed {} user-data events, fills on them are lost", n),
+					Err(broadcast::error::RecvError::Closed) => {
+						error!("User-data stream is gone, executor stops");
+						break;
+					}
+				},
+			}
+		}
+	}
+
+	async fn sync(&mut self, leg: Leg, orders: Vec<NettedOrder>, shared: bool) {
+		match shared {
+			true => self.shared_legs.insert(leg.clone()),
+			false => self.shared_legs.remove(&leg),
+		};
+		// reduce-only can't be amended, so stops placed before the leg became shared, or stopped being, are replaced
+		let stale: Vec<i64> = self
+			.live
+			.iter()
+			.filter(|(_, l)| l.leg == leg && !l.cancelling && matches!(l.order.order, ConceptualOrder::StopMarket(_)) && l.reduce_only == shared)
+			.map(|(order_id, _)| *order_id)
+			.collect();
+		for order_id in stale {
+			self.cancel(&leg, order_id).await;
+		}
+
+		// cached by the placement of whatever is live, so only missing if there is nothing to compare against anyway
+		let rules = self.rules.get(&leg.0.to_string()).cloned();
+		let live: Vec<(i64, ConceptualOrder)> = self
+			.live
+			.iter()
+			.filter(|(_, l)| l.leg == leg && !l.is_market() && !l.cancelling)
+			.map(|(order_id, l)| {
+				let mut order = l.order.order.clone();
+				order.cut_size(l.qty - l.executed);
+				(*order_id, order)
+			})
+			.collect();
+		let target: Vec<ConceptualOrder> = orders.iter().map(|n| n.order.clone()).collect();
+		let params = DiffParams {
+			qty_precision: rules.as_ref().map(|r| r.qty_decimals()).unwrap_or(8),
+			price_tolerance: self.config.price_tolerance,
+			amend_stops: false,
+		};
+
+		for action in diff::plan(&live, &target, &params) {
+			match action {
+				// contributors could have changed, while the order itself did not; price is kept as placed, so that drift within the tolerance does not add up
+				Action::Keep { order_id, target } => self.live.get_mut(&order_id).unwrap().order.allocations = orders[target].allocations.clone(),
+				Action::Cancel { order_id } => self.cancel(&leg, order_id).await,
+				Action::Amend { order_id, target } => {
+					let rules = rules.as_ref().unwrap();
+					let live = self.live.get(&order_id).unwrap();
+					let qty = rules.floor_qty(live.executed + orders[target].order.notional(), false);
+					let price = rules.passive_price(orders[target].order.price().unwrap(), orders[target].order.side());
+					let modified = binance::modify_futures_order(
+						self.key.clone(),
+						self.secret.clone(),
+						leg.0.to_string(),
+						order_id,
+						orders[target].order.side().clone(),
+						rules.format_qty(qty),
+						rules.format_price(price),
+					)
+					.await;
+					match modified {
+						Ok(()) => {
+							debug!(order_id, qty, price, "Amended");
+							self.exposure.register_order(order_id);
+							let live = self.live.get_mut(&order_id).unwrap();
+							live.order = orders[target].clone();
+							live.qty = qty;
+						}
+						Err(e) => {
+							warn!(order_id, "Failed to amend, replacing instead: {}", e);
+							self.cancel(&leg, order_id).await;
+							self.place(leg.clone(), orders[target].clone()).await;
+						}
+					}
+				}
+				Action::Place { target } => self.place(leg.clone(), orders[target].clone()).await,
+			}
+		}
+	}
+
+	async fn cancel(&mut self, leg: &Leg, order_id: i64) {
+		match binance::cancel_futures_order(self.key.clone(), self.secret.clone(), leg.0.to_string(), order_id).await {
+			Ok(()) => {
+				debug!(order_id, "Cancelled");
+				self.exposure.forget_order(&order_id);
+				self.live.get_mut(&order_id).unwrap().cancelling = true;
+			}
+			// most likely filled in the meantime; the user-data stream will tell
+			Err(e) => warn!(order_id, "Failed to cancel: {}", e),
+		}
+	}
+
+	async fn place(&mut self, leg: Leg, order: NettedOrder) {
+		self.place_step(leg, order, None).await
+	}
+
+	/// Market orders with a slippage bound are sent as IOC limits, each next one priced wider, up to the bound. `ladder` is the step to take and the reference price of the first one.
+	async fn place_step(&mut self, leg: Leg, order: NettedOrder, ladder: Option<(u32, f64)>) {
+		let tx_reports = self.tx_reports.clone();
+		let is_market = matches!(order.order, ConceptualOrder::Market(_));
+		let unfilled = |order: NettedOrder, qty: f64, why: Option<String>| {
+			if is_market && qty > 0.0 {
+				let _ = tx_reports.send(ExecutionReport::Unfilled { order, qty, why });
+			}
+		};
+		let rules = match self.rules(&leg.0.to_string()).await {
+			Ok(r) => r,
+			Err(e) => {
+				error!(?order, "Not placed, no trading rules for the symbol: {}", e);
+				unfilled(order.clone(), order.order.notional(), Some(e.to_string()));
+				return;
+			}
+		};
+		let ladder = match (&order.order, ladder) {
+			(_, Some(ladder)) => Some(ladder),
+			(ConceptualOrder::Market(m), None) if m.maximum_slippage_percent < 1.0 => match self.reference_price(&leg, &m.side).await {
+				Ok(price) => Some((1, price)),
+				Err(e) => {
+					error!(?order, "Not placed, no price to bound the slippage against: {}", e);
+					unfilled(order.clone(), order.order.notional(), Some(e.to_string()));
+					return;
+				}
+			},
+			_ => None,
+		};
+
+		let mut step_order = order.order.clone();
+		if let (ConceptualOrder::Market(m), Some((step, _))) = (&mut step_order, ladder) {
+			m.maximum_slippage_percent *= step as f64 / self.config.ioc_steps.max(1) as f64;
+		}
+		let translated = match BinanceOrder::translate(&step_order, leg.1, &rules, ladder.map(|(_, reference)| reference), self.shared_legs.contains(&leg)) {
+			Ok(t) => t,
+			Err(e) => {
+				debug!(?order, "Not placed: {}", e);
+				unfilled(order.clone(), order.order.notional(), None);
+				return;
+			}
+		};
+		let (qty, reduce_only) = (translated.quantity, translated.reduce_only);
+		// rounded away
+		unfilled(order.clone(), order.order.notional() - qty, None);
+		match binance::post_binance_order(self.key.clone(), self.secret.clone(), &translated, &rules).await {
+			Ok(order_id) => {
+				info!(order_id, ?translated, "Placed");
+				self.track(
+					order_id,
+					LiveOrder {
+						leg,
+						order,
+						qty,
+						executed: 0.0,
+						cancelling: false,
+						reduce_only,
+						ladder,
+					},
+				);
+			}
+			Err(e) => {
+				error!(?order, "Failed to place: {}", e);
+				unfilled(order, qty, Some(e.to_string()));
+			}
+		}
+	}
+
+	fn track(&mut self, order_id: i64, live: LiveOrder) {
+		self.exposure.register_order(order_id);
+		self.live.insert(order_id, live);
+	}
+
+	fn untrack(&mut self, order_id: &i64) -> Option<LiveOrder> {
+		self.exposure.forget_order(order_id);
+		self.live.remove(order_id)
+	}
+
+	/// Touch the order would take from, off the local book if it is in sync.
+	async fn reference_price(&self, leg: &Leg, side: &Side) -> Result<f64> {
+		let touch = self.books.read(&leg.0, |b| match side {
+			Side::Buy => b.best_ask(),
+			Side::Sell => b.best_bid(),
+		});
+		match touch.flatten() {
+			Some((price, _)) => Ok(price),
+			None => binance::futures_price(&leg.0.base).await,
+		}
+	}
+
+	async fn rules(&mut self, symbol: &str) -> Result<SymbolRules> {
+		if let Some(r) = self.rules.get(symbol) {
+			return Ok(r.clone());
+		}
+		let r = binance::futures_symbol_rules(symbol).await?;
+		self.rules.insert(symbol.to_owned(), r.clone());
+		Ok(r)
+	}
+
+	/// Events on orders not placed by this executor, ex: acquisition ones, are ignored.
+	async fn on_user_data(&mut self, event: UserDataEvent) {
+		match event {
+			UserDataEvent::Fill(fill) => {
+				let Some(live) = self.live.get_mut(&fill.order_id) else { return };
+				live.executed += fill.last_qty;
+				let commission_usdt = self.fees.commission_usdt(&live.leg.0, &fill);
+				let _ = self.tx_reports.send(ExecutionReport::Fill {
+					order: live.order.clone(),
+					last_qty: fill.last_qty,
+					last_price: fill.last_price,
+					commission_usdt,
+					t: fill.t,
+				});
+			}
+			UserDataEvent::OrderState(state) => match state.status {
+				OrderStatus::New | OrderStatus::PartiallyFilled => {}
+				status => {
+					let Some(live) = self.untrack(&state.order_id) else { return };
+					debug!(order_id = state.order_id, ?status, "Order is done");
+					if !live.is_market() || live.executed >= live.qty {
+						return;
+					}
+					let left = live.qty - live.executed;
+					match live.ladder {
+						Some((step, reference)) if step < self.config.ioc_steps => {
+							debug!(order_id = state.order_id, left, step, "IOC step not filled in full, widening");
+							let mut order = live.order;
+							order.order.cut_size(left);
+							self.place_step(live.leg, order, Some((step + 1, reference))).await;
+						}
+						Some(_) => {
+							let _ = self.tx_reports.send(ExecutionReport::Unfilled {
+								order: live.order,
+								qty: left,
+								why: Some("Ran out of the slippage bound".to_owned()),
+							});
+						}
+						None => {
+							let _ = self.tx_reports.send(ExecutionReport::Unfilled {
+								order: live.order,
+								qty: left,
+								why: Some(format!("Order ended up {:?}", status)),
+							});
+						}
+					}
+				}
+			},
+			_ => {}
+		}
+	}
+}
@@ -0,0 +1,129 @@
+use crate::api::binance;
+use crate::api::user_data::ExchangeFill;
+use crate::api::{Market, Symbol};
+use crate::config::AppConfig;
+use anyhow::Result;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tracing::{debug, info, warn};
+
+/// Futures fees paid in BNB are this much lower. Not reported by the exchange, unlike the spot one.
+const FUTURES_BNB_DISCOUNT: f64 = 0.1;
+
+/// As fractions of the notional, discounts already applied.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub struct FeeRates {
+	pub maker: f64,
+	pub taker: f64,
+}
+impl FeeRates {
+	pub fn of(&self, maker: bool) -> f64 {
+		match maker {
+			true => self.maker,
+			false => self.taker,
+		}
+	}
+
+	fn discounted(self, discount: Option<f64>) -> Self {
+		let keep = 1.0 - discount.unwrap_or(0.0);
+		Self {
+			maker: self.maker * keep,
+			taker: self.taker * keep,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct FeeConfig {
+	/// Rates of each market for symbols the account's own could not be loaded for.
+	pub defaults: HashMap<Market, FeeRates>,
+}
+impl Default for FeeConfig {
+	fn default() -> Self {
+		Self {
+			defaults: HashMap::from([
+				(Market::BinanceFutures, FeeRates { maker: 0.0002, taker: 0.0005 }),
+				(Market::BinanceSpot, FeeRates { maker: 0.001, taker: 0.001 }),
+				(Market::BinanceMargin, FeeRates { maker: 0.001, taker: 0.001 }),
+			]),
+		}
+	}
+}
+
+/// Fee rates of the account per symbol, loaded from the exchange on first use. Clones share the loaded rates.
+#[derive(Clone, Debug)]
+pub struct FeeModel {
+	defaults: HashMap<Market, FeeRates>,
+	key: String,
+	secret: String,
+	loaded: Arc<RwLock<HashMap<Symbol, FeeRates>>>,
+}
+impl FeeModel {
+	pub fn new(config: &AppConfig) -> Self {
+		Self {
+			defaults: config.fees.defaults.clone(),
+			key: config.binance.read_key.clone(),
+			secret: config.binance.read_secret.clone(),
+			loaded: Arc::default(),
+		}
+	}
+
+	/// Without waiting on the exchange: what is loaded, or the defaults of the market.
+	pub fn rates(&self, symbol: &Symbol) -> FeeRates {
+		if let Some(rates) = self.loaded.read().unwrap().get(symbol) {
+			return *rates;
+		}
+		self.defaults.get(&symbol.market).copied().unwrap_or(FeeRates { maker: 0.0, taker: 0.0 })
+	}
+
+	/// Loads the rates of the symbol if they aren't yet. Falls back to the defaults of the market, and tries again on the next call.
+	pub async fn get(&self, symbol: &Symbol) -> FeeRates {
+		if let Some(rates) = self.loaded.read().unwrap().get(symbol) {
+			return *rates;
+		}
+		match self.load(symbol).await {
+			Ok(rates) => {
+				info!(%symbol, market = ?symbol.market, maker = rates.maker, taker = rates.taker, "Loaded fee rates");
+				self.loaded.write().unwrap().insert(symbol.clone(), rates);
+				rates
+			}
+			Err(e) => {
+				warn!(%symbol, market = ?symbol.market, "Failed to load fee rates, going with the defaults: {}", e);
+				self.rates(symbol)
+			}
+		}
+	}
+
+	async fn load(&self, symbol: &Symbol) -> Result<FeeRates> {
+		let (key, secret) = (self.key.clone(), self.secret.clone());
+		match symbol.market {
+			Market::BinanceFutures => {
+				let ((maker, taker), fee_burn) = tokio::try_join!(
+					binance::futures_commission_rate(key.clone(), secret.clone(), symbol.to_string()),
+					binance::futures_fee_burn(key, secret),
+				)?;
+				Ok(FeeRates { maker, taker }.discounted(fee_burn.then_some(FUTURES_BNB_DISCOUNT)))
+			}
+			// margin trades at the spot rates
+			Market::BinanceSpot | Market::BinanceMargin => match binance::spot_symbol_commission(key.clone(), secret.clone(), symbol.to_string()).await {
+				Ok(c) => Ok(FeeRates { maker: c.maker, taker: c.taker }.discounted(c.bnb_discount)),
+				Err(e) => {
+					debug!(%symbol, "No symbol-specific spot rates, taking the account-wide ones: {}", e);
+					let (maker, taker) = binance::spot_account_commission(key, secret).await?;
+					Ok(FeeRates { maker, taker })
+				}
+			},
+		}
+	}
+
+	/// Commission of the fill in USDT. When charged in another asset, ex: BNB, it is estimated from the rates of the symbol instead.
+	pub fn commission_usdt(&self, symbol: &Symbol, fill: &ExchangeFill) -> f64 {
+		fill.commission_usdt().unwrap_or_else(|| {
+			let estimate = fill.last_qty * fill.last_price * self.rates(symbol).of(fill.maker);
+			debug!(fill.commission, fill.commission_asset, estimate, "Commission not in USDT, estimated from the rates");
+			estimate
+		})
+	}
+}
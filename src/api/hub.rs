@@ -0,0 +1,417 @@
+use crate::api::binance::{self, PositionSide};
+use crate::api::executor::{BinanceFuturesExecutor, ExecutionReport, ExecutorCommand};
+use crate::api::fees::FeeModel;
+use crate::api::netting::{self, Allocation};
+use crate::api::order_book::OrderBooks;
+use crate::api::order_types::{ConceptualOrder, Fill};
+use crate::api::user_data::UserDataEvent;
+use crate::api::{Market, Symbol};
+use crate::config::AppConfig;
+use crate::positions::PositionCallback;
+use crate::reconciler::ExposureBook;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, error, warn};
+use uuid::Uuid;
+use v_utils::trades::Side;
+
+/// How often legs are checked for having become shared, or stopped being, through positions of other processes.
+const SHARED_RECHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Single exchange position. In one-way mode there is one per symbol, in hedge mode two.
+pub type Leg = (Symbol, PositionSide);
+
+/// What a position hands over every time its target orders change. An empty `orders` means it no longer wants anything on the exchange.
+#[derive(Debug, Clone)]
+pub struct TargetUpdate {
+	pub orders: Vec<ConceptualOrder>,
+	/// Direction of the position. Orders on this side add to it.
+	pub side: Side,
+	/// Leg all orders of the position go to.
+	pub position_side: PositionSide,
+	/// Id of the last fill the position has processed. Orders computed without knowledge of every fill already sent to it are refused.
+	pub last_fill: Option<Uuid>,
+	/// Cumulative filled notional of each of the orders, as known to the position; fills reported from here on continue from it.
+	pub filled: HashMap<Uuid, f64>,
+	pub callback: PositionCallback,
+}
+
+#[derive(Debug)]
+struct PositionTarget {
+	/// Less whatever has been filled since they were received.
+	orders: Vec<ConceptualOrder>,
+	side: Side,
+	position_side: PositionSide,
+	callback: PositionCallback,
+	/// Last fill sent to the position.
+	last_fill: Option<Uuid>,
+	filled: HashMap<Uuid, f64>,
+	/// Market orders handed to execution that have not come back as fills yet, so that they are not sent twice.
+	in_flight: HashMap<Uuid, f64>,
+	/// Position sent an empty target; kept only until everything in flight for it comes back.
+	released: bool,
+}
+impl PositionTarget {
+	/// What is yet to be put to the exchange.
+	fn outstanding(&self) -> Vec<ConceptualOrder> {
+		self.orders
+			.iter()
+			.filter_map(|o| {
+				let left = o.notional() - self.in_flight.get(&o.id().uuid).unwrap_or(&0.0);
+				(left > 0.0).then(|| {
+					let mut o = o.clone();
+					o.cut_size(left);
+					o
+				})
+			})
+			.collect()
+	}
+}
+
+/// Merges target orders of all positions, nets them per leg and has the executors of each exchange express the result. Fills are routed back to the positions they belong to.
+#[derive(Debug)]
+pub struct Hub {
+	positions: HashMap<Uuid, PositionTarget>,
+	executors: HashMap<Market, mpsc::UnboundedSender<ExecutorCommand>>,
+	books: OrderBooks,
+	/// Of all processes; the only place positions run by the others show up.
+	exposure: ExposureBook,
+	/// Whether each leg was synced as shared.
+	shared: HashMap<Leg, bool>,
+}
+impl Hub {
+	/// Starts the hub and its executors, which run for as long as the process does. Returns the entry for all positions.
+	pub fn spawn(
+		config: &AppConfig,
+		user_data: &broadcast::Sender<UserDataEvent>,
+		books: &OrderBooks,
+		fees: &FeeModel,
+		exposure: &ExposureBook,
+	) -> mpsc::UnboundedSender<TargetUpdate> {
+		let (tx_targets, rx_targets) = mpsc::unbounded_channel();
+		let (tx_reports, rx_reports) = mpsc::unbounded_channel();
+		let mut executors = HashMap::new();
+		executors.insert(
+			Market::BinanceFutures,
+			BinanceFuturesExecutor::new(config, books.clone(), fees.clone(), exposure.clone(), tx_reports).spawn(user_data.subscribe()),
+		);
+		let hub = Self {
+			positions: HashMap::new(),
+			executors,
+			books: books.clone(),
+			exposure: exposure.clone(),
+			shared: HashMap::new(),
+		};
+		tokio::spawn(hub.run(rx_targets, rx_reports));
+		tx_targets
+	}
+
+	async fn run(mut self, mut rx_targets: mpsc::UnboundedReceiver<TargetUpdate>, mut rx_reports: mpsc::UnboundedReceiver<ExecutionReport>) {
+		let mut recheck = tokio::time::interval(SHARED_RECHECK_INTERVAL);
+		loop {
+			select! {
+				Some(update) = rx_targets.recv() => self.on_update(update).await,
+				Some(report) = rx_reports.recv() => self.on_report(report),
+				_ = recheck.tick() => self.recheck_shared().await,
+				else => break,
+			}
+		}
+	}
+
+	/// One-way leg with positions of both sides on it, in this process or any other. None of its orders may then be reduce-only, as the exchange only holds their net.
+	fn is_shared(&self, leg: &Leg) -> bool {
+		let (symbol, position_side) = leg;
+		if *position_side != PositionSide::Both {
+			return false;
+		}
+		let mut sides: Vec<Side> = self
+			.positions
+			.values()
+			.filter(|p| p.position_side == *position_side && p.orders.iter().any(|o| o.symbol() == symbol))
+			.map(|p| p.side.clone())
+			.collect();
+		for held in self.exposure.on_leg(&symbol.to_string(), *position_side) {
+			sides.push(if held > 0.0 { Side::Buy } else { Side::Sell });
+		}
+		sides.contains(&Side::Buy) && sides.contains(&Side::Sell)
+	}
+
+	async fn recheck_shared(&mut self) {
+		let legs: HashSet<Leg> = self
+			.positions
+			.values()
+			.flat_map(|p| p.orders.iter().map(|o| (o.symbol().clone(), p.position_side)))
+			.collect();
+		for leg in legs {
+			if self.is_shared(&leg) != self.shared.get(&leg).copied().unwrap_or(false) {
+				self.sync_leg(&leg).await;
+			}
+		}
+	}
+
+	async fn on_update(&mut self, update: TargetUpdate) {
+		let position_id = update.callback.position_uuid;
+		debug!(%position_id, orders = ?update.orders, "New target orders");
+		// letting go of everything is always safe
+		if let Some(known) = self.positions.get(&position_id) {
+			if !update.orders.is_empty() && known.last_fill != update.last_fill {
+				debug!(%position_id, "Refused target orders made before the last fill was processed; it will send new ones");
+				return;
+			}
+		}
+		let mut legs: HashSet<Leg> = update.orders.iter().map(|o| (o.symbol().clone(), update.position_side)).collect();
+
+		match self.positions.get_mut(&position_id) {
+			Some(known) => {
+				legs.extend(known.orders.iter().map(|o| (o.symbol().clone(), known.position_side)));
+				known.released = update.orders.is_empty();
+				known.orders = update.orders;
+				known.filled.extend(update.filled);
+				known.callback = update.callback;
+			}
+			None if update.orders.is_empty() => return,
+			None => {
+				self.positions.insert(
+					position_id,
+					PositionTarget {
+						orders: update.orders,
+						side: update.side,
+						position_side: update.position_side,
+						callback: update.callback,
+						last_fill: None,
+						filled: update.filled,
+						in_flight: HashMap::new(),
+						released: false,
+					},
+				);
+			}
+		}
+
+		for leg in legs {
+			self.sync_leg(&leg).await;
+		}
+		self.forget_finished();
+	}
+
+	async fn sync_leg(&mut self, leg: &Leg) {
+		let (symbol, position_side) = leg;
+		let Some(executor) = self.executors.get(&symbol.market).cloned() else {
+			error!(%symbol, market = ?symbol.market, "No executor for the market, orders on it are not placed");
+			return;
+		};
+		let targets: HashMap<Uuid, Vec<ConceptualOrder>> = self
+			.positions
+			.iter()
+			.filter(|(_, p)| p.position_side == *position_side)
+			.map(|(id, p)| (*id, p.outstanding()))
+			.collect();
+		let netted = netting::net(&targets, symbol);
+		let shared = self.is_shared(leg);
+		self.shared.insert(leg.clone(), shared);
+
+		if !netted.crossed.is_empty() {
+			let price = match self.books.read(symbol, |b| b.mid()).flatten() {
+				Some(mid) => Ok(mid),
+				None => binance::futures_price(&symbol.base).await,
+			};
+			match price {
+				Ok(price) =>
+					for allocation in netted.crossed {
+						self.report_fill(&allocation, price, 0.0, Utc::now());
+					},
+				// will be crossed again on the next update
+				Err(e) => warn!(%symbol, "No price to match opposite market orders at: {}", e),
+			}
+		}
+
+		let (market, resting): (Vec<_>, Vec<_>) = netted.orders.into_iter().partition(|n| matches!(n.order, ConceptualOrder::Market(_)));
+		for order in market {
+			for allocation in &order.allocations {
+				if let Some(position) = self.positions.get_mut(&allocation.position_id) {
+					*position.in_flight.entry(allocation.protocol_order_id.uuid).or_insert(0.0) += allocation.qty_notional;
+				}
+			}
+			let _ = executor.send(ExecutorCommand::Execute { leg: leg.clone(), order });
+		}
+		let _ = executor.send(ExecutorCommand::Sync {
+			leg: leg.clone(),
+			orders: resting,
+			shared,
+		});
+	}
+
+	fn on_report(&mut self, report: ExecutionReport) {
+		match report {
+			ExecutionReport::Fill {
+				order,
+				last_qty,
+				last_price,
+				commission_usdt,
+				t,
+			} =>
+				for allocation in order.attribute_fill(last_qty) {
+					let commission_share = commission_usdt * allocation.qty_notional / last_qty;
+					self.report_fill(&allocation, last_price, commission_share, t);
+				},
+			ExecutionReport::Unfilled { order, qty, why } => {
+				debug!(?order.order, qty, ?why, "Part of a market order was not filled, it is only retried if the position asks for it again");
+				for allocation in order.attribute_fill(qty) {
+					let Some(position) = self.positions.get_mut(&allocation.position_id) else { continue };
+					let uuid = allocation.protocol_order_id.uuid;
+					if let Some(in_flight) = position.in_flight.get_mut(&uuid) {
+						*in_flight = (*in_flight - allocation.qty_notional).max(0.0);
+					}
+					// or any sync of the leg would send it again
+					if let Some(order) = position.orders.iter_mut().find(|o| o.id().uuid == uuid) {
+						order.cut_size((order.notional() - allocation.qty_notional).max(0.0));
+					}
+					position.orders.retain(|o| o.notional() > 0.0);
+					// rounding leftovers are of no interest
+					if let Some(why) = &why {
+						if let Err(e) = position.callback.send_unfilled(allocation.protocol_order_id.clone(), allocation.qty_notional, why.clone()) {
+							warn!("{}", e);
+						}
+					}
+				}
+			}
+		}
+		self.forget_finished();
+	}
+
+	fn report_fill(&mut self, allocation: &Allocation, last_price: f64, commission_usdt: f64, t: DateTime<Utc>) {
+		let Some(position) = self.positions.get_mut(&allocation.position_id) else {
+			error!(?allocation, "Fill for a position that is not known to the hub");
+			return;
+		};
+		let uuid = allocation.protocol_order_id.uuid;
+		let filled = position.filled.entry(uuid).or_insert(0.0);
+		*filled += allocation.qty_notional;
+		if let Some(in_flight) = position.in_flight.get_mut(&uuid) {
+			*in_flight = (*in_flight - allocation.qty_notional).max(0.0);
+		}
+		// mirrors what the position is going to send once it processes the fill
+		if let Some(order) = position.orders.iter_mut().find(|o| o.id().uuid == uuid) {
+			order.cut_size((order.notional() - allocation.qty_notional).max(0.0));
+		}
+		position.orders.retain(|o| o.notional() > 0.0);
+
+		let fill = Fill {
+			id: allocation.protocol_order_id.clone(),
+			filled_notional: *filled,
+			last_qty: allocation.qty_notional,
+			last_price,
+			commission_usdt,
+			t,
+		};
+		match position.callback.send_fill(fill) {
+			Ok(fill_id) => position.last_fill = Some(fill_id),
+			Err(e) => warn!("{}", e),
+		}
+	}
+
+	/// Positions that want nothing anymore and are owed nothing. Ones that merely had everything filled are kept, as the guard on their last fill still applies.
+	fn forget_finished(&mut self) {
+		self.positions.retain(|_, p| !p.released || p.in_flight.values().any(|q| *q > 0.0));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::order_types::{ConceptualMarket, ConceptualStopMarket, ProtocolOrderId};
+	use crate::positions::ExecutionUpdate;
+
+	fn symbol() -> Symbol {
+		"BTC-USDT-BinanceFutures".parse().unwrap()
+	}
+
+	fn hub(exposure: ExposureBook) -> (Hub, mpsc::UnboundedReceiver<ExecutorCommand>) {
+		let (tx_executor, rx_executor) = mpsc::unbounded_channel();
+		let hub = Hub {
+			positions: HashMap::new(),
+			executors: HashMap::from([(Market::BinanceFutures, tx_executor)]),
+			books: OrderBooks::default(),
+			exposure,
+			shared: HashMap::new(),
+		};
+		(hub, rx_executor)
+	}
+
+	fn update(order: ConceptualOrder, side: Side, callback: PositionCallback) -> TargetUpdate {
+		TargetUpdate {
+			orders: vec![order],
+			side,
+			position_side: PositionSide::Both,
+			last_fill: None,
+			filled: HashMap::new(),
+			callback,
+		}
+	}
+
+	#[tokio::test]
+	async fn unfilled_rest_is_not_resent_by_other_syncs() {
+		let (mut hub, mut rx_executor) = hub(ExposureBook::default());
+		let (tx_updates, mut rx_updates) = mpsc::unbounded_channel();
+		let market = ConceptualOrder::Market(ConceptualMarket {
+			id: ProtocolOrderId::new("acquisition".to_owned(), Uuid::new_v4()),
+			maximum_slippage_percent: 1.0,
+			symbol: symbol(),
+			side: Side::Buy,
+			qty_notional: 0.01,
+		});
+		hub.on_update(update(market, Side::Buy, PositionCallback::new(tx_updates, Uuid::new_v4()))).await;
+		let Ok(ExecutorCommand::Execute { order, .. }) = rx_executor.try_recv() else {
+			panic!("market order was not executed");
+		};
+		hub.on_report(ExecutionReport::Unfilled {
+			order,
+			qty: 0.01,
+			why: Some("expired".to_owned()),
+		});
+		assert!(matches!(rx_updates.try_recv(), Ok(ExecutionUpdate::Unfilled { .. })));
+
+		// another position on the same leg
+		let (tx_other, _rx_other) = mpsc::unbounded_channel();
+		let stop = ConceptualOrder::StopMarket(ConceptualStopMarket {
+			id: ProtocolOrderId::new("ts:p1".to_owned(), Uuid::new_v4()),
+			maximum_slippage_percent: 1.0,
+			symbol: symbol(),
+			side: Side::Buy,
+			price: 70_000.0,
+			qty_notional: 0.01,
+		});
+		hub.on_update(update(stop, Side::Sell, PositionCallback::new(tx_other, Uuid::new_v4()))).await;
+		while let Ok(command) = rx_executor.try_recv() {
+			assert!(!matches!(command, ExecutorCommand::Execute { .. }), "unfilled order was placed again: {command:?}");
+		}
+	}
+
+	#[tokio::test]
+	async fn legs_are_shared_with_positions_of_other_processes() {
+		let exposure = ExposureBook::default();
+		let other = Uuid::new_v4();
+		exposure.set_position(other, symbol().to_string(), PositionSide::Both, -0.01);
+		let (mut hub, mut rx_executor) = hub(exposure.clone());
+		let (tx_updates, _rx_updates) = mpsc::unbounded_channel();
+		let stop = ConceptualOrder::StopMarket(ConceptualStopMarket {
+			id: ProtocolOrderId::new("ts:p1".to_owned(), Uuid::new_v4()),
+			maximum_slippage_percent: 1.0,
+			symbol: symbol(),
+			side: Side::Sell,
+			price: 50_000.0,
+			qty_notional: 0.01,
+		});
+		hub.on_update(update(stop, Side::Buy, PositionCallback::new(tx_updates, Uuid::new_v4()))).await;
+		assert!(matches!(rx_executor.try_recv(), Ok(ExecutorCommand::Sync { shared: true, .. })));
+
+		hub.recheck_shared().await;
+		assert!(rx_executor.try_recv().is_err());
+
+		exposure.remove_position(&other);
+		hub.recheck_shared().await;
+		assert!(matches!(rx_executor.try_recv(), Ok(ExecutorCommand::Sync { shared: false, .. })));
+	}
+}
@@ -1,10 +1,19 @@
 pub mod binance;
-use uuid::Uuid;
+pub mod diff;
+pub mod executor;
+pub mod fees;
+pub mod hub;
+pub mod netting;
+pub mod order_book;
 pub mod order_types;
-use crate::{config::AppConfig, PositionCallback};
+pub mod rate_limit;
+pub mod user_data;
+use crate::config::AppConfig;
 use anyhow::Result;
 use order_types::ConceptualOrder;
+use serde::{Deserialize, Serialize};
 use url::Url;
+use uuid::Uuid;
 use v_utils::macros::graphemics;
 
 pub async fn compile_total_balance(config: AppConfig) -> Result<f64> {
@@ -31,57 +40,10 @@ pub async fn round_to_required_precision(coin: String, quantity: f64) -> Result<
 	Ok(quantity_adjusted)
 }
 
-// Orders struct
-// needs to:
-//	+ receive updates of the target order placement
-// 	+ send back the total_notional of an executed order immediately
-
-// 	+ well, do the execution, and in a manner that the target order distribution can be updated midway
-// So in practice, we want to write to a local Arc<Mutex<T>>, which contains updated target orders for each exchange, which are uploaded according to the maximum frequency they allow.
-
-//TODO!: require providing id of the position of origin.
-/// the global access rx for this is shared among all positions. Each position provides a watch::Sender, to receiver
-/// Currently hard-codes for a single position.
-pub fn i_have_no_clue_how_to_represent_this(rx: mpsc::Receiver<(Vec<ConceptualOrder>, PositionCallback)>) {
-	//- init the runtime of exchanges
-
-	//- merge new recv() with the rest of the known orders globally across all positions.
-
-	//- translate all into exact actual orders on specific exchanges if we were placing them now.
-	// // each ActualOrder must pertain the id of the ConceptualOrder instance it is expressing
-
-	//- compare with the current, calculate the costs of moving (tx between exchanges, latency exposure, spinning the limit), produce final target actual orders for each exchange.
-
-	//- send the batch of new exact orders to the controlling runtime of each exchange.
-	// // these are started locally, as none can be initiated through other means.
-
-	// let mut orders = HashMap::new();
-	// let mut total_notional = 0.0;
-	// let mut total_notional_executed = 0.0;
-	// let mut total_notional_remaining = 0.0;
-
-	// loop {
-	// 	let order = rx.recv().unwrap();
-	// 	match order {
-	// 		ConceptualOrder::Market(m) => {
-	// 			total_notional += m.qty_notional;
-	// 			total_notional_remaining += m.qty_notional;
-	// 		}
-	// 		ConceptualOrder::Limit(l) => {
-	// 			total_notional += l.qty_notional;
-	// 			total_notional_remaining += l.qty_notional;
-	// 		}
-	// 		ConceptualOrder::StopMarket(s) => {
-	// 			total_notional += s.qty_notional;
-	// 			total_notional_remaining += s.qty_notional;
-	// 		}
-	// 	}
-	// }
-}
-
 // translation layer: Vec<ConceptualOrder> -> ActualOrders
 
 // want one runtime handling all of the positions at once, so as not to have to impose artificial requirements on positions containing the same ticker.
+// Positions on the same ticker are combined by `netting::net` in the `hub`, and fills on the resulting orders are split back between them with `NettedOrder::attribute_fill`.
 
 pub struct ActualOrders {
 	pub snapshot_target_orders: Vec<ConceptualOrder>,
@@ -90,7 +52,7 @@ pub struct ActualOrders {
 }
 
 #[allow(dead_code)]
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Market {
 	BinanceFutures,
 	BinanceSpot,
@@ -131,7 +93,7 @@ impl std::str::FromStr for Market {
 ///```rust
 ///let symbol = "BTC-USDT-BinanceFutures".parse::<Symbol>().unwrap();
 ///```
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Symbol {
 	pub base: String,
 	pub quote: String,
@@ -0,0 +1,273 @@
+use super::order_types::{ConceptualLimit, ConceptualMarket, ConceptualOrder, ConceptualStopMarket, ProtocolOrderId};
+use super::Symbol;
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+use v_utils::trades::Side;
+
+/// Share of an exchange-level order that belongs to a single protocol order of a single position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Allocation {
+	pub position_id: Uuid,
+	pub protocol_order_id: ProtocolOrderId,
+	pub qty_notional: f64,
+}
+
+/// One order as it should exist on the exchange, possibly expressing orders of several positions at once.
+/// Carries the id of its largest contributor, so that it stays stable while the set of contributors does not change much.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NettedOrder {
+	pub order: ConceptualOrder,
+	pub allocations: Vec<Allocation>,
+}
+impl NettedOrder {
+	/// Splits an (incremental) fill of the netted order between its contributors, pro-rata to their sizes.
+	pub fn attribute_fill(&self, filled_notional: f64) -> Vec<Allocation> {
+		let total: f64 = self.allocations.iter().map(|a| a.qty_notional).sum();
+		if total == 0.0 {
+			return Vec::new();
+		}
+		self.allocations
+			.iter()
+			.map(|a| Allocation {
+				qty_notional: filled_notional * a.qty_notional / total,
+				..a.clone()
+			})
+			.collect()
+	}
+}
+
+/// Everything the positions want on a single symbol, reduced to what actually has to be on the exchange.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NettedTarget {
+	pub orders: Vec<NettedOrder>,
+	/// Portions of market orders of opposite sides, matched against each other internally. They never reach the exchange, and are to be reported as filled right away.
+	pub crossed: Vec<Allocation>,
+}
+
+/// Nets target orders of all positions on the `symbol` into a single exchange position.
+/// In hedge mode, each leg is a separate exchange position, so this is to be called with the positions of one leg at a time.
+/// - market orders of opposite sides cancel out, what is left of the bigger side is sent as one order
+/// - resting orders of the same type, side and price are merged
+pub fn net(targets: &HashMap<Uuid, Vec<ConceptualOrder>>, symbol: &Symbol) -> NettedTarget {
+	let mut contributions: Vec<(Uuid, &ConceptualOrder)> = targets
+		.iter()
+		.flat_map(|(position_id, orders)| orders.iter().map(move |o| (*position_id, o)))
+		.filter(|(_, o)| o.symbol() == symbol)
+		.collect();
+	// HashMap iteration order is random, while the output should not be.
+	contributions.sort_by(|a, b| (a.0, a.1.id().uuid).cmp(&(b.0, b.1.id().uuid)));
+
+	let mut netted = NettedTarget::default();
+
+	// Market {{{
+	let (buys, sells): (Vec<_>, Vec<_>) = contributions
+		.iter()
+		.filter(|(_, o)| matches!(o, ConceptualOrder::Market(_)))
+		.partition(|(_, o)| o.side() == &Side::Buy);
+	let buy_total: f64 = buys.iter().map(|(_, o)| o.notional()).sum();
+	let sell_total: f64 = sells.iter().map(|(_, o)| o.notional()).sum();
+	let crossed_total = buy_total.min(sell_total);
+
+	for (side_orders, side_total) in [(&buys, buy_total), (&sells, sell_total)] {
+		if side_total == 0.0 {
+			continue;
+		}
+		let crossed_fraction = crossed_total / side_total;
+		let mut allocations = Vec::new();
+		for (position_id, order) in side_orders.iter() {
+			let crossed = order.notional() * crossed_fraction;
+			if crossed > 0.0 {
+				netted.crossed.push(Allocation {
+					position_id: *position_id,
+					protocol_order_id: order.id().clone(),
+					qty_notional: crossed,
+				});
+			}
+			if order.notional() - crossed > 0.0 {
+				allocations.push(Allocation {
+					position_id: *position_id,
+					protocol_order_id: order.id().clone(),
+					qty_notional: order.notional() - crossed,
+				});
+			}
+		}
+		if allocations.is_empty() {
+			continue;
+		}
+		let contributors: Vec<&ConceptualMarket> = side_orders
+			.iter()
+			.filter_map(|(_, o)| match o {
+				ConceptualOrder::Market(m) => Some(m),
+				_ => None,
+			})
+			.collect();
+		let largest = largest_allocation(&allocations);
+		netted.orders.push(NettedOrder {
+			order: ConceptualOrder::Market(ConceptualMarket {
+				id: largest,
+				// strictest of the requested
+				maximum_slippage_percent: contributors.iter().map(|m| m.maximum_slippage_percent).fold(f64::INFINITY, f64::min),
+				symbol: symbol.clone(),
+				side: contributors[0].side.clone(),
+				qty_notional: allocations.iter().map(|a| a.qty_notional).sum(),
+			}),
+			allocations,
+		});
+	}
+	//,}}}
+
+	// Resting {{{
+	// keyed on everything that makes two orders interchangeable on the exchange
+	let mut resting: BTreeMap<(u8, bool, u64, bool), Vec<(Uuid, &ConceptualOrder)>> = BTreeMap::new();
+	for (position_id, order) in contributions.iter() {
+		let key = match order {
+			ConceptualOrder::Market(_) => continue,
+			ConceptualOrder::Limit(l) => (0, l.side == Side::Buy, l.price.to_bits(), l.limit_only),
+			ConceptualOrder::StopMarket(s) => (1, s.side == Side::Buy, s.price.to_bits(), false),
+		};
+		resting.entry(key).or_default().push((*position_id, order));
+	}
+	for (_, group) in resting {
+		let allocations: Vec<Allocation> = group
+			.iter()
+			.map(|(position_id, o)| Allocation {
+				position_id: *position_id,
+				protocol_order_id: o.id().clone(),
+				qty_notional: o.notional(),
+			})
+			.collect();
+		let qty_notional: f64 = allocations.iter().map(|a| a.qty_notional).sum();
+		let id = largest_allocation(&allocations);
+		let order = match group[0].1 {
+			ConceptualOrder::Limit(l) => ConceptualOrder::Limit(ConceptualLimit {
+				id,
+				qty_notional,
+				..l.clone()
+			}),
+			ConceptualOrder::StopMarket(s) => ConceptualOrder::StopMarket(ConceptualStopMarket {
+				id,
+				qty_notional,
+				maximum_slippage_percent: group
+					.iter()
+					.filter_map(|(_, o)| match o {
+						ConceptualOrder::StopMarket(s) => Some(s.maximum_slippage_percent),
+						_ => None,
+					})
+					.fold(f64::INFINITY, f64::min),
+				..s.clone()
+			}),
+			ConceptualOrder::Market(_) => unreachable!(),
+		};
+		netted.orders.push(NettedOrder { order, allocations });
+	}
+	//,}}}
+
+	netted
+}
+
+fn largest_allocation(allocations: &[Allocation]) -> ProtocolOrderId {
+	allocations
+		.iter()
+		.max_by(|a, b| a.qty_notional.partial_cmp(&b.qty_notional).unwrap_or(std::cmp::Ordering::Equal))
+		.unwrap()
+		.protocol_order_id
+		.clone()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn symbol() -> Symbol {
+		"BTC-USDT-BinanceFutures".parse().unwrap()
+	}
+
+	fn id() -> ProtocolOrderId {
+		ProtocolOrderId::new("test".to_owned(), Uuid::new_v4())
+	}
+
+	fn market(side: Side, qty_notional: f64) -> ConceptualOrder {
+		ConceptualOrder::Market(ConceptualMarket {
+			id: id(),
+			maximum_slippage_percent: 1.0,
+			symbol: symbol(),
+			side,
+			qty_notional,
+		})
+	}
+
+	fn stop(side: Side, price: f64, qty_notional: f64) -> ConceptualOrder {
+		ConceptualOrder::StopMarket(ConceptualStopMarket {
+			id: id(),
+			maximum_slippage_percent: 1.0,
+			symbol: symbol(),
+			side,
+			price,
+			qty_notional,
+		})
+	}
+
+	#[test]
+	fn opposite_market_orders_cross() {
+		let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+		let targets = HashMap::from([(a, vec![market(Side::Buy, 3.0)]), (b, vec![market(Side::Sell, 1.0)])]);
+		let netted = net(&targets, &symbol());
+
+		assert_eq!(netted.orders.len(), 1);
+		let order = &netted.orders[0];
+		assert_eq!(order.order.side(), &Side::Buy);
+		assert_eq!(order.order.notional(), 2.0);
+		assert_eq!(order.allocations.len(), 1);
+		assert_eq!(order.allocations[0].position_id, a);
+
+		let crossed: f64 = netted.crossed.iter().map(|c| c.qty_notional).sum();
+		assert_eq!(crossed, 2.0);
+		assert!(netted.crossed.iter().any(|c| c.position_id == b && c.qty_notional == 1.0));
+	}
+
+	#[test]
+	fn equal_resting_orders_merge_under_the_largest() {
+		let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+		let big = stop(Side::Sell, 100.0, 2.0);
+		let targets = HashMap::from([(a, vec![big.clone()]), (b, vec![stop(Side::Sell, 100.0, 1.0)])]);
+		let netted = net(&targets, &symbol());
+
+		assert!(netted.crossed.is_empty());
+		assert_eq!(netted.orders.len(), 1);
+		assert_eq!(netted.orders[0].order.notional(), 3.0);
+		assert_eq!(netted.orders[0].order.id(), big.id());
+		assert_eq!(netted.orders[0].allocations.len(), 2);
+	}
+
+	#[test]
+	fn differing_resting_orders_stay_apart() {
+		let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+		let targets = HashMap::from([(a, vec![stop(Side::Sell, 100.0, 1.0)]), (b, vec![stop(Side::Buy, 120.0, 1.0)])]);
+		let netted = net(&targets, &symbol());
+
+		assert_eq!(netted.orders.len(), 2);
+		assert!(netted.orders.iter().all(|o| o.allocations.len() == 1));
+	}
+
+	#[test]
+	fn other_symbols_are_ignored() {
+		let mut other = market(Side::Buy, 1.0);
+		if let ConceptualOrder::Market(m) = &mut other {
+			m.symbol.market = crate::api::Market::BinanceSpot;
+		}
+		let targets = HashMap::from([(Uuid::new_v4(), vec![other])]);
+		assert_eq!(net(&targets, &symbol()), NettedTarget::default());
+	}
+
+	#[test]
+	fn fills_are_attributed_pro_rata() {
+		let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+		let targets = HashMap::from([(a, vec![stop(Side::Sell, 100.0, 3.0)]), (b, vec![stop(Side::Sell, 100.0, 1.0)])]);
+		let netted = net(&targets, &symbol());
+
+		let shares = netted.orders[0].attribute_fill(2.0);
+		let share_of = |id: Uuid| shares.iter().find(|s| s.position_id == id).unwrap().qty_notional;
+		assert_eq!(share_of(a), 1.5);
+		assert_eq!(share_of(b), 0.5);
+	}
+}
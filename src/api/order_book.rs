@@ -0,0 +1,307 @@
+use crate::api::rate_limit;
+use crate::api::{Market, Symbol};
+use anyhow::{anyhow, bail, Result};
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::{info, warn};
+use v_utils::trades::Side;
+
+const SNAPSHOT_LIMIT: u32 = 1000;
+const MAX_RESYNC_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Price as a key of the book; exchanges never send NaNs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Price(f64);
+impl Eq for Price {}
+impl PartialOrd for Price {
+	fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+		Some(self.cmp(other))
+	}
+}
+impl Ord for Price {
+	fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+		self.0.total_cmp(&other.0)
+	}
+}
+
+/// Local L2 book of one symbol: quantity resting at each price level.
+#[derive(Clone, Debug, Default)]
+pub struct OrderBook {
+	bids: BTreeMap<Price, f64>,
+	asks: BTreeMap<Price, f64>,
+	/// Of the snapshot or the last diff applied on top of it.
+	last_update_id: u64,
+}
+impl OrderBook {
+	pub fn best_bid(&self) -> Option<(f64, f64)> {
+		self.bids.iter().next_back().map(|(p, q)| (p.0, *q))
+	}
+
+	pub fn best_ask(&self) -> Option<(f64, f64)> {
+		self.asks.iter().next().map(|(p, q)| (p.0, *q))
+	}
+
+	pub fn mid(&self) -> Option<f64> {
+		Some((self.best_bid()?.0 + self.best_ask()?.0) / 2.0)
+	}
+
+	/// Levels an order of `side` would take from, best first.
+	pub fn levels(&self, side: &Side) -> Box<dyn Iterator<Item = (f64, f64)> + '_> {
+		match side {
+			Side::Buy => Box::new(self.asks.iter().map(|(p, q)| (p.0, *q))),
+			Side::Sell => Box::new(self.bids.iter().rev().map(|(p, q)| (p.0, *q))),
+		}
+	}
+
+	fn apply_levels(book: &mut BTreeMap<Price, f64>, levels: &[(String, String)]) -> Result<()> {
+		for (price, qty) in levels {
+			let (price, qty) = (Price(price.parse()?), qty.parse::<f64>()?);
+			match qty == 0.0 {
+				true => book.remove(&price),
+				false => book.insert(price, qty),
+			};
+		}
+		Ok(())
+	}
+
+	/// One-off snapshot from REST, not kept up to date.
+	pub async fn fetch(symbol: &Symbol) -> Result<Self> {
+		let depth_path = match symbol.market {
+			Market::BinanceFutures => "/fapi/v1/depth",
+			Market::BinanceSpot | Market::BinanceMargin => "/api/v3/depth",
+		};
+		let mut url = symbol.market.get_base_url().join(depth_path)?;
+		url.query_pairs_mut()
+			.append_pair("symbol", &symbol.to_string())
+			.append_pair("limit", &SNAPSHOT_LIMIT.to_string());
+		let snapshot: DepthSnapshot = rate_limit::get(url).await?.error_for_status()?.json().await?;
+		Self::from_snapshot(&snapshot)
+	}
+
+	fn from_snapshot(snapshot: &DepthSnapshot) -> Result<Self> {
+		let mut book = Self {
+			last_update_id: snapshot.lastUpdateId,
+			..Default::default()
+		};
+		Self::apply_levels(&mut book.bids, &snapshot.bids)?;
+		Self::apply_levels(&mut book.asks, &snapshot.asks)?;
+		Ok(book)
+	}
+
+	/// Applies the diff if it continues from where the book is. `Ok(false)` if it is from before the snapshot, and so is skipped.
+	fn apply_diff(&mut self, diff: &DepthDiff, first: bool) -> Result<bool> {
+		if diff.u <= self.last_update_id {
+			return Ok(false);
+		}
+		let continues = match (first, diff.pu) {
+			// the first one straddles the snapshot
+			(true, _) => diff.U <= self.last_update_id + 1,
+			// futures say which diff this one follows
+			(false, Some(pu)) => pu == self.last_update_id,
+			(false, None) => diff.U == self.last_update_id + 1,
+		};
+		if !continues {
+			bail!("Diff {}..={} does not follow {}", diff.U, diff.u, self.last_update_id);
+		}
+		Self::apply_levels(&mut self.bids, &diff.b)?;
+		Self::apply_levels(&mut self.asks, &diff.a)?;
+		self.last_update_id = diff.u;
+		Ok(true)
+	}
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(non_snake_case)]
+struct DepthSnapshot {
+	lastUpdateId: u64,
+	bids: Vec<(String, String)>,
+	asks: Vec<(String, String)>,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(non_snake_case)]
+struct DepthDiff {
+	U: u64,
+	u: u64,
+	/// Only on futures.
+	pu: Option<u64>,
+	b: Vec<(String, String)>,
+	a: Vec<(String, String)>,
+}
+
+/// Books of every symbol anyone asked for, kept up to date in the background. Reads are synchronous.
+#[derive(Clone, Debug, Default)]
+pub struct OrderBooks {
+	/// Only holds books that are in sync; one is taken out for as long as it is being resynced.
+	books: Arc<RwLock<HashMap<Symbol, OrderBook>>>,
+	followed: Arc<RwLock<Vec<Symbol>>>,
+}
+impl OrderBooks {
+	/// Starts maintaining the book of `symbol`, unless it already is. Runs for as long as the process does.
+	pub fn follow(&self, symbol: &Symbol) {
+		{
+			let mut followed = self.followed.write().unwrap();
+			if followed.contains(symbol) {
+				return;
+			}
+			followed.push(symbol.clone());
+		}
+		let (books, symbol) = (self.books.clone(), symbol.clone());
+		tokio::spawn(async move {
+			let mut backoff = Duration::from_secs(1);
+			loop {
+				let started = tokio::time::Instant::now();
+				if let Err(e) = maintain(&books, &symbol).await {
+					warn!(%symbol, "Order book out of sync, resyncing: {}", e);
+				}
+				books.write().unwrap().remove(&symbol);
+				if started.elapsed() > MAX_RESYNC_BACKOFF {
+					backoff = Duration::from_secs(1);
+				}
+				tokio::time::sleep(backoff).await;
+				backoff = (backoff * 2).min(MAX_RESYNC_BACKOFF);
+			}
+		});
+	}
+
+	/// `None` if the book is not followed or is being resynced.
+	pub fn read<R>(&self, symbol: &Symbol, f: impl FnOnce(&OrderBook) -> R) -> Option<R> {
+		self.books.read().unwrap().get(symbol).map(f)
+	}
+}
+
+/// Single connection: subscribes to the diffs, lays them over a snapshot and keeps applying them. Only returns on a gap or a lost connection.
+async fn maintain(books: &RwLock<HashMap<Symbol, OrderBook>>, symbol: &Symbol) -> Result<()> {
+	let stream_symbol = symbol.to_string().to_lowercase();
+	let ws_base = match symbol.market {
+		Market::BinanceFutures => "wss://fstream.binance.com/ws",
+		Market::BinanceSpot | Market::BinanceMargin => "wss://stream.binance.com:9443/ws",
+	};
+	let (ws_stream, _) = connect_async(url::Url::parse(&format!("{ws_base}/{stream_symbol}@depth@100ms"))?).await?;
+	let (mut write, mut read) = ws_stream.split();
+
+	// diffs sent in the meantime wait in the socket; the snapshot is only shared once the first of them is laid over it
+	let mut pending = Some(OrderBook::fetch(symbol).await?);
+
+	while let Some(msg) = read.next().await {
+		let text = match msg? {
+			Message::Text(text) => text,
+			Message::Ping(payload) => {
+				write.send(Message::Pong(payload)).await?;
+				continue;
+			}
+			Message::Close(frame) => bail!("Closed by the server: {:?}", frame),
+			_ => continue,
+		};
+		let diff: DepthDiff = serde_json::from_str(&text).map_err(|e| anyhow!("Failed to parse a depth diff: {}", e))?;
+		match pending.as_mut() {
+			Some(book) => {
+				if book.apply_diff(&diff, true)? {
+					info!(%symbol, last_update_id = book.last_update_id, "Order book in sync");
+					books.write().unwrap().insert(symbol.clone(), pending.take().unwrap());
+				}
+			}
+			None => {
+				let mut books = books.write().unwrap();
+				let book = books.get_mut(symbol).ok_or_else(|| anyhow!("Book was taken out from under us"))?;
+				book.apply_diff(&diff, false)?;
+			}
+		}
+	}
+	bail!("Depth stream ended")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+
+	/// Book holding just the given `(price, qty)` levels.
+	pub(crate) fn book_of(bids: &[(f64, f64)], asks: &[(f64, f64)]) -> OrderBook {
+		OrderBook {
+			bids: bids.iter().map(|(p, q)| (Price(*p), *q)).collect(),
+			asks: asks.iter().map(|(p, q)| (Price(*p), *q)).collect(),
+			last_update_id: 0,
+		}
+	}
+
+	fn book() -> OrderBook {
+		let snapshot: DepthSnapshot = serde_json::from_str(
+			r#"{
+				"lastUpdateId": 100,
+				"bids": [["99.0", "2.0"], ["98.0", "3.0"], ["97.0", "0.0"]],
+				"asks": [["101.0", "1.0"], ["102.0", "4.0"]]
+			}"#,
+		)
+		.unwrap();
+		OrderBook::from_snapshot(&snapshot).unwrap()
+	}
+
+	fn diff(json: &str) -> DepthDiff {
+		serde_json::from_str(json).unwrap()
+	}
+
+	#[test]
+	fn snapshot_is_laid_out_by_price() {
+		let book = book();
+		assert_eq!(book.last_update_id, 100);
+		assert_eq!(book.best_bid(), Some((99.0, 2.0)));
+		assert_eq!(book.best_ask(), Some((101.0, 1.0)));
+		assert_eq!(book.mid(), Some(100.0));
+		assert_eq!(book.levels(&Side::Buy).collect::<Vec<_>>(), vec![(101.0, 1.0), (102.0, 4.0)]);
+		// zero quantity in the snapshot is no level at all
+		assert_eq!(book.levels(&Side::Sell).collect::<Vec<_>>(), vec![(99.0, 2.0), (98.0, 3.0)]);
+	}
+
+	#[test]
+	fn empty_side_has_no_top() {
+		let mut book = book();
+		book.asks.clear();
+		assert_eq!(book.best_ask(), None);
+		assert_eq!(book.mid(), None);
+	}
+
+	#[test]
+	fn diffs_from_before_the_snapshot_are_skipped() {
+		let mut book = book();
+		assert!(!book.apply_diff(&diff(r#"{"U": 90, "u": 100, "b": [["99.0", "0"]], "a": []}"#), true).unwrap());
+		assert_eq!(book.best_bid(), Some((99.0, 2.0)));
+		assert_eq!(book.last_update_id, 100);
+	}
+
+	#[test]
+	fn first_diff_straddles_the_snapshot() {
+		let mut book = book();
+		assert!(book.apply_diff(&diff(r#"{"U": 95, "u": 105, "b": [["99.5", "1.0"]], "a": [["101.0", "0"]]}"#), true).unwrap());
+		assert_eq!(book.last_update_id, 105);
+		assert_eq!(book.best_bid(), Some((99.5, 1.0)));
+		// zero quantity removes the level
+		assert_eq!(book.best_ask(), Some((102.0, 4.0)));
+
+		let mut gapped = self::book();
+		assert!(gapped.apply_diff(&diff(r#"{"U": 102, "u": 105, "b": [], "a": []}"#), true).is_err());
+	}
+
+	#[test]
+	fn spot_diffs_continue_by_id() {
+		let mut book = book();
+		assert!(book.apply_diff(&diff(r#"{"U": 101, "u": 103, "b": [], "a": []}"#), false).unwrap());
+		assert!(book.apply_diff(&diff(r#"{"U": 104, "u": 110, "b": [["98.0", "5.0"]], "a": []}"#), false).unwrap());
+		assert_eq!(book.levels(&Side::Sell).nth(1), Some((98.0, 5.0)));
+		assert!(book.apply_diff(&diff(r#"{"U": 112, "u": 115, "b": [], "a": []}"#), false).is_err());
+		assert_eq!(book.last_update_id, 110);
+	}
+
+	#[test]
+	fn futures_diffs_continue_by_previous_id() {
+		let mut book = book();
+		// ids are not contiguous on futures; only `pu` says what the diff follows
+		assert!(book.apply_diff(&diff(r#"{"U": 105, "u": 108, "pu": 100, "b": [], "a": []}"#), false).unwrap());
+		assert!(book.apply_diff(&diff(r#"{"U": 111, "u": 120, "pu": 108, "b": [], "a": []}"#), false).unwrap());
+		assert!(book.apply_diff(&diff(r#"{"U": 125, "u": 130, "pu": 122, "b": [], "a": []}"#), false).is_err());
+		assert_eq!(book.last_update_id, 120);
+	}
+}
@@ -1,5 +1,7 @@
 use crate::api::Symbol;
 use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use v_utils::trades::Side;
 
@@ -7,7 +9,7 @@ use v_utils::trades::Side;
 
 //TODO!!: automatically derive the Protocol Order types (by substituting `size` with `percent_size`, then auto-implementation of the conversion. Looks like I'm making a `discretionary_engine_macros` crate specifically to for this.
 
-#[derive(Debug, Hash, Clone, PartialEq)]
+#[derive(Debug, Hash, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ProtocolOrderId {
 	pub produced_by: String,
 	pub uuid: Uuid,
@@ -21,8 +23,25 @@ impl ProtocolOrderId {
 	}
 }
 
+/// Report of a single (partial) execution of an order, in the same terms exchanges report them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Fill {
+	pub id: ProtocolOrderId,
+	/// Cumulative over the whole order.
+	pub filled_notional: f64,
+	/// Quantity of this execution alone. Absent in journals written before v4.
+	#[serde(default)]
+	pub last_qty: f64,
+	#[serde(default)]
+	pub last_price: f64,
+	#[serde(default)]
+	pub commission_usdt: f64,
+	#[serde(default = "Utc::now")]
+	pub t: DateTime<Utc>,
+}
+
 /// Generics for defining order types and their whereabouts. Details of execution do not concern us here. We are only trying to specify what we are trying to capture.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ConceptualOrder {
 	Market(ConceptualMarket),
 	Limit(ConceptualLimit),
@@ -45,6 +64,30 @@ impl ConceptualOrder {
 		}
 	}
 
+	pub fn id(&self) -> &ProtocolOrderId {
+		match self {
+			ConceptualOrder::Market(m) => &m.id,
+			ConceptualOrder::Limit(l) => &l.id,
+			ConceptualOrder::StopMarket(s) => &s.id,
+		}
+	}
+
+	pub fn symbol(&self) -> &Symbol {
+		match self {
+			ConceptualOrder::Market(m) => &m.symbol,
+			ConceptualOrder::Limit(l) => &l.symbol,
+			ConceptualOrder::StopMarket(s) => &s.symbol,
+		}
+	}
+
+	pub fn side(&self) -> &Side {
+		match self {
+			ConceptualOrder::Market(m) => &m.side,
+			ConceptualOrder::Limit(l) => &l.side,
+			ConceptualOrder::StopMarket(s) => &s.side,
+		}
+	}
+
 	pub fn cut_size(&mut self, new: f64) {
 		match self {
 			ConceptualOrder::Market(m) => m.qty_notional = new,
@@ -55,7 +98,7 @@ impl ConceptualOrder {
 }
 
 /// Will be executed via above-the-price limits most of the time to prevent excessive slippages.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ConceptualMarket {
 	pub id: ProtocolOrderId,
 	/// 1.0 will be translated into an actual Market order. Others, most of the time, will be expressed via limit orders.
@@ -65,7 +108,7 @@ pub struct ConceptualMarket {
 	pub qty_notional: f64,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ConceptualStopMarket {
 	pub id: ProtocolOrderId,
 	/// 1.0 will be translated into an actual Market order. Others, most of the time, will be expressed via limit orders.
@@ -75,7 +118,7 @@ pub struct ConceptualStopMarket {
 	pub price: f64,
 	pub qty_notional: f64,
 }
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ConceptualLimit {
 	pub id: ProtocolOrderId,
 	pub symbol: Symbol,
@@ -89,7 +132,7 @@ pub struct ConceptualLimit {
 // Apparently, this is how we're pushing orders up to later be chosen and assigned sizes
 //=============================================================================
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ConceptualOrderPercents {
 	Market(ConceptualMarketPercents),
 	Limit(ConceptualLimitPercents),
@@ -106,7 +149,7 @@ impl ConceptualOrderPercents {
 	}
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ConceptualMarketPercents {
 	pub maximum_slippage_percent: f64,
 	pub symbol: Symbol,
@@ -126,7 +169,7 @@ impl ConceptualMarketPercents {
 	}
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ConceptualStopMarketPercents {
 	pub maximum_slippage_percent: f64,
 	pub symbol: Symbol,
@@ -148,7 +191,7 @@ impl ConceptualStopMarketPercents {
 	}
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ConceptualLimitPercents {
 	pub symbol: Symbol,
 	pub side: Side,
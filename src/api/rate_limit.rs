@@ -0,0 +1,399 @@
+use crate::api::binance::RateLimit;
+use anyhow::Result;
+use reqwest::{Method, StatusCode};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use tracing::{error, warn};
+
+/// Order in which requests get through once the limits are close: whatever waits is served in it. Protective ones can use up the whole budget, others leave some to them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+	/// Stops and anything reduce-only.
+	Protective,
+	/// Other orders and cancels.
+	Trading,
+	/// Queries.
+	Info,
+}
+impl Priority {
+	fn share(&self) -> f64 {
+		match self {
+			Priority::Protective => 1.0,
+			Priority::Trading => 0.95,
+			Priority::Info => 0.8,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum LimitKind {
+	RequestWeight,
+	Orders,
+}
+
+#[derive(Debug)]
+struct Budget {
+	limit: u32,
+	interval: Duration,
+	used: u32,
+	/// Binance windows are aligned to the clock, so the index of the current one identifies it.
+	window: u64,
+}
+impl Budget {
+	fn new(limit: u32, interval: Duration) -> Self {
+		Self {
+			limit,
+			interval,
+			used: 0,
+			window: 0,
+		}
+	}
+
+	fn keyed(self, kind: LimitKind) -> ((LimitKind, Duration), Budget) {
+		((kind, self.interval), self)
+	}
+
+	fn roll(&mut self, now: Duration) {
+		let window = (now.as_millis() / self.interval.as_millis()) as u64;
+		if window != self.window {
+			self.window = window;
+			self.used = 0;
+		}
+	}
+
+	fn until_next_window(&self, now: Duration) -> Duration {
+		let interval_ms = self.interval.as_millis() as u64;
+		Duration::from_millis(interval_ms - (now.as_millis() as u64 % interval_ms))
+	}
+}
+
+#[derive(Debug)]
+struct HostLimits {
+	budgets: HashMap<(LimitKind, Duration), Budget>,
+	banned_until: Option<Duration>,
+	/// Requests waiting to go out, by priority and then arrival. Only the first one may take from the budgets.
+	queue: BTreeSet<(Priority, u64)>,
+	next_ticket: u64,
+	/// Woken whenever the first in the queue changes.
+	queue_moved: Arc<Notify>,
+}
+impl HostLimits {
+	/// Binance's published limits, for until the ones from exchangeInfo are loaded.
+	fn defaults(host: &str) -> Self {
+		let minute = Duration::from_secs(60);
+		let budgets = match host {
+			"fapi.binance.com" => vec![
+				Budget::new(2400, minute).keyed(LimitKind::RequestWeight),
+				Budget::new(1200, minute).keyed(LimitKind::Orders),
+				Budget::new(300, Duration::from_secs(10)).keyed(LimitKind::Orders),
+			],
+			_ => vec![
+				Budget::new(6000, minute).keyed(LimitKind::RequestWeight),
+				Budget::new(100, Duration::from_secs(10)).keyed(LimitKind::Orders),
+				Budget::new(200_000, Duration::from_secs(86400)).keyed(LimitKind::Orders),
+			],
+		};
+		Self {
+			budgets: budgets.into_iter().collect(),
+			banned_until: None,
+			queue: BTreeSet::new(),
+			next_ticket: 0,
+			queue_moved: Arc::new(Notify::new()),
+		}
+	}
+
+	fn enqueue(&mut self, priority: Priority) -> (Priority, u64) {
+		let ticket = (priority, self.next_ticket);
+		self.next_ticket += 1;
+		self.queue.insert(ticket);
+		ticket
+	}
+
+	fn dequeue(&mut self, ticket: &(Priority, u64)) {
+		if self.queue.first() == Some(ticket) {
+			self.queue_moved.notify_waiters();
+		}
+		self.queue.remove(ticket);
+	}
+
+	/// Takes from the budgets if all of them allow it, otherwise says how long to wait.
+	fn try_take(&mut self, priority: Priority, weight: u32, is_order: bool, now: Duration) -> std::result::Result<(), Duration> {
+		if let Some(until) = self.banned_until {
+			if until > now {
+				return Err(until - now);
+			}
+			self.banned_until = None;
+		}
+		let mut wait = Duration::ZERO;
+		for ((kind, _), budget) in self.budgets.iter_mut() {
+			budget.roll(now);
+			let cost = match kind {
+				LimitKind::RequestWeight => weight,
+				LimitKind::Orders if is_order => 1,
+				LimitKind::Orders => 0,
+			};
+			if cost > 0 && (budget.used + cost) as f64 > budget.limit as f64 * priority.share() {
+				wait = wait.max(budget.until_next_window(now));
+			}
+		}
+		if wait > Duration::ZERO {
+			return Err(wait);
+		}
+		for ((kind, _), budget) in self.budgets.iter_mut() {
+			budget.used += match kind {
+				LimitKind::RequestWeight => weight,
+				LimitKind::Orders if is_order => 1,
+				LimitKind::Orders => 0,
+			};
+		}
+		Ok(())
+	}
+}
+
+/// Shared by every REST call of the process, as the limits are per IP and per account.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+	hosts: Mutex<HashMap<String, HostLimits>>,
+}
+impl RateLimiter {
+	/// Replaces the defaults with what the exchange reports in `exchangeInfo`.
+	pub fn set_limits(&self, host: &str, limits: &[RateLimit]) {
+		let mut hosts = self.hosts.lock().unwrap();
+		let host_limits = hosts.entry(host.to_owned()).or_insert_with(|| HostLimits::defaults(host));
+		for l in limits {
+			let kind = match l.rateLimitType.as_str() {
+				"REQUEST_WEIGHT" => LimitKind::RequestWeight,
+				"ORDERS" => LimitKind::Orders,
+				_ => continue,
+			};
+			let Some(interval) = parse_interval(&l.interval, l.intervalNum) else {
+				warn!(?l, "Unknown rate limit interval");
+				continue;
+			};
+			host_limits.budgets.entry((kind, interval)).or_insert_with(|| Budget::new(l.limit, interval)).limit = l.limit;
+		}
+	}
+
+	/// Waits for every request queued before it at the same or a higher priority to get through first.
+	async fn acquire(&self, host: &str, priority: Priority, weight: u32, is_order: bool) {
+		let ticket = self.with_host(host, |h| h.enqueue(priority));
+		// a request dropped while waiting must not hold up the ones behind it
+		let _in_queue = QueueGuard { limiter: self, host, ticket };
+		loop {
+			let queue_moved = self.with_host(host, |h| h.queue_moved.clone());
+			let notified = queue_moved.notified();
+			tokio::pin!(notified);
+			notified.as_mut().enable();
+
+			let taken = self.with_host(host, |h| match h.queue.first() == Some(&ticket) {
+				true => h.try_take(priority, weight, is_order, now()).map_err(Some),
+				false => Err(None),
+			});
+			match taken {
+				Ok(()) => return,
+				Err(Some(wait)) => {
+					warn!(host, ?priority, weight, ?wait, "Holding a request back to stay within rate limits");
+					tokio::time::sleep(wait).await;
+				}
+				Err(None) => notified.await,
+			}
+		}
+	}
+
+	fn with_host<R>(&self, host: &str, f: impl FnOnce(&mut HostLimits) -> R) -> R {
+		let mut hosts = self.hosts.lock().unwrap();
+		f(hosts.entry(host.to_owned()).or_insert_with(|| HostLimits::defaults(host)))
+	}
+
+	/// The exchange's counts are authoritative; ours only cover this process.
+	fn observe(&self, host: &str, response: &reqwest::Response) {
+		let mut hosts = self.hosts.lock().unwrap();
+		let host_limits = hosts.entry(host.to_owned()).or_insert_with(|| HostLimits::defaults(host));
+		let now = now();
+		for (name, value) in response.headers() {
+			let name = name.as_str().to_ascii_lowercase();
+			let (kind, suffix) = match (name.strip_prefix("x-mbx-used-weight-"), name.strip_prefix("x-mbx-order-count-")) {
+				(Some(suffix), _) => (LimitKind::RequestWeight, suffix),
+				(_, Some(suffix)) => (LimitKind::Orders, suffix),
+				_ => continue,
+			};
+			let (Some(interval), Some(used)) = (parse_header_interval(suffix), value.to_str().ok().and_then(|v| v.parse::<u32>().ok())) else {
+				continue;
+			};
+			if let Some(budget) = host_limits.budgets.get_mut(&(kind, interval)) {
+				budget.roll(now);
+				budget.used = used;
+			}
+		}
+
+		if response.status() == StatusCode::TOO_MANY_REQUESTS || response.status() == StatusCode::IM_A_TEAPOT {
+			let retry_after = response
+				.headers()
+				.get("retry-after")
+				.and_then(|v| v.to_str().ok())
+				.and_then(|v| v.parse::<u64>().ok())
+				.unwrap_or(60);
+			error!(host, status = %response.status(), retry_after, "Rate limited by the exchange, holding all requests back");
+			host_limits.banned_until = Some(now + Duration::from_secs(retry_after));
+		}
+	}
+}
+
+struct QueueGuard<'a> {
+	limiter: &'a RateLimiter,
+	host: &'a str,
+	ticket: (Priority, u64),
+}
+impl Drop for QueueGuard<'_> {
+	fn drop(&mut self) {
+		self.limiter.with_host(self.host, |h| h.dequeue(&self.ticket));
+	}
+}
+
+pub fn limiter() -> &'static RateLimiter {
+	static LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+	LIMITER.get_or_init(RateLimiter::default)
+}
+
+/// Every REST request to the exchanges is to be sent through here. Weight and priority are derived from the request itself.
+pub async fn send(client: &reqwest::Client, request: reqwest::Request) -> Result<reqwest::Response> {
+	let host = request.url().host_str().unwrap_or_default().to_owned();
+	let query: HashMap<String, String> = request.url().query_pairs().into_owned().collect();
+	let path = request.url().path().to_owned();
+	let is_order = is_order(request.method(), &path);
+	let priority = priority(request.method(), &path, &query);
+
+	limiter().acquire(&host, priority, weight(request.method(), &path, &query), is_order).await;
+	let response = client.execute(request).await?;
+	limiter().observe(&host, &response);
+	Ok(response)
+}
+
+/// Unsigned GET, for public endpoints.
+pub async fn get(url: url::Url) -> Result<reqwest::Response> {
+	let client = reqwest::Client::new();
+	let request = client.get(url).build()?;
+	send(&client, request).await
+}
+
+fn is_order(method: &Method, path: &str) -> bool {
+	matches!(*method, Method::POST | Method::PUT) && path.ends_with("/order")
+}
+
+fn priority(method: &Method, path: &str, query: &HashMap<String, String>) -> Priority {
+	if *method == Method::GET {
+		return Priority::Info;
+	}
+	let protective_type = query.get("type").map(|t| t.starts_with("STOP") || t.starts_with("TAKE_PROFIT")).unwrap_or(false);
+	let reducing = query.get("reduceOnly").map(|v| v == "true").unwrap_or(false) || query.get("closePosition").map(|v| v == "true").unwrap_or(false);
+	match is_order(method, path) && (protective_type || reducing) {
+		true => Priority::Protective,
+		false => Priority::Trading,
+	}
+}
+
+/// Per Binance docs; anything not listed is assumed to weigh 1.
+fn weight(method: &Method, path: &str, query: &HashMap<String, String>) -> u32 {
+	let with_symbol = query.contains_key("symbol");
+	match (method, path) {
+		(&Method::GET, "/fapi/v1/openOrders") if !with_symbol => 40,
+		(&Method::GET, "/fapi/v2/ticker/price") if !with_symbol => 2,
+		(&Method::GET, "/fapi/v1/premiumIndex") if !with_symbol => 10,
+		(&Method::GET, "/fapi/v2/positionRisk" | "/fapi/v2/balance" | "/fapi/v2/account") => 5,
+		(&Method::GET, "/fapi/v1/depth") => match query.get("limit").and_then(|l| l.parse::<u32>().ok()).unwrap_or(500) {
+			0..=50 => 2,
+			51..=100 => 5,
+			101..=500 => 10,
+			_ => 20,
+		},
+		(&Method::GET, "/api/v3/account") => 20,
+		(&Method::GET, "/api/v3/depth") => match query.get("limit").and_then(|l| l.parse::<u32>().ok()).unwrap_or(100) {
+			0..=100 => 5,
+			101..=500 => 25,
+			501..=1000 => 50,
+			_ => 250,
+		},
+		(&Method::GET, "/sapi/v1/margin/account") => 10,
+		_ => 1,
+	}
+}
+
+fn parse_interval(interval: &str, num: u32) -> Option<Duration> {
+	let unit = match interval {
+		"SECOND" => 1,
+		"MINUTE" => 60,
+		"HOUR" => 3600,
+		"DAY" => 86400,
+		_ => return None,
+	};
+	Some(Duration::from_secs(unit * num as u64))
+}
+
+/// Ex: `1m`, `10s`.
+fn parse_header_interval(suffix: &str) -> Option<Duration> {
+	let (num, unit) = suffix.split_at(suffix.len().checked_sub(1)?);
+	let interval = match unit {
+		"s" => "SECOND",
+		"m" => "MINUTE",
+		"h" => "HOUR",
+		"d" => "DAY",
+		_ => return None,
+	};
+	parse_interval(interval, num.parse().ok()?)
+}
+
+fn now() -> Duration {
+	SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn waiting_requests_go_out_by_priority() {
+		let limiter = Arc::new(RateLimiter::default());
+		limiter.with_host("test", |h| h.banned_until = Some(now() + Duration::from_millis(300)));
+
+		let served = Arc::new(Mutex::new(Vec::new()));
+		let mut handles = Vec::new();
+		// arriving in the reverse of the order they are to be served in
+		for priority in [Priority::Info, Priority::Trading, Priority::Info, Priority::Protective] {
+			let (limiter, served) = (limiter.clone(), served.clone());
+			handles.push(tokio::spawn(async move {
+				limiter.acquire("test", priority, 1, false).await;
+				served.lock().unwrap().push(priority);
+			}));
+			tokio::time::sleep(Duration::from_millis(20)).await;
+		}
+		for handle in handles {
+			handle.await.unwrap();
+		}
+		assert_eq!(*served.lock().unwrap(), vec![Priority::Protective, Priority::Trading, Priority::Info, Priority::Info]);
+	}
+
+	#[tokio::test]
+	async fn dropped_waiters_leave_the_queue() {
+		let limiter = RateLimiter::default();
+		limiter.with_host("test", |h| h.banned_until = Some(now() + Duration::from_millis(100)));
+		let _ = tokio::time::timeout(Duration::from_millis(10), limiter.acquire("test", Priority::Protective, 1, false)).await;
+		limiter.with_host("test", |h| assert!(h.queue.is_empty()));
+		// not held up behind the dropped one
+		tokio::time::timeout(Duration::from_millis(500), limiter.acquire("test", Priority::Info, 1, false)).await.unwrap();
+	}
+
+	#[test]
+	fn lower_priorities_leave_budget_to_protective() {
+		let mut host = HostLimits::defaults("fapi.binance.com");
+		let now = now();
+		for budget in host.budgets.values_mut() {
+			budget.roll(now);
+		}
+		host.budgets.get_mut(&(LimitKind::RequestWeight, Duration::from_secs(60))).unwrap().used = 2200;
+		assert!(host.try_take(Priority::Info, 10, false, now).is_err());
+		assert!(host.try_take(Priority::Trading, 10, false, now).is_ok());
+		assert!(host.try_take(Priority::Protective, 90, false, now).is_ok());
+	}
+}
@@ -0,0 +1,509 @@
+//! Binance futures user-data stream. Everything happening on the account (fills, order state changes, balance and position updates) is pushed here, instead of being polled for.
+//! Events emitted while reconnecting are lost; the reconciler is what catches up on those.
+use crate::api::binance::{self, OrderStatus};
+use crate::api::rate_limit;
+use crate::api::Market;
+use anyhow::{bail, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Deserializer};
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::broadcast;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::{debug, error, info, warn};
+use url::Url;
+use v_utils::trades::Side;
+
+/// Binance expires the key after 60m without a keepalive.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// Binance drops every connection at 24h; reconnecting on our own terms is cleaner.
+const MAX_CONNECTION_AGE: Duration = Duration::from_secs(23 * 60 * 60);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UserDataEvent {
+	Fill(ExchangeFill),
+	OrderState(OrderState),
+	Balance(BalanceUpdate),
+	Position(PositionUpdate),
+}
+
+/// Single execution on an order. Quantities are in the base asset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExchangeFill {
+	pub symbol: String,
+	pub order_id: i64,
+	pub client_order_id: String,
+	pub trade_id: i64,
+	pub side: Side,
+	pub last_qty: f64,
+	pub last_price: f64,
+	/// Over the whole order, this fill included.
+	pub cumulative_qty: f64,
+	pub commission: f64,
+	pub commission_asset: String,
+	pub maker: bool,
+	pub t: DateTime<Utc>,
+}
+impl ExchangeFill {
+	/// `None` if the commission was charged in something else (BNB discount), as then we'd need its price.
+	pub fn commission_usdt(&self) -> Option<f64> {
+		(self.commission_asset == "USDT" || self.commission == 0.0).then_some(self.commission)
+	}
+}
+
+/// Emitted on every change of the order, fills included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderState {
+	pub symbol: String,
+	pub order_id: i64,
+	pub client_order_id: String,
+	pub status: OrderStatus,
+	/// Ex: `NEW`, `TRADE`, `CANCELED`, `EXPIRED`, `AMENDMENT`
+	pub execution_type: String,
+	pub t: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceUpdate {
+	pub asset: String,
+	pub wallet_balance: f64,
+	pub cross_wallet_balance: f64,
+	/// Ex: `ORDER`, `FUNDING_FEE`, `DEPOSIT`
+	pub reason: String,
+	pub t: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionUpdate {
+	pub symbol: String,
+	/// Signed; negative for shorts.
+	pub position_amt: f64,
+	pub entry_price: f64,
+	pub unrealised_pnl: f64,
+	/// `BOTH` unless in hedge mode.
+	pub position_side: String,
+	pub t: DateTime<Utc>,
+}
+
+/// Connection settings of the stream. The secret is only needed to look up orders whose updates could not be parsed.
+#[derive(Debug, Clone)]
+pub struct UserDataStream {
+	key: String,
+	secret: String,
+	rest_base: Url,
+	ws_base: Url,
+}
+impl UserDataStream {
+	pub fn new(key: String, secret: String) -> Self {
+		Self {
+			key,
+			secret,
+			rest_base: Market::BinanceFutures.get_base_url(),
+			ws_base: Url::parse("wss://fstream.binance.com/ws/").unwrap(),
+		}
+	}
+
+	/// Runs for as long as the process does, reconnecting with a fresh listenKey whenever the connection is lost.
+	/// Having no subscribers at some point is fine, the events are then just dropped.
+	pub fn spawn(self, tx: broadcast::Sender<UserDataEvent>) -> tokio::task::JoinHandle<()> {
+		tokio::spawn(async move {
+			let mut backoff = Duration::from_secs(1);
+			loop {
+				let started = tokio::time::Instant::now();
+				match self.run_connection(&tx).await {
+					Ok(()) => info!("User-data stream connection ended, reconnecting"),
+					Err(e) => warn!("User-data stream connection failed: {}", e),
+				}
+				// only back off if we can't hold a connection at all
+				if started.elapsed() > MAX_RECONNECT_BACKOFF {
+					backoff = Duration::from_secs(1);
+				}
+				tokio::time::sleep(backoff).await;
+				backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+			}
+		})
+	}
+
+	/// Lifetime of a single listenKey. Returns `Ok` if it ended in a way that warrants an immediate reconnect.
+	async fn run_connection(&self, tx: &broadcast::Sender<UserDataEvent>) -> Result<()> {
+		let listen_key = self.create_listen_key().await?;
+		let (ws_stream, _) = connect_async(self.ws_base.join(&listen_key)?).await?;
+		let (mut write, mut read) = ws_stream.split();
+		info!("Connected to the user-data stream");
+
+		let mut keepalive = tokio::time::interval(KEEPALIVE_INTERVAL);
+		keepalive.tick().await;
+		let max_age = tokio::time::sleep(MAX_CONNECTION_AGE);
+		tokio::pin!(max_age);
+
+		loop {
+			select! {
+				msg = read.next() => {
+					let msg = match msg {
+						Some(msg) => msg?,
+						None => bail!("Closed by the server"),
+					};
+					match msg {
+						Message::Text(text) => match parse(&text) {
+							Ok(Parsed::Events(events)) => for event in events {
+								debug!(?event);
+								let _ = tx.send(event);
+							},
+							Ok(Parsed::KeyExpired) => {
+								warn!("listenKey expired");
+								return Ok(());
+							}
+							Ok(Parsed::Ignored) => {}
+							Err(e) => {
+								warn!("Failed to parse user-data message: {}\n{}", e, text);
+								if let Some(unparsed) = UnparsedOrderUpdate::of(&text) {
+									if let Err(e) = self.resync_order(&unparsed, tx).await {
+										error!(?unparsed, "Failed to recover the order update off REST, a fill on it may be lost: {}", e);
+									}
+								}
+							}
+						},
+						Message::Ping(payload) => write.send(Message::Pong(payload)).await?,
+						Message::Close(frame) => bail!("Closed by the server: {:?}", frame),
+						_ => {}
+					}
+				},
+				_ = keepalive.tick() => self.keepalive().await?,
+				_ = &mut max_age => return Ok(()),
+			}
+		}
+	}
+
+	/// Rebuilds what the update would have been from the order and its trades.
+	async fn resync_order(&self, unparsed: &UnparsedOrderUpdate, tx: &broadcast::Sender<UserDataEvent>) -> Result<()> {
+		let (key, secret) = (self.key.clone(), self.secret.clone());
+		let order = binance::poll_futures_order(key.clone(), secret.clone(), unparsed.order_id, unparsed.symbol.clone()).await?;
+		if let Some(trade_id) = unparsed.trade_id {
+			let trades = binance::futures_order_trades(key, secret, unparsed.symbol.clone(), unparsed.order_id).await?;
+			let mut cumulative_qty = 0.0;
+			for trade in trades {
+				cumulative_qty += trade.qty.parse::<f64>()?;
+				if trade.id != trade_id {
+					continue;
+				}
+				let fill = ExchangeFill {
+					symbol: unparsed.symbol.clone(),
+					order_id: unparsed.order_id,
+					client_order_id: order.clientOrderId.clone().unwrap_or_default(),
+					trade_id,
+					side: match trade.side.as_str() {
+						"BUY" => Side::Buy,
+						_ => Side::Sell,
+					},
+					last_qty: trade.qty.parse()?,
+					last_price: trade.price.parse()?,
+					cumulative_qty,
+					commission: trade.commission.parse::<f64>()?.abs(),
+					commission_asset: trade.commissionAsset,
+					maker: trade.maker,
+					t: ms_to_datetime(trade.time),
+				};
+				info!(?fill, "Recovered a fill off REST");
+				let _ = tx.send(UserDataEvent::Fill(fill));
+				break;
+			}
+		}
+		let _ = tx.send(UserDataEvent::OrderState(OrderState {
+			symbol: unparsed.symbol.clone(),
+			order_id: unparsed.order_id,
+			client_order_id: order.clientOrderId.unwrap_or_default(),
+			status: order.status,
+			execution_type: unparsed.execution_type.clone(),
+			t: ms_to_datetime(order.updateTime),
+		}));
+		Ok(())
+	}
+
+	async fn listen_key_request(&self, method: reqwest::Method) -> Result<reqwest::Response> {
+		let url = self.rest_base.join("/fapi/v1/listenKey")?;
+		let client = reqwest::Client::new();
+		let request = client.request(method, url).header("X-MBX-APIKEY", &self.key).build()?;
+		let r = rate_limit::send(&client, request).await?;
+		Ok(r.error_for_status()?)
+	}
+
+	async fn create_listen_key(&self) -> Result<String> {
+		#[derive(Deserialize)]
+		struct ListenKeyResponse {
+			#[serde(rename = "listenKey")]
+			listen_key: String,
+		}
+		let r: ListenKeyResponse = self.listen_key_request(reqwest::Method::POST).await?.json().await?;
+		Ok(r.listen_key)
+	}
+
+	async fn keepalive(&self) -> Result<()> {
+		self.listen_key_request(reqwest::Method::PUT).await?;
+		Ok(())
+	}
+}
+
+// Parsing {{{
+enum Parsed {
+	Events(Vec<UserDataEvent>),
+	KeyExpired,
+	Ignored,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "e")]
+enum RawEvent {
+	#[serde(rename = "ORDER_TRADE_UPDATE")]
+	OrderTradeUpdate {
+		#[serde(rename = "T")]
+		t: i64,
+		o: RawOrderUpdate,
+	},
+	#[serde(rename = "ACCOUNT_UPDATE")]
+	AccountUpdate {
+		#[serde(rename = "T")]
+		t: i64,
+		a: RawAccountUpdate,
+	},
+	#[serde(rename = "listenKeyExpired")]
+	ListenKeyExpired,
+	#[serde(other)]
+	Other,
+}
+
+#[derive(Deserialize)]
+struct RawOrderUpdate {
+	#[serde(rename = "s")]
+	symbol: String,
+	#[serde(rename = "c")]
+	client_order_id: String,
+	#[serde(rename = "S")]
+	side: String,
+	#[serde(rename = "x")]
+	execution_type: String,
+	#[serde(rename = "X")]
+	status: OrderStatus,
+	#[serde(rename = "i")]
+	order_id: i64,
+	#[serde(rename = "l", deserialize_with = "str_f64")]
+	last_qty: f64,
+	#[serde(rename = "z", deserialize_with = "str_f64")]
+	cumulative_qty: f64,
+	#[serde(rename = "L", deserialize_with = "str_f64")]
+	last_price: f64,
+	/// Absent if nothing was charged.
+	#[serde(rename = "n", default, deserialize_with = "opt_str_f64")]
+	commission: Option<f64>,
+	#[serde(rename = "N", default)]
+	commission_asset: Option<String>,
+	#[serde(rename = "T")]
+	trade_time: i64,
+	#[serde(rename = "t")]
+	trade_id: i64,
+	#[serde(rename = "m")]
+	maker: bool,
+}
+
+#[derive(Deserialize)]
+struct RawAccountUpdate {
+	#[serde(rename = "m")]
+	reason: String,
+	#[serde(rename = "B", default)]
+	balances: Vec<RawBalance>,
+	#[serde(rename = "P", default)]
+	positions: Vec<RawPosition>,
+}
+
+#[derive(Deserialize)]
+struct RawBalance {
+	#[serde(rename = "a")]
+	asset: String,
+	#[serde(rename = "wb", deserialize_with = "str_f64")]
+	wallet_balance: f64,
+	#[serde(rename = "cw", deserialize_with = "str_f64")]
+	cross_wallet_balance: f64,
+}
+
+#[derive(Deserialize)]
+struct RawPosition {
+	#[serde(rename = "s")]
+	symbol: String,
+	#[serde(rename = "pa", deserialize_with = "str_f64")]
+	position_amt: f64,
+	#[serde(rename = "ep", deserialize_with = "str_f64")]
+	entry_price: f64,
+	#[serde(rename = "up", deserialize_with = "str_f64")]
+	unrealised_pnl: f64,
+	#[serde(rename = "ps")]
+	position_side: String,
+}
+
+fn str_f64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+	let s = String::deserialize(deserializer)?;
+	s.parse::<f64>().map_err(serde::de::Error::custom)
+}
+
+fn opt_str_f64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+	match Option::<String>::deserialize(deserializer)? {
+		Some(s) => s.parse::<f64>().map(Some).map_err(serde::de::Error::custom),
+		None => Ok(None),
+	}
+}
+
+fn ms_to_datetime(ms: i64) -> DateTime<Utc> {
+	Utc.timestamp_millis_opt(ms).single().unwrap_or_else(Utc::now)
+}
+
+/// What can still be made out of an `ORDER_TRADE_UPDATE` that failed to parse in full.
+#[derive(Debug, Clone, PartialEq)]
+struct UnparsedOrderUpdate {
+	symbol: String,
+	order_id: i64,
+	/// Set if the update reported a trade.
+	trade_id: Option<i64>,
+	execution_type: String,
+}
+impl UnparsedOrderUpdate {
+	fn of(text: &str) -> Option<Self> {
+		let json: serde_json::Value = serde_json::from_str(text).ok()?;
+		if json.get("e")?.as_str()? != "ORDER_TRADE_UPDATE" {
+			return None;
+		}
+		let o = json.get("o")?;
+		Some(Self {
+			symbol: o.get("s")?.as_str()?.to_owned(),
+			order_id: o.get("i")?.as_i64()?,
+			trade_id: o.get("t").and_then(|t| t.as_i64()).filter(|t| *t > 0),
+			execution_type: o.get("x").and_then(|x| x.as_str()).unwrap_or("UNKNOWN").to_owned(),
+		})
+	}
+}
+
+fn parse(text: &str) -> Result<Parsed> {
+	let events = match serde_json::from_str::<RawEvent>(text)? {
+		RawEvent::OrderTradeUpdate { t, o } => {
+			let mut events = Vec::new();
+			// the fill goes first, so that by the time a consumer sees `FILLED`, it has all the quantity accounted for
+			if o.execution_type == "TRADE" {
+				events.push(UserDataEvent::Fill(ExchangeFill {
+					symbol: o.symbol.clone(),
+					order_id: o.order_id,
+					client_order_id: o.client_order_id.clone(),
+					trade_id: o.trade_id,
+					side: match o.side.as_str() {
+						"BUY" => Side::Buy,
+						_ => Side::Sell,
+					},
+					last_qty: o.last_qty,
+					last_price: o.last_price,
+					cumulative_qty: o.cumulative_qty,
+					commission: o.commission.unwrap_or(0.0),
+					commission_asset: o.commission_asset.unwrap_or_default(),
+					maker: o.maker,
+					t: ms_to_datetime(o.trade_time),
+				}));
+			}
+			events.push(UserDataEvent::OrderState(OrderState {
+				symbol: o.symbol,
+				order_id: o.order_id,
+				client_order_id: o.client_order_id,
+				status: o.status,
+				execution_type: o.execution_type,
+				t: ms_to_datetime(t),
+			}));
+			events
+		}
+		RawEvent::AccountUpdate { t, a } => {
+			let t = ms_to_datetime(t);
+			let balances = a.balances.into_iter().map(|b| {
+				UserDataEvent::Balance(BalanceUpdate {
+					asset: b.asset,
+					wallet_balance: b.wallet_balance,
+					cross_wallet_balance: b.cross_wallet_balance,
+					reason: a.reason.clone(),
+					t,
+				})
+			});
+			let positions = a.positions.into_iter().map(|p| {
+				UserDataEvent::Position(PositionUpdate {
+					symbol: p.symbol,
+					position_amt: p.position_amt,
+					entry_price: p.entry_price,
+					unrealised_pnl: p.unrealised_pnl,
+					position_side: p.position_side,
+					t,
+				})
+			});
+			balances.chain(positions).collect()
+		}
+		RawEvent::ListenKeyExpired => return Ok(Parsed::KeyExpired),
+		RawEvent::Other => return Ok(Parsed::Ignored),
+	};
+	Ok(Parsed::Events(events))
+}
+//,}}}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const TRADE: &str = r#"{"e":"ORDER_TRADE_UPDATE","E":1568879465651,"T":1568879465650,"o":{"s":"BTCUSDT","c":"TEST","S":"SELL","o":"MARKET","f":"GTC","q":"0.002","p":"0","ap":"7819.01","sp":"0","x":"TRADE","X":"FILLED","i":8886774,"l":"0.002","z":"0.002","L":"7819.01","N":"USDT","n":"0.0062552","T":1568879465650,"t":698759,"b":"0","a":"0","m":false,"R":false,"wt":"CONTRACT_PRICE","ot":"MARKET","ps":"BOTH","cp":false,"rp":"0"}}"#;
+
+	#[test]
+	fn trade_update_is_a_fill_then_a_state() {
+		let Parsed::Events(events) = parse(TRADE).unwrap() else { panic!("not parsed into events") };
+		let [UserDataEvent::Fill(fill), UserDataEvent::OrderState(state)] = events.as_slice() else {
+			panic!("unexpected events: {:?}", events)
+		};
+		assert_eq!((fill.order_id, fill.trade_id, fill.side.clone()), (8886774, 698759, Side::Sell));
+		assert_eq!((fill.last_qty, fill.last_price, fill.cumulative_qty), (0.002, 7819.01, 0.002));
+		assert_eq!(fill.commission_usdt(), Some(0.0062552));
+		assert!(!fill.maker);
+		assert_eq!((state.order_id, state.status.clone()), (8886774, OrderStatus::Filled));
+	}
+
+	#[test]
+	fn new_order_update_is_only_a_state() {
+		let text = TRADE.replace(r#""x":"TRADE","X":"FILLED""#, r#""x":"NEW","X":"NEW""#);
+		let Parsed::Events(events) = parse(&text).unwrap() else { panic!("not parsed into events") };
+		assert!(matches!(events.as_slice(), [UserDataEvent::OrderState(OrderState { status: OrderStatus::New, .. })]));
+	}
+
+	#[test]
+	fn account_update_has_balances_and_positions() {
+		let text = r#"{"e":"ACCOUNT_UPDATE","E":1564745798939,"T":1564745798938,"a":{"m":"ORDER","B":[{"a":"USDT","wb":"122624.12345678","cw":"100.12345678","bc":"50.12345678"}],"P":[{"s":"BTCUSDT","pa":"-0.002","ep":"7819.01","cr":"200","up":"0.1","mt":"isolated","iw":"0","ps":"BOTH"}]}}"#;
+		let Parsed::Events(events) = parse(text).unwrap() else { panic!("not parsed into events") };
+		let [UserDataEvent::Balance(balance), UserDataEvent::Position(position)] = events.as_slice() else {
+			panic!("unexpected events: {:?}", events)
+		};
+		assert_eq!((balance.asset.as_str(), balance.reason.as_str()), ("USDT", "ORDER"));
+		assert_eq!((position.position_amt, position.position_side.as_str()), (-0.002, "BOTH"));
+	}
+
+	#[test]
+	fn expired_key_and_others() {
+		assert!(matches!(parse(r#"{"e":"listenKeyExpired","E":1576653824250}"#).unwrap(), Parsed::KeyExpired));
+		assert!(matches!(parse(r#"{"e":"MARGIN_CALL","E":1587727187525}"#).unwrap(), Parsed::Ignored));
+	}
+
+	#[test]
+	fn unparsable_trade_update_is_still_identified() {
+		let text = TRADE.replace(r#""l":"0.002""#, r#""l":"garbage""#);
+		assert!(parse(&text).is_err());
+		assert_eq!(
+			UnparsedOrderUpdate::of(&text),
+			Some(UnparsedOrderUpdate {
+				symbol: "BTCUSDT".to_owned(),
+				order_id: 8886774,
+				trade_id: Some(698759),
+				execution_type: "TRADE".to_owned(),
+			})
+		);
+
+		let not_a_trade = TRADE.replace(r#""t":698759"#, r#""t":0"#);
+		assert_eq!(UnparsedOrderUpdate::of(&not_a_trade).unwrap().trade_id, None);
+		assert_eq!(UnparsedOrderUpdate::of(r#"{"e":"ACCOUNT_UPDATE"}"#), None);
+	}
+}
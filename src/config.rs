@@ -1,3 +1,9 @@
+use crate::api::executor::ExecutionConfig;
+use crate::api::fees::FeeConfig;
+use crate::impact::ImpactLimits;
+use crate::margin::MarginConfig;
+use crate::reconciler::ReconciliationConfig;
+use crate::risk::RiskLimits;
 use anyhow::{Context, Result};
 use config::{Conifg, File};
 use serde::de::{self, Deserializer, Visitor};
@@ -11,6 +17,18 @@ use v_utils::{io::ExpandedPath, macros::MyConfigPrimitives};
 pub struct AppConfig {
 	pub positions_dir: PathBuf,
 	pub binance: Binance,
+	#[serde(default)]
+	pub reconciliation: ReconciliationConfig,
+	#[serde(default)]
+	pub risk: RiskLimits,
+	#[serde(default)]
+	pub margin: MarginConfig,
+	#[serde(default)]
+	pub execution: ExecutionConfig,
+	#[serde(default)]
+	pub impact: ImpactLimits,
+	#[serde(default)]
+	pub fees: FeeConfig,
 }
 #[derive(Clone, Debug, MyConfigPrimitives)]
 pub struct Binance {
@@ -35,3 +53,25 @@ impl AppConfig {
 		Ok(settings)
 	}
 }
+
+#[cfg(test)]
+impl AppConfig {
+	/// Defaults everywhere and no keys; for tests that never reach the exchange.
+	pub fn test() -> Self {
+		Self {
+			positions_dir: std::env::temp_dir().join("discretionary_engine_tests"),
+			binance: Binance {
+				full_key: String::new(),
+				full_secret: String::new(),
+				read_key: String::new(),
+				read_secret: String::new(),
+			},
+			reconciliation: Default::default(),
+			risk: Default::default(),
+			margin: Default::default(),
+			execution: Default::default(),
+			impact: Default::default(),
+			fees: Default::default(),
+		}
+	}
+}
@@ -0,0 +1,116 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+use uuid::Uuid;
+
+/// Hand-issued changes to a position that is being followed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlCommand {
+	/// Changes the open size by this fraction of it. Ex: `-0.3` closes 30%, `0.2` adds 20%.
+	Resize { fraction: f64 },
+	/// Market out of everything, dropping all protocols.
+	CloseAll,
+}
+
+/// What the position answered; `Err` if it refused the command.
+pub type ControlReply = std::result::Result<String, String>;
+
+/// Each running position listens on its own socket, next to its journal.
+pub fn socket_path(positions_dir: &Path, position_id: Uuid) -> PathBuf {
+	positions_dir.join(format!("{position_id}.sock"))
+}
+
+/// Starts accepting commands for the position. The socket file is cleaned up by the first connection after the receiver is dropped, or by the next `listen` for the same id.
+pub fn listen(positions_dir: &Path, position_id: Uuid) -> Result<mpsc::UnboundedReceiver<(ControlCommand, oneshot::Sender<ControlReply>)>> {
+	let path = socket_path(positions_dir, position_id);
+	// left over from a previous run that did not exit cleanly
+	let _ = std::fs::remove_file(&path);
+	let listener = UnixListener::bind(&path)?;
+	let (tx, rx) = mpsc::unbounded_channel();
+
+	tokio::spawn(async move {
+		loop {
+			let (stream, _) = match listener.accept().await {
+				Ok(s) => s,
+				Err(e) => {
+					warn!("Control socket failed: {}", e);
+					break;
+				}
+			};
+			if tx.is_closed() {
+				break;
+			}
+			let tx = tx.clone();
+			tokio::spawn(async move {
+				if let Err(e) = serve(stream, tx).await {
+					warn!("Control connection failed: {}", e);
+				}
+			});
+		}
+		let _ = std::fs::remove_file(&path);
+	});
+	Ok(rx)
+}
+
+/// One command per connection, as a single json line; answered with one line too.
+async fn serve(stream: UnixStream, tx: mpsc::UnboundedSender<(ControlCommand, oneshot::Sender<ControlReply>)>) -> Result<()> {
+	let (read, mut write) = stream.into_split();
+	let mut line = String::new();
+	// only checking that the position is followed
+	if BufReader::new(read).read_line(&mut line).await? == 0 {
+		return Ok(());
+	}
+	let reply: ControlReply = match serde_json::from_str::<ControlCommand>(&line) {
+		Ok(command) => {
+			let (tx_reply, rx_reply) = oneshot::channel();
+			tx.send((command, tx_reply)).map_err(|_| anyhow::anyhow!("Position is no longer followed"))?;
+			rx_reply.await.unwrap_or_else(|_| Err("Position stopped before answering".to_owned()))
+		}
+		Err(e) => Err(format!("Malformed command: {e}")),
+	};
+	write.write_all(format!("{}\n", serde_json::to_string(&reply)?).as_bytes()).await?;
+	Ok(())
+}
+
+/// Whether some running engine is following the position.
+pub async fn is_followed(positions_dir: &Path, position_id: Uuid) -> bool {
+	UnixStream::connect(socket_path(positions_dir, position_id)).await.is_ok()
+}
+
+/// Every position some running engine is following.
+pub async fn followed(positions_dir: &Path) -> Vec<Uuid> {
+	let mut followed = Vec::new();
+	let Ok(entries) = std::fs::read_dir(positions_dir) else { return followed };
+	for path in entries.flatten().map(|e| e.path()) {
+		if path.extension().is_some_and(|e| e == "sock") {
+			if let Some(position_id) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<Uuid>().ok()) {
+				if is_followed(positions_dir, position_id).await {
+					followed.push(position_id);
+				}
+			}
+		}
+	}
+	followed
+}
+
+pub async fn send(positions_dir: &Path, position_id: Uuid, command: &ControlCommand) -> Result<String> {
+	let path = socket_path(positions_dir, position_id);
+	let stream = match UnixStream::connect(&path).await {
+		Ok(s) => s,
+		Err(e) => bail!("Position {} is not being followed by any running engine ({:?}: {})", position_id, path, e),
+	};
+	let (read, mut write) = stream.into_split();
+	write.write_all(format!("{}\n", serde_json::to_string(command)?).as_bytes()).await?;
+
+	let mut line = String::new();
+	BufReader::new(read).read_line(&mut line).await?;
+	match serde_json::from_str::<ControlReply>(&line)? {
+		Ok(answer) => Ok(answer),
+		Err(refusal) => bail!("{}", refusal),
+	}
+}
@@ -0,0 +1,452 @@
+//! Columnar record of what the market did and what the engine did about it, for offline analysis.
+//!
+//! Layout on disk, relative to `positions_dir`:
+//! ```text
+//! data/<kind>/symbol=<BASE>-<QUOTE>-<Market>/date=<YYYY-MM-DD>/part-<first_t_ms>-<nonce>.arrow
+//! ```
+//! Every file is an Arrow IPC file (aka Feather v2) with the schema of its `<kind>`, so a whole `<kind>` directory can be opened as one hive-partitioned dataset.
+//! Schemas are append-only: columns may be added at the end, never renamed, reordered or retyped.
+use crate::api::order_types::ConceptualOrder;
+use crate::api::Symbol;
+use anyhow::{Context, Result};
+use arrow2::{
+	array::{Array, BooleanArray, PrimitiveArray, Utf8Array},
+	chunk::Chunk,
+	datatypes::{DataType, Field, Schema},
+	io::ipc::{
+		read::{read_file_metadata, FileReader},
+		write::{FileWriter, WriteOptions},
+	},
+};
+use chrono::{Duration as ChronoDuration, TimeZone, Utc};
+use futures_util::StreamExt;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio_tungstenite::connect_async;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+const FLUSH_AT_LEN: usize = 10_000;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+pub trait ArrowRecord: Sized {
+	/// Name of the top-level directory. Part of the on-disk format, so never change it.
+	const KIND: &'static str;
+	fn schema() -> Schema;
+	/// Determines the day-partition the record goes to.
+	fn t_ms(&self) -> i64;
+	fn to_chunk(records: &[Self]) -> Chunk<Box<dyn Array>>;
+}
+
+#[derive(Clone, Debug)]
+pub struct DataStore {
+	root: PathBuf,
+}
+impl DataStore {
+	pub fn new(positions_dir: &Path) -> Result<Self> {
+		let root = positions_dir.join("data");
+		std::fs::create_dir_all(&root).with_context(|| format!("Failed to create data directory at {:?}", root))?;
+		Ok(Self { root })
+	}
+
+	/// Writes a new part file for each day the records span. Never touches existing files.
+	pub fn write<R: ArrowRecord>(&self, symbol: &Symbol, records: Vec<R>) -> Result<()> {
+		let mut by_day: BTreeMap<String, Vec<R>> = BTreeMap::new();
+		for record in records {
+			let day = Utc.timestamp_millis_opt(record.t_ms()).unwrap().format("%Y-%m-%d").to_string();
+			by_day.entry(day).or_default().push(record);
+		}
+
+		for (day, records) in by_day {
+			let dir = self.partition_dir(R::KIND, symbol, &day);
+			std::fs::create_dir_all(&dir)?;
+			let nonce = &Uuid::new_v4().simple().to_string()[..8];
+			let path = dir.join(format!("part-{}-{nonce}.arrow", records[0].t_ms()));
+
+			let file = std::fs::File::create(&path).with_context(|| format!("Failed to create {:?}", path))?;
+			let mut writer = FileWriter::try_new(file, R::schema(), None, WriteOptions { compression: None })?;
+			writer.write(&R::to_chunk(&records), None)?;
+			writer.finish()?;
+		}
+		Ok(())
+	}
+
+	fn partition_dir(&self, kind: &str, symbol: &Symbol, day: &str) -> PathBuf {
+		self.root
+			.join(kind)
+			.join(format!("symbol={}-{}-{:?}", symbol.base, symbol.quote, symbol.market))
+			.join(format!("date={day}"))
+	}
+
+	/// Lowest and highest recorded trade price of the symbol in `[from_ms, to_ms]`. `None` if nothing was recorded over the period.
+	pub fn trade_price_range(&self, symbol: &Symbol, from_ms: i64, to_ms: i64) -> Result<Option<(f64, f64)>> {
+		let mut range: Option<(f64, f64)> = None;
+		let mut day = Utc.timestamp_millis_opt(from_ms).unwrap().date_naive();
+		let last_day = Utc.timestamp_millis_opt(to_ms).unwrap().date_naive();
+		while day <= last_day {
+			let dir = self.partition_dir(TradeRecord::KIND, symbol, &day.format("%Y-%m-%d").to_string());
+			day += ChronoDuration::days(1);
+			let Ok(entries) = std::fs::read_dir(&dir) else {
+				continue;
+			};
+
+			for entry in entries {
+				let path = entry?.path();
+				let mut file = std::fs::File::open(&path)?;
+				let metadata = read_file_metadata(&mut file)?;
+				for chunk in FileReader::new(file, metadata, Some(vec![0, 1]), None) {
+					let chunk = chunk?;
+					let (Some(t), Some(price)) = (
+						chunk.arrays()[0].as_any().downcast_ref::<PrimitiveArray<i64>>(),
+						chunk.arrays()[1].as_any().downcast_ref::<PrimitiveArray<f64>>(),
+					) else {
+						anyhow::bail!("Unexpected schema in {:?}", path);
+					};
+					for (t, price) in t.values_iter().zip(price.values_iter()) {
+						if *t < from_ms || *t > to_ms {
+							continue;
+						}
+						range = Some(match range {
+							Some((low, high)) => (low.min(*price), high.max(*price)),
+							None => (*price, *price),
+						});
+					}
+				}
+			}
+		}
+		Ok(range)
+	}
+}
+
+/// Accumulates records in memory, and writes them out in batches, so that per-tick producers don't produce a file per tick.
+#[derive(Debug)]
+pub struct RecordBuffer<R: ArrowRecord> {
+	store: DataStore,
+	symbol: Symbol,
+	records: Vec<R>,
+	last_flush: Instant,
+}
+impl<R: ArrowRecord> RecordBuffer<R> {
+	pub fn new(store: DataStore, symbol: Symbol) -> Self {
+		Self {
+			store,
+			symbol,
+			records: Vec::new(),
+			last_flush: Instant::now(),
+		}
+	}
+
+	/// Never fails; a failed flush is logged, and the records are dropped.
+	pub fn extend(&mut self, records: impl IntoIterator<Item = R>) {
+		self.records.extend(records);
+		if self.records.len() >= FLUSH_AT_LEN || self.last_flush.elapsed() >= FLUSH_INTERVAL {
+			if let Err(e) = self.flush() {
+				error!("Failed to flush {} records: {}", R::KIND, e);
+			}
+		}
+	}
+
+	pub fn flush(&mut self) -> Result<()> {
+		self.last_flush = Instant::now();
+		if self.records.is_empty() {
+			return Ok(());
+		}
+		self.store.write(&self.symbol, std::mem::take(&mut self.records))
+	}
+}
+impl<R: ArrowRecord> Drop for RecordBuffer<R> {
+	fn drop(&mut self) {
+		if let Err(e) = self.flush() {
+			error!("Failed to flush {} records on drop: {}", R::KIND, e);
+		}
+	}
+}
+
+// Records {{{
+#[derive(Debug, Clone)]
+pub struct TradeRecord {
+	pub t_ms: i64,
+	pub price: f64,
+	pub qty: f64,
+	pub is_buyer_maker: bool,
+}
+impl ArrowRecord for TradeRecord {
+	const KIND: &'static str = "trades";
+
+	fn schema() -> Schema {
+		Schema::from(vec![
+			Field::new("t_ms", DataType::Int64, false),
+			Field::new("price", DataType::Float64, false),
+			Field::new("qty", DataType::Float64, false),
+			Field::new("is_buyer_maker", DataType::Boolean, false),
+		])
+	}
+
+	fn t_ms(&self) -> i64 {
+		self.t_ms
+	}
+
+	fn to_chunk(records: &[Self]) -> Chunk<Box<dyn Array>> {
+		Chunk::new(vec![
+			PrimitiveArray::<i64>::from_vec(records.iter().map(|r| r.t_ms).collect()).boxed(),
+			PrimitiveArray::<f64>::from_vec(records.iter().map(|r| r.price).collect()).boxed(),
+			PrimitiveArray::<f64>::from_vec(records.iter().map(|r| r.qty).collect()).boxed(),
+			BooleanArray::from_slice(records.iter().map(|r| r.is_buyer_maker).collect::<Vec<_>>()).boxed(),
+		])
+	}
+}
+
+/// Only closed klines are recorded.
+#[derive(Debug, Clone)]
+pub struct KlineRecord {
+	pub open_time_ms: i64,
+	pub close_time_ms: i64,
+	pub interval: String,
+	pub open: f64,
+	pub high: f64,
+	pub low: f64,
+	pub close: f64,
+	pub volume: f64,
+}
+impl ArrowRecord for KlineRecord {
+	const KIND: &'static str = "klines";
+
+	fn schema() -> Schema {
+		Schema::from(vec![
+			Field::new("open_time_ms", DataType::Int64, false),
+			Field::new("close_time_ms", DataType::Int64, false),
+			Field::new("interval", DataType::Utf8, false),
+			Field::new("open", DataType::Float64, false),
+			Field::new("high", DataType::Float64, false),
+			Field::new("low", DataType::Float64, false),
+			Field::new("close", DataType::Float64, false),
+			Field::new("volume", DataType::Float64, false),
+		])
+	}
+
+	fn t_ms(&self) -> i64 {
+		self.open_time_ms
+	}
+
+	fn to_chunk(records: &[Self]) -> Chunk<Box<dyn Array>> {
+		Chunk::new(vec![
+			PrimitiveArray::<i64>::from_vec(records.iter().map(|r| r.open_time_ms).collect()).boxed(),
+			PrimitiveArray::<i64>::from_vec(records.iter().map(|r| r.close_time_ms).collect()).boxed(),
+			Utf8Array::<i32>::from_slice(records.iter().map(|r| r.interval.as_str()).collect::<Vec<_>>()).boxed(),
+			PrimitiveArray::<f64>::from_vec(records.iter().map(|r| r.open).collect()).boxed(),
+			PrimitiveArray::<f64>::from_vec(records.iter().map(|r| r.high).collect()).boxed(),
+			PrimitiveArray::<f64>::from_vec(records.iter().map(|r| r.low).collect()).boxed(),
+			PrimitiveArray::<f64>::from_vec(records.iter().map(|r| r.close).collect()).boxed(),
+			PrimitiveArray::<f64>::from_vec(records.iter().map(|r| r.volume).collect()).boxed(),
+		])
+	}
+}
+
+/// One row per order of a `TargetOrders` snapshot. Rows sharing `t_ms` belong to the same snapshot.
+#[derive(Debug, Clone)]
+pub struct TargetOrderRecord {
+	pub t_ms: i64,
+	pub produced_by: String,
+	pub order_uuid: Uuid,
+	pub order_type: &'static str,
+	pub side: String,
+	pub price: Option<f64>,
+	pub qty_notional: f64,
+}
+impl TargetOrderRecord {
+	pub fn snapshot(orders: &[ConceptualOrder]) -> Vec<Self> {
+		let t_ms = Utc::now().timestamp_millis();
+		orders
+			.iter()
+			.map(|o| {
+				let order_type = match o {
+					ConceptualOrder::Market(_) => "market",
+					ConceptualOrder::Limit(_) => "limit",
+					ConceptualOrder::StopMarket(_) => "stop_market",
+				};
+				Self {
+					t_ms,
+					produced_by: o.id().produced_by.clone(),
+					order_uuid: o.id().uuid,
+					order_type,
+					side: o.side().to_string(),
+					price: o.price().ok(),
+					qty_notional: o.notional(),
+				}
+			})
+			.collect()
+	}
+}
+impl ArrowRecord for TargetOrderRecord {
+	const KIND: &'static str = "target_orders";
+
+	fn schema() -> Schema {
+		Schema::from(vec![
+			Field::new("t_ms", DataType::Int64, false),
+			Field::new("produced_by", DataType::Utf8, false),
+			Field::new("order_uuid", DataType::Utf8, false),
+			Field::new("order_type", DataType::Utf8, false),
+			Field::new("side", DataType::Utf8, false),
+			Field::new("price", DataType::Float64, true),
+			Field::new("qty_notional", DataType::Float64, false),
+		])
+	}
+
+	fn t_ms(&self) -> i64 {
+		self.t_ms
+	}
+
+	fn to_chunk(records: &[Self]) -> Chunk<Box<dyn Array>> {
+		Chunk::new(vec![
+			PrimitiveArray::<i64>::from_vec(records.iter().map(|r| r.t_ms).collect()).boxed(),
+			Utf8Array::<i32>::from_slice(records.iter().map(|r| r.produced_by.as_str()).collect::<Vec<_>>()).boxed(),
+			Utf8Array::<i32>::from_slice(records.iter().map(|r| r.order_uuid.to_string()).collect::<Vec<_>>()).boxed(),
+			Utf8Array::<i32>::from_slice(records.iter().map(|r| r.order_type).collect::<Vec<_>>()).boxed(),
+			Utf8Array::<i32>::from_slice(records.iter().map(|r| r.side.as_str()).collect::<Vec<_>>()).boxed(),
+			PrimitiveArray::<f64>::from(records.iter().map(|r| r.price).collect::<Vec<_>>()).boxed(),
+			PrimitiveArray::<f64>::from_vec(records.iter().map(|r| r.qty_notional).collect()).boxed(),
+		])
+	}
+}
+
+/// One row per execution; `qty_notional` is of that execution alone. `price` is only known when the fill is reported by the exchange with it.
+#[derive(Debug, Clone)]
+pub struct FillRecord {
+	pub t_ms: i64,
+	pub produced_by: String,
+	pub order_uuid: Uuid,
+	pub price: Option<f64>,
+	pub qty_notional: f64,
+	pub commission_usdt: f64,
+}
+impl ArrowRecord for FillRecord {
+	const KIND: &'static str = "fills";
+
+	fn schema() -> Schema {
+		Schema::from(vec![
+			Field::new("t_ms", DataType::Int64, false),
+			Field::new("produced_by", DataType::Utf8, false),
+			Field::new("order_uuid", DataType::Utf8, false),
+			Field::new("price", DataType::Float64, true),
+			Field::new("qty_notional", DataType::Float64, false),
+			Field::new("commission_usdt", DataType::Float64, false),
+		])
+	}
+
+	fn t_ms(&self) -> i64 {
+		self.t_ms
+	}
+
+	fn to_chunk(records: &[Self]) -> Chunk<Box<dyn Array>> {
+		Chunk::new(vec![
+			PrimitiveArray::<i64>::from_vec(records.iter().map(|r| r.t_ms).collect()).boxed(),
+			Utf8Array::<i32>::from_slice(records.iter().map(|r| r.produced_by.as_str()).collect::<Vec<_>>()).boxed(),
+			Utf8Array::<i32>::from_slice(records.iter().map(|r| r.order_uuid.to_string()).collect::<Vec<_>>()).boxed(),
+			PrimitiveArray::<f64>::from(records.iter().map(|r| r.price).collect::<Vec<_>>()).boxed(),
+			PrimitiveArray::<f64>::from_vec(records.iter().map(|r| r.qty_notional).collect()).boxed(),
+			PrimitiveArray::<f64>::from_vec(records.iter().map(|r| r.commission_usdt).collect()).boxed(),
+		])
+	}
+}
+//,}}}
+
+/// Records aggTrades and closed 1m klines of the symbol for as long as the process lives.
+pub fn spawn_market_recorder(store: DataStore, symbol: Symbol) -> tokio::task::JoinHandle<()> {
+	let stream_symbol = symbol.to_string().to_lowercase();
+	let address = format!("wss://fstream.binance.com/stream?streams={stream_symbol}@aggTrade/{stream_symbol}@kline_1m");
+
+	tokio::spawn(async move {
+		let mut trades: RecordBuffer<TradeRecord> = RecordBuffer::new(store.clone(), symbol.clone());
+		let mut klines: RecordBuffer<KlineRecord> = RecordBuffer::new(store, symbol);
+
+		let url = url::Url::parse(&address).unwrap();
+		let mut backoff = Duration::from_secs(1);
+		// trades in between the last one before a disconnect and the first one after are missing from the record
+		let (mut last_trade_ms, mut disconnected) = (None::<i64>, false);
+		loop {
+			if disconnected {
+				if let Err(e) = trades.flush().and(klines.flush()) {
+					error!("Market recorder failed to flush before reconnecting: {}", e);
+				}
+				tokio::time::sleep(backoff).await;
+				backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+			}
+			disconnected = true;
+			let (ws_stream, _) = match connect_async(url.clone()).await {
+				Ok(s) => s,
+				Err(e) => {
+					warn!("Market recorder failed to connect, retrying in {:?}: {}", backoff, e);
+					continue;
+				}
+			};
+			let connected_at = Instant::now();
+			let (_, mut read) = ws_stream.split();
+			let mut gap_pending = last_trade_ms.is_some();
+
+			while let Some(msg) = read.next().await {
+				let data = match msg {
+					Ok(m) => m.into_data(),
+					Err(e) => {
+						warn!("Market recorder stream error: {}", e);
+						break;
+					}
+				};
+				let json: Value = match serde_json::from_slice(&data) {
+					Ok(json) => json,
+					Err(_) => continue,
+				};
+				let (Some(stream), Some(payload)) = (json.get("stream").and_then(|s| s.as_str()), json.get("data")) else {
+					continue;
+				};
+
+				let parse_f64 = |v: &Value, key: &str| v.get(key).and_then(|x| x.as_str()).and_then(|x| x.parse::<f64>().ok());
+				if stream.ends_with("@aggTrade") {
+					if let (Some(t_ms), Some(price), Some(qty), Some(is_buyer_maker)) = (
+						payload.get("T").and_then(|x| x.as_i64()),
+						parse_f64(payload, "p"),
+						parse_f64(payload, "q"),
+						payload.get("m").and_then(|x| x.as_bool()),
+					) {
+						if let (true, Some(last)) = (std::mem::take(&mut gap_pending), last_trade_ms) {
+							warn!(from_ms = last, to_ms = t_ms, "Market recorder missed {:.1}s of trades while reconnecting", (t_ms - last) as f64 / 1000.0);
+						}
+						last_trade_ms = Some(t_ms);
+						trades.extend([TradeRecord { t_ms, price, qty, is_buyer_maker }]);
+					}
+				} else if let Some(k) = payload.get("k") {
+					if k.get("x").and_then(|x| x.as_bool()) != Some(true) {
+						continue;
+					}
+					if let (Some(open_time_ms), Some(close_time_ms), Some(interval), Some(open), Some(high), Some(low), Some(close), Some(volume)) = (
+						k.get("t").and_then(|x| x.as_i64()),
+						k.get("T").and_then(|x| x.as_i64()),
+						k.get("i").and_then(|x| x.as_str()),
+						parse_f64(k, "o"),
+						parse_f64(k, "h"),
+						parse_f64(k, "l"),
+						parse_f64(k, "c"),
+						parse_f64(k, "v"),
+					) {
+						klines.extend([KlineRecord {
+							open_time_ms,
+							close_time_ms,
+							interval: interval.to_owned(),
+							open,
+							high,
+							low,
+							close,
+							volume,
+						}]);
+					}
+				}
+			}
+			if connected_at.elapsed() > MAX_RECONNECT_BACKOFF {
+				backoff = Duration::from_secs(1);
+			}
+			warn!("Market recorder disconnected, reconnecting in {:?}", backoff);
+		}
+	})
+}
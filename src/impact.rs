@@ -0,0 +1,157 @@
+use crate::api::order_book::OrderBook;
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use v_utils::trades::Side;
+
+/// Bounds on the expected slippage of the entry of a new position, as fractions of the mid price.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ImpactLimits {
+	/// Above this, the position is only opened once confirmed, even with `--noconfirm`.
+	pub confirm_above: f64,
+	/// Above this, the position is refused.
+	pub refuse_above: f64,
+}
+impl Default for ImpactLimits {
+	fn default() -> Self {
+		Self {
+			confirm_above: 0.002,
+			refuse_above: 0.01,
+		}
+	}
+}
+
+/// What a market order is expected to get, walking the book as it is now.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImpactEstimate {
+	pub mid: f64,
+	pub avg_price: f64,
+	/// Price of the last level the order reaches into.
+	pub worst_price: f64,
+	/// Of the average price from the mid, against the order. Includes half the spread.
+	pub slippage: f64,
+	pub levels: usize,
+}
+impl std::fmt::Display for ImpactEstimate {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"expected fill at {} on average (worst {}), {:.1}bp off the mid of {}, over {} levels",
+			self.avg_price,
+			self.worst_price,
+			self.slippage * 10_000.0,
+			self.mid,
+			self.levels
+		)
+	}
+}
+
+/// Fills `size_usdt` against the levels of `book` an order of `side` takes from. Fails if the book, as far as it is known, can't take it.
+pub fn estimate(book: &OrderBook, side: &Side, size_usdt: f64) -> Result<ImpactEstimate> {
+	let Some(mid) = book.mid() else { bail!("Book is empty") };
+	let (mut left_usdt, mut qty, mut levels, mut worst_price) = (size_usdt, 0.0, 0, mid);
+	for (price, level_qty) in book.levels(side) {
+		if left_usdt <= 0.0 {
+			break;
+		}
+		let taken = level_qty.min(left_usdt / price);
+		qty += taken;
+		left_usdt -= taken * price;
+		levels += 1;
+		worst_price = price;
+	}
+	// float leftovers of the last level
+	if left_usdt > size_usdt * 1e-9 {
+		bail!("Only {:.2} of {:.2} USDT can be filled within the {} levels of the book", size_usdt - left_usdt, size_usdt, levels);
+	}
+
+	let avg_price = size_usdt / qty;
+	let slippage = match side {
+		Side::Buy => avg_price / mid - 1.0,
+		Side::Sell => 1.0 - avg_price / mid,
+	};
+	Ok(ImpactEstimate {
+		mid,
+		avg_price,
+		worst_price,
+		slippage,
+		levels,
+	})
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+	Acceptable,
+	NeedsConfirmation(String),
+	Refused(String),
+}
+
+pub fn judge(estimate: &ImpactEstimate, limits: &ImpactLimits) -> Verdict {
+	let bp = |x: f64| x * 10_000.0;
+	match estimate.slippage {
+		s if s > limits.refuse_above => Verdict::Refused(format!("Expected slippage of {:.1}bp is over the limit of {:.1}bp", bp(s), bp(limits.refuse_above))),
+		s if s > limits.confirm_above => Verdict::NeedsConfirmation(format!("Expected slippage of {:.1}bp is over {:.1}bp", bp(s), bp(limits.confirm_above))),
+		_ => Verdict::Acceptable,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::order_book::tests::book_of;
+
+	fn book() -> OrderBook {
+		book_of(&[(99.0, 1.0), (98.0, 1.0)], &[(101.0, 1.0), (102.0, 1.0)])
+	}
+
+	fn limits() -> ImpactLimits {
+		ImpactLimits {
+			confirm_above: 0.01,
+			refuse_above: 0.02,
+		}
+	}
+
+	#[test]
+	fn fills_within_the_top_level() {
+		let e = estimate(&book(), &Side::Buy, 50.5).unwrap();
+		assert_eq!(e.levels, 1);
+		assert_eq!(e.worst_price, 101.0);
+		assert!((e.avg_price - 101.0).abs() < 1e-9);
+		assert!((e.slippage - 0.01).abs() < 1e-9);
+	}
+
+	#[test]
+	fn walks_down_the_levels() {
+		// 1 at 101 and 0.5 at 102
+		let e = estimate(&book(), &Side::Buy, 152.0).unwrap();
+		assert_eq!(e.levels, 2);
+		assert_eq!(e.worst_price, 102.0);
+		assert!((e.avg_price - 152.0 / 1.5).abs() < 1e-9);
+		assert!((e.slippage - (152.0 / 1.5 / 100.0 - 1.0)).abs() < 1e-9);
+
+		// 1 at 99 and 0.5 at 98
+		let e = estimate(&book(), &Side::Sell, 148.0).unwrap();
+		assert_eq!((e.levels, e.worst_price), (2, 98.0));
+		assert!((e.slippage - (1.0 - 148.0 / 1.5 / 100.0)).abs() < 1e-9);
+	}
+
+	#[test]
+	fn fails_on_a_book_that_cant_take_it() {
+		assert!(estimate(&book(), &Side::Buy, 204.0).is_err());
+		assert!(estimate(&book_of(&[], &[(101.0, 1.0)]), &Side::Buy, 10.0).is_err());
+	}
+
+	#[test]
+	fn judged_by_slippage() {
+		let at = |slippage| ImpactEstimate {
+			mid: 100.0,
+			avg_price: 100.0,
+			worst_price: 100.0,
+			slippage,
+			levels: 1,
+		};
+		assert_eq!(judge(&at(0.01), &limits()), Verdict::Acceptable);
+		assert!(matches!(judge(&at(0.015), &limits()), Verdict::NeedsConfirmation(_)));
+		assert!(matches!(judge(&at(0.025), &limits()), Verdict::Refused(_)));
+	}
+}
@@ -0,0 +1,356 @@
+use crate::api::order_types::{ConceptualOrder, Fill};
+use crate::pnl::PositionPnl;
+use crate::positions::{ClosedRecord, Lifecycle, PositionSpec, PositionStage, ACQUISITION};
+use crate::protocols::ProtocolOrders;
+use anyhow::{Context, Result};
+use chrono::{TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Bumped on any change to the shape of `JournalEvent` that an older reader could misinterpret. Adding a new variant is such a change.
+pub const JOURNAL_VERSION: u32 = 7;
+
+/// One line of the journal. Format is described in docs.md.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+	pub v: u32,
+	pub t_ms: i64,
+	pub event: JournalEvent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JournalEvent {
+	Opened { spec: PositionSpec },
+	Protocols { followup: Vec<String> },
+	Acquired {
+		acquired_notional: f64,
+		/// since v4
+		#[serde(default)]
+		avg_entry_price: Option<f64>,
+	},
+	ProtocolOrders(ProtocolOrders),
+	TargetOrders { orders: Vec<ConceptualOrder> },
+	/// Fields other than `id` and `filled_notional` since v4. Fills of the acquisition order since v7.
+	Fill(Fill),
+	/// since v2
+	ProtocolState { produced_by: String, state: serde_json::Value },
+	/// since v2. Engine picked the position back up after a restart, and found this much of it on the exchange.
+	Resumed { exchange_notional: f64 },
+	/// since v3
+	Transition { from: PositionStage, to: PositionStage },
+	/// since v3. Always the last entry.
+	Closed { record: ClosedRecord },
+	/// since v5. Hand-issued market order; written again with the filled size if it is abandoned.
+	ManualOrder { order: ConceptualOrder },
+}
+
+/// Everything the followup needs to continue, folded from the journal entries.
+#[derive(Debug, Clone)]
+pub struct Replayed {
+	pub spec: PositionSpec,
+	pub lifecycle: Lifecycle,
+	pub followup_specs: Vec<String>,
+	pub acquired_notional: Option<f64>,
+	pub pnl: PositionPnl,
+	pub protocol_orders: HashMap<String, ProtocolOrders>,
+	pub protocol_states: HashMap<String, serde_json::Value>,
+	/// Latest cumulative filled notional of each order.
+	pub fills: HashMap<Uuid, f64>,
+	pub manual_orders: HashMap<Uuid, ConceptualOrder>,
+}
+impl Replayed {
+	/// Fills of manual scale-ins add to the position instead.
+	pub fn closed_notional(&self) -> f64 {
+		self.fills.iter().filter(|(uuid, _)| !self.is_entry(uuid)).map(|(_, filled)| filled).sum()
+	}
+
+	pub fn is_entry(&self, uuid: &Uuid) -> bool {
+		self.manual_orders.get(uuid).map(|o| o.side() == &self.spec.side).unwrap_or(false)
+	}
+}
+
+/// Append-only record of everything that happened to a single position, at `<positions_dir>/<position_id>.jsonl`.
+#[derive(Debug)]
+pub struct Journal {
+	path: PathBuf,
+	file: std::fs::File,
+}
+impl Journal {
+	/// Creates the journal of a new position, starting it with the `Opened` event.
+	pub fn create(positions_dir: &Path, spec: &PositionSpec) -> Result<Self> {
+		let path = Self::path(positions_dir, spec.id);
+		let file = std::fs::OpenOptions::new()
+			.create_new(true)
+			.append(true)
+			.open(&path)
+			.with_context(|| format!("Failed to create position journal at {:?}", path))?;
+		let journal = Self { path, file };
+		journal.append(JournalEvent::Opened { spec: spec.clone() })?;
+		Ok(journal)
+	}
+
+	/// Continues appending to the journal of an existing position.
+	pub fn open(positions_dir: &Path, position_id: Uuid) -> Result<Self> {
+		let path = Self::path(positions_dir, position_id);
+		let file = std::fs::OpenOptions::new()
+			.append(true)
+			.open(&path)
+			.with_context(|| format!("Failed to open position journal at {:?}", path))?;
+		Ok(Self { path, file })
+	}
+
+	pub fn path(positions_dir: &Path, position_id: Uuid) -> PathBuf {
+		positions_dir.join(format!("{position_id}.jsonl"))
+	}
+
+	/// Each entry is flushed to disk before returning, so that everything that was acted upon is on the record.
+	pub fn append(&self, event: JournalEvent) -> Result<()> {
+		let entry = JournalEntry {
+			v: JOURNAL_VERSION,
+			t_ms: Utc::now().timestamp_millis(),
+			event,
+		};
+		let mut line = serde_json::to_string(&entry)?;
+		line.push('\n');
+
+		let mut file = &self.file;
+		file.write_all(line.as_bytes()).with_context(|| format!("Failed to write to {:?}", self.path))?;
+		file.sync_data()?;
+		Ok(())
+	}
+
+	/// Reads all entries of a journal. Fails on entries written by a newer version of the format.
+	pub fn read(path: &Path) -> Result<Vec<JournalEntry>> {
+		let file = std::fs::File::open(path).with_context(|| format!("Failed to open position journal at {:?}", path))?;
+		let mut entries = Vec::new();
+		for (i, line) in BufReader::new(file).lines().enumerate() {
+			let line = line?;
+			if line.trim().is_empty() {
+				continue;
+			}
+			let entry: JournalEntry = serde_json::from_str(&line).with_context(|| format!("Malformed entry on line {} of {:?}", i + 1, path))?;
+			if entry.v > JOURNAL_VERSION {
+				anyhow::bail!("{:?} line {} is of journal version {}, while only <= {} is supported", path, i + 1, entry.v, JOURNAL_VERSION);
+			}
+			entries.push(entry);
+		}
+		Ok(entries)
+	}
+
+	pub fn replay(entries: Vec<JournalEntry>) -> Result<Replayed> {
+		let mut entries = entries.into_iter();
+		let (spec, t_opened) = match entries.next() {
+			Some(JournalEntry {
+				t_ms,
+				event: JournalEvent::Opened { spec },
+				..
+			}) => (spec, Utc.timestamp_millis_opt(t_ms).unwrap()),
+			_ => anyhow::bail!("Journal does not start with an `opened` event"),
+		};
+
+		let mut transitions = vec![(PositionStage::Pending, t_opened)];
+		let mut replayed = Replayed {
+			pnl: PositionPnl::new(spec.side.clone()),
+			spec,
+			lifecycle: Lifecycle::new(),
+			followup_specs: Vec::new(),
+			acquired_notional: None,
+			protocol_orders: HashMap::new(),
+			protocol_states: HashMap::new(),
+			fills: HashMap::new(),
+			manual_orders: HashMap::new(),
+		};
+		// journals from before v7 only have the entry in `Acquired`, without its fees
+		let mut acquisition_fills = false;
+		for entry in entries {
+			match entry.event {
+				JournalEvent::Opened { .. } => anyhow::bail!("Journal contains more than one `opened` event"),
+				JournalEvent::Protocols { followup } => replayed.followup_specs = followup,
+				JournalEvent::Acquired {
+					acquired_notional,
+					avg_entry_price,
+				} => {
+					replayed.acquired_notional = Some(acquired_notional);
+					if let (Some(price), false) = (avg_entry_price, acquisition_fills) {
+						replayed.pnl.record_entry(acquired_notional, price, 0.0);
+					}
+				}
+				JournalEvent::ProtocolOrders(orders) => {
+					replayed.protocol_orders.insert(orders.produced_by.clone(), orders);
+				}
+				JournalEvent::TargetOrders { .. } => {}
+				JournalEvent::Fill(fill) if fill.id.produced_by == ACQUISITION => {
+					acquisition_fills = true;
+					replayed.pnl.record_entry(fill.last_qty, fill.last_price, fill.commission_usdt);
+				}
+				JournalEvent::Fill(fill) => {
+					if replayed.is_entry(&fill.id.uuid) {
+						replayed.pnl.record_entry(fill.last_qty, fill.last_price, fill.commission_usdt);
+						replayed.acquired_notional = Some(replayed.acquired_notional.unwrap_or(0.0) + fill.last_qty);
+					} else {
+						replayed.pnl.record_exit(&fill.id.produced_by, fill.last_qty, fill.last_price, fill.commission_usdt);
+					}
+					replayed.fills.insert(fill.id.uuid, fill.filled_notional);
+				}
+				JournalEvent::ProtocolState { produced_by, state } => {
+					replayed.protocol_states.insert(produced_by, state);
+				}
+				JournalEvent::Resumed { exchange_notional } => {
+					// whatever drift there was, has been adopted at this point
+					replayed.acquired_notional = Some(exchange_notional + replayed.closed_notional());
+				}
+				JournalEvent::Transition { to, .. } => transitions.push((to, Utc.timestamp_millis_opt(entry.t_ms).unwrap())),
+				JournalEvent::Closed { .. } => {}
+				JournalEvent::ManualOrder { order } => {
+					replayed.manual_orders.insert(order.id().uuid, order);
+				}
+			}
+		}
+		replayed.lifecycle = Lifecycle::from_transitions(transitions);
+		Ok(replayed)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::order_types::ProtocolOrderId;
+	use crate::stops::StopMode;
+	use v_utils::trades::Side;
+
+	fn spec() -> PositionSpec {
+		PositionSpec::new("BTC".to_owned(), Side::Buy, 600.0, StopMode::Native)
+	}
+
+	fn entry(t_ms: i64, event: JournalEvent) -> JournalEntry {
+		JournalEntry { v: JOURNAL_VERSION, t_ms, event }
+	}
+
+	fn transition(t_ms: i64, from: PositionStage, to: PositionStage) -> JournalEntry {
+		entry(t_ms, JournalEvent::Transition { from, to })
+	}
+
+	fn fill(produced_by: &str, uuid: Uuid, filled_notional: f64, last_qty: f64, last_price: f64, commission_usdt: f64) -> JournalEvent {
+		JournalEvent::Fill(Fill {
+			id: ProtocolOrderId::new(produced_by.to_owned(), uuid),
+			filled_notional,
+			last_qty,
+			last_price,
+			commission_usdt,
+			t: Utc::now(),
+		})
+	}
+
+	/// Acquired in two fills at 100 on average, then half of it closed by "ts:p1" at 110.
+	fn followed(spec: &PositionSpec) -> Vec<JournalEntry> {
+		let (acquisition, stop) = (Uuid::new_v4(), Uuid::new_v4());
+		vec![
+			entry(0, JournalEvent::Opened { spec: spec.clone() }),
+			entry(1, JournalEvent::Protocols { followup: vec!["ts:p1".to_owned()] }),
+			transition(2, PositionStage::Pending, PositionStage::Acquiring),
+			entry(3, fill(ACQUISITION, acquisition, 3.0, 3.0, 99.0, 0.15)),
+			entry(4, fill(ACQUISITION, acquisition, 6.0, 3.0, 101.0, 0.15)),
+			transition(5, PositionStage::Acquiring, PositionStage::Following),
+			entry(
+				6,
+				JournalEvent::Acquired {
+					acquired_notional: 6.0,
+					avg_entry_price: Some(100.0),
+				},
+			),
+			entry(
+				7,
+				JournalEvent::ProtocolState {
+					produced_by: "ts:p1".to_owned(),
+					state: serde_json::json!({"top": 105.0}),
+				},
+			),
+			entry(8, fill("ts:p1", stop, 3.0, 3.0, 110.0, 0.165)),
+			transition(9, PositionStage::Following, PositionStage::Closing),
+		]
+	}
+
+	#[test]
+	fn replays_a_followed_position() {
+		let spec = spec();
+		let replayed = Journal::replay(followed(&spec)).unwrap();
+		assert_eq!(replayed.spec.id, spec.id);
+		assert_eq!(replayed.followup_specs, vec!["ts:p1".to_owned()]);
+		assert_eq!(replayed.lifecycle.stage(), PositionStage::Closing);
+		assert_eq!(replayed.acquired_notional, Some(6.0));
+		assert_eq!(replayed.closed_notional(), 3.0);
+		assert_eq!(replayed.protocol_states["ts:p1"], serde_json::json!({"top": 105.0}));
+
+		// entry comes from the acquisition fills alone, with their fees
+		assert_eq!(replayed.pnl.entry_notional, 6.0);
+		assert_eq!(replayed.pnl.avg_entry_price(), Some(100.0));
+		assert!((replayed.pnl.fees_usdt - 0.465).abs() < 1e-9);
+		assert!((replayed.pnl.realised_usdt - 30.0).abs() < 1e-9);
+		// the followup only continues the orders of the protocols
+		assert_eq!(replayed.fills.len(), 1);
+	}
+
+	#[test]
+	fn replays_a_closed_position() {
+		let spec = spec();
+		let mut entries = followed(&spec);
+		let replayed = Journal::replay(entries.clone()).unwrap();
+		entries.push(transition(10, PositionStage::Closing, PositionStage::Closed));
+		let record = ClosedRecord {
+			spec: spec.clone(),
+			acquired_notional: 6.0,
+			closed_notional: 6.0,
+			lifecycle: replayed.lifecycle.clone(),
+			pnl: replayed.pnl.clone(),
+			t_closed: Utc.timestamp_millis_opt(10).unwrap(),
+		};
+		entries.push(entry(10, JournalEvent::Closed { record }));
+
+		let replayed = Journal::replay(entries).unwrap();
+		assert_eq!(replayed.lifecycle.stage(), PositionStage::Closed);
+		assert_eq!(replayed.lifecycle.entered_at(PositionStage::Closed), Some(Utc.timestamp_millis_opt(10).unwrap()));
+	}
+
+	#[test]
+	fn entry_of_old_journals_is_taken_from_acquired() {
+		let spec = spec();
+		let entries = vec![
+			entry(0, JournalEvent::Opened { spec: spec.clone() }),
+			entry(
+				1,
+				JournalEvent::Acquired {
+					acquired_notional: 6.0,
+					avg_entry_price: Some(100.0),
+				},
+			),
+		];
+		let replayed = Journal::replay(entries).unwrap();
+		assert_eq!(replayed.pnl.avg_entry_price(), Some(100.0));
+		assert_eq!(replayed.pnl.entry_notional, 6.0);
+		assert_eq!(replayed.pnl.fees_usdt, 0.0);
+	}
+
+	#[test]
+	fn replay_needs_a_single_opened_first() {
+		let spec = spec();
+		assert!(Journal::replay(vec![entry(0, JournalEvent::Protocols { followup: Vec::new() })]).is_err());
+		let twice = vec![entry(0, JournalEvent::Opened { spec: spec.clone() }), entry(1, JournalEvent::Opened { spec })];
+		assert!(Journal::replay(twice).is_err());
+	}
+
+	#[test]
+	fn newer_versions_are_refused() {
+		let spec = spec();
+		let path = std::env::temp_dir().join(format!("discretionary_engine_journal_{}.jsonl", spec.id));
+		let mut newer = entry(0, JournalEvent::Opened { spec });
+		newer.v = JOURNAL_VERSION + 1;
+		std::fs::write(&path, format!("{}\n", serde_json::to_string(&newer).unwrap())).unwrap();
+		let read = Journal::read(&path);
+		let _ = std::fs::remove_file(&path);
+		assert!(read.unwrap_err().to_string().contains("journal version"));
+	}
+}
@@ -1,16 +1,26 @@
 pub mod api;
 pub mod config;
+pub mod control;
+pub mod data_store;
+pub mod impact;
+pub mod journal;
+pub mod margin;
+pub mod pnl;
 pub mod positions;
 pub mod protocols;
+pub mod reconciler;
+pub mod report;
+pub mod risk;
+pub mod sizing;
+pub mod stops;
 pub mod utils;
-use clap::{Args, Parser, Subcommand};
+use clap::{ArgGroup, Args, Parser, Subcommand};
 use config::AppConfig;
+use journal::{Journal, JournalEvent};
 use positions::*;
 use protocols::FollowupProtocols;
-use v_utils::{
-	io::ExpandedPath,
-	trades::{Side, Timeframe},
-};
+use sizing::SizingMode;
+use v_utils::{io::ExpandedPath, trades::Timeframe};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -26,12 +36,58 @@ struct Cli {
 enum Commands {
 	/// Start the program
 	New(PositionArgs),
+	/// Resume following positions left over from a previous run, after reconciling them with the exchange
+	Resume(ResumeArgs),
+	/// Lift the freeze on exposure-increasing actions, accepting whatever drift caused it
+	Unfreeze,
+	/// Write a trade journal of all closed positions
+	Report(ReportArgs),
+	/// Freeze the engine, close the followed positions, then cancel every open order and close every position on the account
+	Kill,
+	/// Add to or partially close a position that is being followed
+	Resize(ResizeArgs),
+	/// Close a position that is being followed at market, dropping its protocols
+	Close(CloseArgs),
 }
 #[derive(Args)]
+struct ResizeArgs {
+	#[arg(long)]
+	id: uuid::Uuid,
+	/// fraction of the open size to add, or to close if negative. Ex: "20%", "-30%"
+	#[arg(long, allow_hyphen_values = true, value_parser = sizing::parse_fraction)]
+	by: f64,
+}
+#[derive(Args)]
+struct CloseArgs {
+	#[arg(long)]
+	id: uuid::Uuid,
+}
+#[derive(Args)]
+struct ReportArgs {
+	#[arg(long, value_enum, default_value = "csv")]
+	format: report::ReportFormat,
+	/// file to write the report to. Printed to stdout if not provided.
+	#[arg(short, long)]
+	output: Option<ExpandedPath>,
+}
+#[derive(Args)]
+struct ResumeArgs {
+	/// id of the position to resume. If not provided, all positions with a journal in `positions_dir` are considered. Ones another running engine follows are skipped.
+	#[arg(long)]
+	id: Option<uuid::Uuid>,
+}
+#[derive(Args)]
+#[command(group(ArgGroup::new("sizing").required(true).args(["size", "size_usdt", "risk"])))]
 struct PositionArgs {
-	/// percentage of the total balance to use
+	/// fraction of the total balance to use. Negative for shorts, here and in the other sizing modes.
+	#[arg(long)]
+	size: Option<f64>,
+	/// fixed size in USDT
 	#[arg(long)]
-	size: f64,
+	size_usdt: Option<f64>,
+	/// fraction of the total balance to lose if the initial stop is hit, e.g. "0.5%". Requires a followup protocol that places a stop.
+	#[arg(long, value_parser = sizing::parse_fraction)]
+	risk: Option<f64>,
 	/// timeframe, in the format of "1m", "1h", "3M", etc.
 	/// determines the target period for which we expect the edge to persist.
 	#[arg(long)]
@@ -45,6 +101,9 @@ struct PositionArgs {
 	/// position followup parameters, in the format of "<protocol>-<params>", e.g. "ts:p0.5". Params consist of their starting letter followed by the value, e.g. "p0.5" for 0.5% offset. If multiple params are required, they are separated by '-'.
 	#[arg(short, long, default_value = "")]
 	followup_protocols_spec: Vec<String>,
+	/// how stops of the position are expressed. Overrides `[execution] stop_mode` of the config.
+	#[arg(long, value_enum)]
+	stops: Option<stops::StopMode>,
 }
 
 // Later on we will initialize exchange sockets once, then just have a loop listening on localhost, that accepts new positions or modification requests.
@@ -60,35 +119,210 @@ async fn main() {
 			std::process::exit(1);
 		}
 	};
-	//let noconfirm = cli.noconfirm;
+	let noconfirm = cli.noconfirm;
 
 	match cli.command {
 		Commands::New(position_args) => {
+			let engine = EngineHandles::init(&config).await.unwrap();
+			if let Some(reason) = engine.freeze.reason() {
+				eprintln!("Engine is frozen, refusing to open new positions. Run `unfreeze` once resolved. Reason:\n{}", reason);
+				std::process::exit(1);
+			}
 			// init position
 			// update acquisition and followup protocols on it
 			// they themselves decide whether cache needs to be updated/created
 
+			//let followup_protocols = ProtocolsSpec::try_from(position_args.followup_protocols_spec).unwrap();
+			// Do I need the cache thing though?
+			//let cache = FollowupCache::new();
+
+			let trailing_stop_hardcoded = protocols::interpret_followup_specs(position_args.followup_protocols_spec).unwrap();
+
+			let mode = match (position_args.size, position_args.size_usdt, position_args.risk) {
+				(Some(fraction), _, _) => SizingMode::BalanceFraction(fraction),
+				(_, Some(usdt), _) => SizingMode::Usdt(usdt),
+				(_, _, Some(risk)) => SizingMode::Risk(risk),
+				_ => unreachable!("enforced by clap"),
+			};
 			let balance = api::compile_total_balance(config.clone()).await.unwrap();
-			let (side, target_size) = match position_args.size {
-				s if s > 0.0 => (Side::Buy, s * balance),
-				s if s < 0.0 => (Side::Sell, -s * balance),
-				_ => {
-					eprintln!("Size must be non-zero");
+			let sizing = match sizing::compute(mode, balance, &trailing_stop_hardcoded) {
+				Ok(s) => s,
+				Err(e) => {
+					eprintln!("{}", e);
 					std::process::exit(1);
 				}
 			};
+			println!("{}: {} (total balance {:.2} USDT)", position_args.coin, sizing, balance);
+			let symbol = format!("{}USDT", position_args.coin.to_uppercase());
+			if let Err(e) = engine.risk.refresh(&config).await.and_then(|_| engine.risk.check_increase_usdt(&symbol, sizing.size_usdt)) {
+				eprintln!("Refusing to open the position: {}", e);
+				std::process::exit(1);
+			}
+			let entry_symbol = api::Symbol {
+				base: position_args.coin.clone(),
+				quote: "USDT".to_owned(),
+				market: api::Market::BinanceFutures,
+			};
+			let mut confirmed = false;
+			match api::order_book::OrderBook::fetch(&entry_symbol).await.and_then(|book| impact::estimate(&book, &sizing.side, sizing.size_usdt)) {
+				Ok(estimate) => {
+					println!("Entry: {}", estimate);
+					match impact::judge(&estimate, &config.impact) {
+						impact::Verdict::Acceptable => {}
+						impact::Verdict::NeedsConfirmation(why) => {
+							if !utils::confirm(&format!("{}. Proceed anyway?", why)) {
+								std::process::exit(0);
+							}
+							confirmed = true;
+						}
+						impact::Verdict::Refused(why) => {
+							eprintln!("Refusing to open the position: {}", why);
+							std::process::exit(1);
+						}
+					}
+				}
+				Err(e) => {
+					eprintln!("Refusing to open the position, as its market impact can't be estimated: {}", e);
+					std::process::exit(1);
+				}
+			}
+			if !noconfirm && !confirmed && !utils::confirm("Proceed?") {
+				std::process::exit(0);
+			}
 
-			//let followup_protocols = ProtocolsSpec::try_from(position_args.followup_protocols_spec).unwrap();
-			// Do I need the cache thing though?
-			//let cache = FollowupCache::new();
+			if let Err(e) = margin::adjust(&config, &position_args.coin, sizing.size_usdt).await {
+				eprintln!("Refusing to open the position: {}", e);
+				std::process::exit(1);
+			}
 
-			let trailing_stop_hardcoded = protocols::interpret_followup_specs(position_args.followup_protocols_spec).unwrap();
+			let spec = PositionSpec::new(
+				position_args.coin,
+				sizing.side,
+				sizing.size_usdt,
+				position_args.stops.unwrap_or(config.execution.stop_mode),
+			);
+			data_store::spawn_market_recorder(engine.data_store.clone(), spec.symbol());
+
+			let journal = Journal::create(&config.positions_dir, &spec).unwrap();
+			journal
+				.append(JournalEvent::Protocols {
+					followup: trailing_stop_hardcoded.iter().map(|p| p.to_string()).collect(),
+				})
+				.unwrap();
+
+			let acquired = match PositionAcquisition::do_acquisition(spec, &journal, &engine).await {
+				Ok(acquired) => acquired,
+				Err(e) => {
+					eprintln!("Failed to acquire the position: {}", e);
+					std::process::exit(1);
+				}
+			};
+			journal
+				.append(JournalEvent::Acquired {
+					acquired_notional: acquired.acquired_notional,
+					avg_entry_price: acquired.pnl.avg_entry_price(),
+				})
+				.unwrap();
+			let closed = PositionFollowup::do_followup(acquired, trailing_stop_hardcoded, engine, journal, FollowupCarryover::default())
+				.await
+				.unwrap();
+			println!("{:?}", closed.record());
+		}
+		Commands::Resume(resume_args) => {
+			let journal_paths: Vec<std::path::PathBuf> = match resume_args.id {
+				Some(id) => vec![Journal::path(&config.positions_dir, id)],
+				None => std::fs::read_dir(&config.positions_dir)
+					.unwrap()
+					.filter_map(|e| e.ok().map(|e| e.path()))
+					.filter(|p| p.extension().map(|e| e == "jsonl").unwrap_or(false))
+					.collect(),
+			};
+			let engine = EngineHandles::init(&config).await.unwrap();
+
+			let mut replays = Vec::new();
+			for path in journal_paths {
+				match Journal::read(&path).and_then(Journal::replay) {
+					Ok(r) => replays.push(r),
+					Err(e) => eprintln!("Skipping {:?}: {}", path, e),
+				}
+			}
+			// split has to consider all positions at once, as several of them could be on the same symbol.
+			let split = positions::split_exchange_exposure(&config, &replays, engine.position_mode).await.unwrap();
 
-			let spec = PositionSpec::new(position_args.coin, side, target_size);
-			let acquired = PositionAcquisition::dbg_new(spec).await.unwrap();
-			// currently followup does nothing
-			let followed = PositionFollowup::do_followup(acquired, trailing_stop_hardcoded).await.unwrap();
-			println!("{:?}", followed);
+			let mut handles = Vec::new();
+			for replayed in replays {
+				// still in the split, as what it holds is on the exchange all the same
+				if control::is_followed(&config.positions_dir, replayed.spec.id).await {
+					eprintln!("Skipping {}: followed by another running engine", replayed.spec.id);
+					continue;
+				}
+				let exchange_notional = split[&replayed.spec.id];
+				let config = config.clone();
+				let engine = engine.clone();
+				handles.push(tokio::spawn(
+					async move { PositionFollowup::resume(&config, replayed, exchange_notional, engine).await },
+				));
+			}
+			for handle in handles {
+				match handle.await.unwrap() {
+					Ok(Some(closed)) => println!("{:?}", closed.record()),
+					Ok(None) => {}
+					Err(e) => eprintln!("Failed to resume a position: {}", e),
+				}
+			}
+		}
+		Commands::Unfreeze => {
+			let freeze = reconciler::Freeze::new(&config.positions_dir);
+			match freeze.reason() {
+				Some(reason) => {
+					println!("Lifting freeze. It was caused by:\n{}", reason);
+					freeze.unfreeze().unwrap();
+				}
+				None => println!("Engine is not frozen"),
+			}
+		}
+		Commands::Kill => {
+			if !noconfirm && !utils::confirm("Close everything on the account?") {
+				std::process::exit(0);
+			}
+			// first, so that nothing running re-opens what's being closed
+			reconciler::Freeze::new(&config.positions_dir).freeze("`kill` was ran").unwrap();
+			match risk::flatten_all(&config).await {
+				Ok(()) => println!("Flattened. Run `unfreeze` to allow opening positions again."),
+				Err(e) => {
+					eprintln!("{}", e);
+					std::process::exit(1);
+				}
+			}
+		}
+		Commands::Resize(resize_args) => {
+			let command = control::ControlCommand::Resize { fraction: resize_args.by };
+			match control::send(&config.positions_dir, resize_args.id, &command).await {
+				Ok(answer) => println!("{}", answer),
+				Err(e) => {
+					eprintln!("{}", e);
+					std::process::exit(1);
+				}
+			}
+		}
+		Commands::Close(close_args) => match control::send(&config.positions_dir, close_args.id, &control::ControlCommand::CloseAll).await {
+			Ok(answer) => println!("{}", answer),
+			Err(e) => {
+				eprintln!("{}", e);
+				std::process::exit(1);
+			}
+		},
+		Commands::Report(report_args) => {
+			let data_store = data_store::DataStore::new(&config.positions_dir).unwrap();
+			let rows: Vec<report::TradeRow> = report::closed_positions(&config.positions_dir)
+				.unwrap()
+				.iter()
+				.map(|(record, protocols)| report::trade_row(record, protocols, &data_store).unwrap())
+				.collect();
+			match report_args.output {
+				Some(path) => report::write_report(&rows, report_args.format, std::fs::File::create(path.to_string()).unwrap()).unwrap(),
+				None => report::write_report(&rows, report_args.format, std::io::stdout()).unwrap(),
+			}
 		}
 	}
 }
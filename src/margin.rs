@@ -0,0 +1,110 @@
+use crate::api::binance::{self, LeverageBracket, MarginType};
+use crate::api::Market;
+use crate::config::AppConfig;
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct MarginConfig {
+	pub margin_type: MarginType,
+	/// Multiplier on the notional leverage is picked for, so that adverse moves and small scale-ins don't immediately need more margin.
+	pub headroom: f64,
+}
+impl Default for MarginConfig {
+	fn default() -> Self {
+		Self {
+			margin_type: MarginType::Cross,
+			headroom: 1.5,
+		}
+	}
+}
+
+/// Lowest leverage at which `notional_usdt` (times `headroom`) is covered by `margin_balance`, if the brackets allow for it at that size.
+/// Margin already used by other symbols is not subtracted; with cross margin it is shared anyway.
+pub fn pick_leverage(brackets: &[LeverageBracket], notional_usdt: f64, margin_balance: f64, headroom: f64) -> Result<u32> {
+	if margin_balance <= 0.0 {
+		bail!("No margin balance");
+	}
+	let sized = notional_usdt * headroom;
+	let needed = (sized / margin_balance).ceil().max(1.0) as u32;
+	let allowed = match brackets.iter().find(|b| sized >= b.notional_floor && sized < b.notional_cap) {
+		Some(b) => b.initial_leverage,
+		None => bail!("{:.2} USDT is over the largest leverage bracket", sized),
+	};
+	if needed > allowed {
+		bail!("{:.2} USDT needs leverage of {}, while at most {} is allowed at that size", sized, needed, allowed);
+	}
+	Ok(needed)
+}
+
+/// Sets the margin type from config and the lowest fitting leverage for what is held on the `asset`, plus `additional_usdt`.
+/// Called before anything that changes the size on it; leverage is per symbol, so it covers all positions on it.
+pub async fn adjust(config: &AppConfig, asset: &str, additional_usdt: f64) -> Result<u32> {
+	let (key, secret) = (config.binance.full_key.clone(), config.binance.full_secret.clone());
+	let held = binance::get_futures_symbol_notional(key, secret, Market::BinanceFutures.format_symbol(asset)).await?;
+	fit(config, asset, held + additional_usdt).await
+}
+
+/// Same as `adjust`, for a `notional_usdt` the caller knows better than the exchange; as right after a fill, which its positions may not reflect yet.
+pub async fn fit(config: &AppConfig, asset: &str, notional_usdt: f64) -> Result<u32> {
+	let (key, secret) = (config.binance.full_key.clone(), config.binance.full_secret.clone());
+	let symbol = Market::BinanceFutures.format_symbol(asset);
+	let (brackets, margin_balance) = tokio::try_join!(
+		binance::get_leverage_brackets(key.clone(), secret.clone(), symbol.clone()),
+		binance::get_balance(key.clone(), secret.clone(), Market::BinanceFutures),
+	)?;
+	let leverage = pick_leverage(&brackets, notional_usdt, margin_balance, config.margin.headroom)?;
+
+	// can't be changed while anything is open on the symbol; the leverage still can
+	if let Err(e) = binance::set_margin_type(key.clone(), secret.clone(), symbol.clone(), config.margin.margin_type).await {
+		warn!(symbol, margin_type = %config.margin.margin_type, "{}", e);
+	}
+	binance::set_leverage(key, secret, symbol.clone(), leverage).await?;
+	info!(symbol, leverage, notional_usdt, "Adjusted leverage");
+	Ok(leverage)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn brackets() -> Vec<LeverageBracket> {
+		[(0.0, 50000.0, 50), (50000.0, 250000.0, 20), (250000.0, 1000000.0, 10)]
+			.into_iter()
+			.map(|(notional_floor, notional_cap, initial_leverage)| LeverageBracket {
+				initial_leverage,
+				notional_cap,
+				notional_floor,
+				maint_margin_ratio: 0.01,
+			})
+			.collect()
+	}
+
+	#[test]
+	fn lowest_leverage_covering_the_notional_with_headroom() {
+		assert_eq!(pick_leverage(&brackets(), 500.0, 1000.0, 1.5).unwrap(), 1);
+		assert_eq!(pick_leverage(&brackets(), 1000.0, 1000.0, 1.5).unwrap(), 2);
+		assert_eq!(pick_leverage(&brackets(), 2000.0, 1000.0, 1.0).unwrap(), 2);
+		assert_eq!(pick_leverage(&brackets(), 2001.0, 1000.0, 1.0).unwrap(), 3);
+	}
+
+	#[test]
+	fn brackets_are_picked_by_the_notional_with_headroom() {
+		// 49999 * 1.0 is in the first bracket, while 1.5 of it is in the second
+		assert_eq!(pick_leverage(&brackets(), 49999.0, 1000.0, 1.0).unwrap(), 50);
+		assert!(pick_leverage(&brackets(), 49999.0, 1000.0, 1.5).is_err());
+		// the floor is in its bracket, the cap in the next one
+		assert_eq!(pick_leverage(&brackets(), 50000.0, 2500.0, 1.0).unwrap(), 20);
+		assert!(pick_leverage(&brackets(), 50000.0, 2499.0, 1.0).is_err());
+		assert_eq!(pick_leverage(&brackets(), 250000.0, 25000.0, 1.0).unwrap(), 10);
+	}
+
+	#[test]
+	fn refused_if_no_bracket_fits() {
+		assert!(pick_leverage(&brackets(), 1000000.0, 1e9, 1.0).is_err());
+		assert!(pick_leverage(&brackets(), 700000.0, 1e9, 1.5).is_err());
+		assert!(pick_leverage(&brackets(), 100.0, 0.0, 1.5).is_err());
+	}
+}
@@ -0,0 +1,187 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use v_utils::trades::Side;
+
+/// Part of the position closed by a single protocol.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProtocolPnl {
+	pub closed_notional: f64,
+	/// Sum of qty * price over the closing fills.
+	pub exit_value_usdt: f64,
+	/// Gross, fees are in `fees_usdt`.
+	pub realised_usdt: f64,
+	pub fees_usdt: f64,
+}
+impl ProtocolPnl {
+	pub fn avg_exit_price(&self) -> Option<f64> {
+		(self.closed_notional > 0.0).then(|| self.exit_value_usdt / self.closed_notional)
+	}
+}
+
+/// Running result of a position. Entry is a single weighted average, as all acquisition fills go to the same pot; exits are attributed to the protocols that produced them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PositionPnl {
+	pub side: Side,
+	pub entry_notional: f64,
+	/// Sum of qty * price over the entry fills.
+	pub entry_value_usdt: f64,
+	pub closed_notional: f64,
+	/// Gross, fees are in `fees_usdt`.
+	pub realised_usdt: f64,
+	/// All commissions paid, both on entry and on exit.
+	pub fees_usdt: f64,
+	pub by_protocol: HashMap<String, ProtocolPnl>,
+}
+impl PositionPnl {
+	pub fn new(side: Side) -> Self {
+		Self {
+			side,
+			entry_notional: 0.0,
+			entry_value_usdt: 0.0,
+			closed_notional: 0.0,
+			realised_usdt: 0.0,
+			fees_usdt: 0.0,
+			by_protocol: HashMap::new(),
+		}
+	}
+
+	fn direction(&self) -> f64 {
+		match self.side {
+			Side::Buy => 1.0,
+			Side::Sell => -1.0,
+		}
+	}
+
+	pub fn avg_entry_price(&self) -> Option<f64> {
+		(self.entry_notional > 0.0).then(|| self.entry_value_usdt / self.entry_notional)
+	}
+
+	pub fn open_notional(&self) -> f64 {
+		self.entry_notional - self.closed_notional
+	}
+
+	pub fn record_entry(&mut self, qty: f64, price: f64, commission_usdt: f64) {
+		self.entry_notional += qty;
+		self.entry_value_usdt += qty * price;
+		self.fees_usdt += commission_usdt;
+	}
+
+	/// `produced_by` is the protocol whose order got filled.
+	pub fn record_exit(&mut self, produced_by: &str, qty: f64, price: f64, commission_usdt: f64) {
+		let realised = match self.avg_entry_price() {
+			Some(entry) => (price - entry) * qty * self.direction(),
+			None => 0.0,
+		};
+		self.closed_notional += qty;
+		self.realised_usdt += realised;
+		self.fees_usdt += commission_usdt;
+
+		let protocol = self.by_protocol.entry(produced_by.to_owned()).or_default();
+		protocol.closed_notional += qty;
+		protocol.exit_value_usdt += qty * price;
+		protocol.realised_usdt += realised;
+		protocol.fees_usdt += commission_usdt;
+	}
+
+	pub fn unrealised_usdt(&self, mark_price: f64) -> f64 {
+		match self.avg_entry_price() {
+			Some(entry) => (mark_price - entry) * self.open_notional() * self.direction(),
+			None => 0.0,
+		}
+	}
+
+	/// Unrealised, less what closing the rest at `exit_fee_rate` would cost.
+	pub fn net_unrealised_usdt(&self, mark_price: f64, exit_fee_rate: f64) -> f64 {
+		self.unrealised_usdt(mark_price) - mark_price * self.open_notional() * exit_fee_rate
+	}
+
+	/// Price at which closing what is open, paying `exit_fee_rate` on it, leaves the whole position at zero after all fees.
+	pub fn break_even_price(&self, exit_fee_rate: f64) -> Option<f64> {
+		let entry = self.avg_entry_price()?;
+		let open = self.open_notional();
+		if open <= 0.0 {
+			return None;
+		}
+		// solves realised + (p - entry) * open * direction - fees - p * open * exit_fee_rate = 0
+		let owed = self.fees_usdt - self.realised_usdt + entry * open * self.direction();
+		Some(owed / (open * (self.direction() - exit_fee_rate)))
+	}
+
+	/// Realised minus all fees.
+	pub fn net_realised_usdt(&self) -> f64 {
+		self.realised_usdt - self.fees_usdt
+	}
+
+	/// Return on the part of the position the protocol closed, relative to the entry price. Ex: `0.012` for +1.2%.
+	pub fn protocol_return(&self, produced_by: &str) -> Option<f64> {
+		let protocol = self.by_protocol.get(produced_by)?;
+		let entry = self.avg_entry_price()?;
+		(protocol.closed_notional > 0.0).then(|| protocol.realised_usdt / (entry * protocol.closed_notional))
+	}
+
+	/// Share of the acquired size the protocol closed.
+	pub fn protocol_share(&self, produced_by: &str) -> Option<f64> {
+		let protocol = self.by_protocol.get(produced_by)?;
+		(self.entry_notional > 0.0).then(|| protocol.closed_notional / self.entry_notional)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const FEE: f64 = 0.0005;
+
+	/// Bought 2 at 100 on average, half of it closed by "tp" at 110.
+	fn long() -> PositionPnl {
+		let mut pnl = PositionPnl::new(Side::Buy);
+		pnl.record_entry(1.0, 99.0, 0.0495);
+		pnl.record_entry(1.0, 101.0, 0.0505);
+		pnl.record_exit("tp", 1.0, 110.0, 0.055);
+		pnl
+	}
+
+	#[test]
+	fn exits_are_realised_against_the_average_entry() {
+		let pnl = long();
+		assert_eq!(pnl.avg_entry_price(), Some(100.0));
+		assert_eq!(pnl.open_notional(), 1.0);
+		assert!((pnl.realised_usdt - 10.0).abs() < 1e-9);
+		assert!((pnl.net_realised_usdt() - (10.0 - 0.155)).abs() < 1e-9);
+		assert!((pnl.protocol_return("tp").unwrap() - 0.1).abs() < 1e-9);
+		assert_eq!(pnl.protocol_share("tp"), Some(0.5));
+	}
+
+	#[test]
+	fn net_unrealised_pays_for_the_exit() {
+		let pnl = long();
+		assert!((pnl.net_unrealised_usdt(120.0, FEE) - (20.0 - 120.0 * FEE)).abs() < 1e-9);
+
+		let mut short = PositionPnl::new(Side::Sell);
+		short.record_entry(2.0, 100.0, 0.1);
+		assert!((short.net_unrealised_usdt(90.0, FEE) - (20.0 - 2.0 * 90.0 * FEE)).abs() < 1e-9);
+	}
+
+	#[test]
+	fn break_even_closes_at_zero_after_all_fees() {
+		let pnl = long();
+		let p = pnl.break_even_price(FEE).unwrap();
+		// what is already realised covers part of the way down
+		assert!(p < 100.0);
+		assert!((pnl.net_realised_usdt() + pnl.net_unrealised_usdt(p, FEE)).abs() < 1e-9);
+
+		let mut short = PositionPnl::new(Side::Sell);
+		short.record_entry(2.0, 100.0, 0.1);
+		let p = short.break_even_price(FEE).unwrap();
+		assert!(p < 100.0);
+		assert!((short.net_realised_usdt() + short.net_unrealised_usdt(p, FEE)).abs() < 1e-9);
+	}
+
+	#[test]
+	fn no_break_even_without_anything_open() {
+		assert_eq!(PositionPnl::new(Side::Buy).break_even_price(FEE), None);
+		let mut pnl = long();
+		pnl.record_exit("sl", 1.0, 95.0, 0.0475);
+		assert_eq!(pnl.break_even_price(FEE), None);
+	}
+}
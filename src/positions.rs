@@ -1,86 +1,209 @@
-use crate::api::order_types::{ConceptualOrder, ConceptualOrderPercents};
-use crate::api::{binance, Symbol};
+use crate::api::order_types::{ConceptualMarket, ConceptualOrder, ConceptualOrderPercents, Fill, ProtocolOrderId};
+use crate::api::user_data::{UserDataEvent, UserDataStream};
+use crate::api::binance::{self, PositionMode, PositionSide};
+use crate::api::fees::FeeModel;
+use crate::api::hub::{Hub, TargetUpdate};
+use crate::api::order_book::OrderBooks;
+use crate::api::{Market, Symbol};
+use crate::data_store::{DataStore, FillRecord, RecordBuffer, TargetOrderRecord};
+use crate::config::AppConfig;
+use crate::control::{ControlCommand, ControlReply};
+use crate::journal::{Journal, JournalEvent, Replayed};
+use crate::pnl::PositionPnl;
+use crate::reconciler::{ExposureBook, Freeze};
+use crate::risk::RiskGuard;
+use crate::stops::{StopExpression, StopMode};
 use crate::protocols::{FollowupProtocol, ProtocolOrders, ProtocolType};
 use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::str::FromStr;
-use tokio::select;
-use tracing::{info, instrument};
+use tokio::{
+	select,
+	sync::{broadcast, mpsc},
+};
+use tracing::{error, info, instrument, warn};
 use uuid::Uuid;
 use v_utils::trades::Side;
 
+/// Longest the acquisition waits on its order without hearing anything of it.
+const ACQUISITION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
 /// What the Position _*is*_
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PositionSpec {
+	pub id: Uuid,
 	pub asset: String,
 	pub side: Side,
 	pub size_usdt: f64,
+	/// Journals from before it was introduced had all stops native.
+	#[serde(default)]
+	pub stop_mode: StopMode,
 }
 impl PositionSpec {
-	pub fn new(asset: String, side: Side, size_usdt: f64) -> Self {
-		Self { asset, side, size_usdt }
+	pub fn new(asset: String, side: Side, size_usdt: f64, stop_mode: StopMode) -> Self {
+		Self {
+			id: Uuid::new_v4(),
+			asset,
+			side,
+			size_usdt,
+			stop_mode,
+		}
+	}
+
+	pub fn symbol(&self) -> Symbol {
+		Symbol {
+			base: self.asset.clone(),
+			quote: "USDT".to_owned(),
+			market: Market::BinanceFutures,
+		}
+	}
+}
+
+/// Stages are only ever passed in order, except for `Acquiring -> Closed` when nothing was acquired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PositionStage {
+	Pending,
+	Acquiring,
+	Following,
+	/// Some of the position has already been closed.
+	Closing,
+	Closed,
+}
+impl PositionStage {
+	pub fn can_transition_to(&self, next: PositionStage) -> bool {
+		use PositionStage::*;
+		matches!(
+			(self, next),
+			(Pending, Acquiring) | (Acquiring, Following) | (Acquiring, Closed) | (Following, Closing) | (Closing, Closed)
+		)
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lifecycle {
+	/// Every stage the position has been in, with the time it was entered.
+	transitions: Vec<(PositionStage, DateTime<Utc>)>,
+}
+impl Lifecycle {
+	pub fn new() -> Self {
+		Self {
+			transitions: vec![(PositionStage::Pending, Utc::now())],
+		}
+	}
+
+	/// Restores the lifecycle from already validated transitions, ex: the ones in the journal.
+	pub fn from_transitions(transitions: Vec<(PositionStage, DateTime<Utc>)>) -> Self {
+		Self { transitions }
+	}
+
+	pub fn stage(&self) -> PositionStage {
+		self.transitions.last().unwrap().0
+	}
+
+	pub fn entered_at(&self, stage: PositionStage) -> Option<DateTime<Utc>> {
+		self.transitions.iter().find(|(s, _)| *s == stage).map(|(_, t)| *t)
+	}
+
+	/// Records the transition in the journal; refuses any not allowed by `PositionStage::can_transition_to`.
+	pub fn transition(&mut self, to: PositionStage, journal: &Journal) -> Result<()> {
+		let from = self.stage();
+		if !from.can_transition_to(to) {
+			anyhow::bail!("Invalid position stage transition: {:?} -> {:?}", from, to);
+		}
+		journal.append(JournalEvent::Transition { from, to })?;
+		self.transitions.push((to, Utc::now()));
+		Ok(())
 	}
 }
 
 #[derive(Debug)]
 pub struct PositionAcquisition {
-	spec: PositionSpec,
-	target_notional: f64,
-	acquired_notional: f64,
+	pub spec: PositionSpec,
+	pub target_notional: f64,
+	pub acquired_notional: f64,
+	pub lifecycle: Lifecycle,
+	pub pnl: PositionPnl,
+	/// Of the acquisition order. The followup continues the position's target in the hub, so it has to pick up from here.
+	pub last_fill: Option<Uuid>,
 	protocols_spec: Option<String>, //Vec<AcquisitionProtocol>,
 }
 impl PositionAcquisition {
-	pub async fn dbg_new(spec: PositionSpec) -> Result<Self> {
-		Ok(Self {
-			spec,
-			target_notional: 10.0,
-			acquired_notional: 10.0,
-			protocols_spec: None,
-		})
-	}
-
-	pub async fn do_acquisition(spec: PositionSpec) -> Result<Self> {
-		// is this not in config?
-		let full_key = std::env::var("BINANCE_TIGER_FULL_KEY").unwrap();
-		let full_secret = std::env::var("BINANCE_TIGER_FULL_SECRET").unwrap();
-		//let position = Position::new(Market::BinanceFutures, side, symbol.clone(), usdt_quantity, protocols, Utc::now());
-		let coin = spec.asset.clone();
-		let symbol = Symbol::from_str(format!("{coin}-USDT-BinanceFutures").as_str())?;
-		info!(coin);
-
-		let (current_price, quantity_precision) = tokio::join! {
-			binance::futures_price(&coin),
-			binance::futures_quantity_precision(&coin),
-		};
-		let factor = 10_f64.powi(quantity_precision as i32);
-		let coin_quantity = spec.size_usdt / current_price;
-		let coin_quantity_adjusted = (coin_quantity * factor).round() / factor;
+	/// Enters with a single market order, put through the hub like any other. Waits until it is filled, or the exchange gives up on it.
+	/// Ends up with whatever was filled; fails only if that is nothing.
+	pub async fn do_acquisition(spec: PositionSpec, journal: &Journal, engine: &EngineHandles) -> Result<Self> {
+		let mut lifecycle = Lifecycle::new();
+		lifecycle.transition(PositionStage::Acquiring, journal)?;
+		let symbol = spec.symbol();
+		let (current_price, rules) = tokio::try_join!(binance::futures_price(&spec.asset), binance::futures_symbol_rules(&symbol.to_string()))?;
+		// pre-rounded the way the executor would, as rounding leftovers are not reported back
+		let target_notional = rules.floor_qty(spec.size_usdt / current_price, true);
+		info!(%symbol, target_notional, "Acquiring");
 
 		let mut current_state = Self {
 			spec: spec.clone(),
-			target_notional: coin_quantity_adjusted,
+			target_notional,
 			acquired_notional: 0.0,
+			lifecycle,
+			pnl: PositionPnl::new(spec.side.clone()),
+			last_fill: None,
 			protocols_spec: None,
 		};
 
-		let order_id = binance::post_futures_order(
-			full_key.clone(),
-			full_secret.clone(),
-			"MARKET".to_string(),
-			symbol.to_string(),
-			spec.side.clone(),
-			coin_quantity_adjusted,
-		)
-		.await?;
-		//info!(target: "/tmp/discretionary_engine.lock", "placed order: {:?}", order_id);
-		loop {
-			let order = binance::poll_futures_order(full_key.clone(), full_secret.clone(), order_id, symbol.to_string()).await?;
-			if order.status == binance::OrderStatus::Filled {
-				let order_notional = order.origQty.parse::<f64>()?;
-				current_state.acquired_notional += order_notional;
-				break;
+		let position_side = PositionSide::of_position(engine.position_mode, &spec.side);
+		let (tx_fills, mut rx_fills) = mpsc::unbounded_channel::<ExecutionUpdate>();
+		let mut target_orders = TargetOrders::new(engine.tx_targets.clone(), spec.side.clone(), position_side, PositionCallback::new(tx_fills, spec.id));
+		let order = ConceptualOrder::Market(ConceptualMarket {
+			id: ProtocolOrderId::new(ACQUISITION.to_owned(), Uuid::new_v4()),
+			maximum_slippage_percent: 1.0,
+			symbol: symbol.clone(),
+			side: spec.side.clone(),
+			qty_notional: target_notional,
+		});
+		let mut all_fills = HashMap::new();
+		target_orders.update_orders(vec![order], None, &all_fills);
+
+		while current_state.acquired_notional < target_notional {
+			let update = match tokio::time::timeout(ACQUISITION_TIMEOUT, rx_fills.recv()).await {
+				Ok(Some(update)) => update,
+				Ok(None) => anyhow::bail!("Execution layer is gone"),
+				Err(_) => {
+					warn!(acquired = current_state.acquired_notional, target_notional, "Nothing heard of the acquisition order for {:?}, giving up on the rest", ACQUISITION_TIMEOUT);
+					break;
+				}
+			};
+			match update {
+				ExecutionUpdate::Fill(fill_id, fill) => {
+					current_state.last_fill = Some(fill_id);
+					all_fills.insert(fill.id.uuid, fill.filled_notional);
+					current_state.acquired_notional = fill.filled_notional;
+					current_state.pnl.record_entry(fill.last_qty, fill.last_price, fill.commission_usdt);
+					if let Err(e) = journal.append(JournalEvent::Fill(fill)) {
+						error!("Failed to journal a fill: {}", e);
+					}
+					let signed = match spec.side {
+						Side::Buy => current_state.acquired_notional,
+						Side::Sell => -current_state.acquired_notional,
+					};
+					engine.exposure.set_position(spec.id, symbol.to_string(), position_side, signed);
+				}
+				ExecutionUpdate::Unfilled { qty_notional, why, .. } => {
+					warn!(qty_notional, "Part of the acquisition order was not filled: {}", why);
+					break;
+				}
 			}
 		}
+		// the followup takes over under the same id
+		target_orders.update_orders(Vec::new(), current_state.last_fill, &all_fills);
+		if current_state.acquired_notional <= 0.0 {
+			engine.exposure.remove_position(&spec.id);
+			current_state.lifecycle.transition(PositionStage::Closed, journal)?;
+			anyhow::bail!("Nothing was acquired");
+		}
+		current_state.lifecycle.transition(PositionStage::Following, journal)?;
 
 		Ok(current_state)
 	}
@@ -93,87 +216,275 @@ pub struct PositionFollowup {
 	closed_notional: f64,
 }
 
+/// Engine-wide machinery every position reports to.
+#[derive(Debug, Clone)]
+pub struct EngineHandles {
+	pub config: AppConfig,
+	pub positions_dir: PathBuf,
+	/// Detected once on start; switching it on the exchange requires having no positions open anyway.
+	pub position_mode: PositionMode,
+	pub data_store: DataStore,
+	pub exposure: ExposureBook,
+	pub freeze: Freeze,
+	/// Entry into the execution layer, shared by all positions.
+	pub tx_targets: mpsc::UnboundedSender<TargetUpdate>,
+	pub risk: RiskGuard,
+	/// Fills, order updates and balance changes of the account, as pushed by the exchange. `subscribe()` to listen.
+	pub user_data: broadcast::Sender<UserDataEvent>,
+	/// Local books of the symbols positions are on. `follow` a symbol to have its book maintained.
+	pub books: OrderBooks,
+	/// Fee rates of the account, per symbol.
+	pub fees: FeeModel,
+}
+impl EngineHandles {
+	/// Also starts the reconciler, the risk guard, the execution layer and the user-data stream, which run for as long as the process does.
+	pub async fn init(config: &AppConfig) -> Result<Self> {
+		let position_mode = binance::get_position_mode(config.binance.read_key.clone(), config.binance.read_secret.clone()).await?;
+		info!(?position_mode);
+		if let Err(e) = binance::load_futures_rate_limits().await {
+			warn!("Failed to load rate limits, going with the defaults: {}", e);
+		}
+		let (user_data, _) = broadcast::channel(1024);
+		let exposure = ExposureBook::new(&config.positions_dir)?;
+		let freeze = Freeze::new(&config.positions_dir);
+		let books = OrderBooks::default();
+		let fees = FeeModel::new(config);
+		let tx_targets = Hub::spawn(config, &user_data, &books, &fees, &exposure);
+		let handles = Self {
+			config: config.clone(),
+			positions_dir: config.positions_dir.clone(),
+			position_mode,
+			data_store: DataStore::new(&config.positions_dir)?,
+			risk: RiskGuard::new(config, exposure.clone(), freeze.clone()),
+			exposure,
+			freeze,
+			tx_targets,
+			user_data,
+			books,
+			fees,
+		};
+		UserDataStream::new(config.binance.full_key.clone(), config.binance.full_secret.clone()).spawn(handles.user_data.clone());
+		crate::reconciler::spawn(config.clone(), handles.exposure.clone(), handles.freeze.clone());
+		crate::risk::spawn(config.clone(), handles.risk.clone());
+		Ok(handles)
+	}
+}
+
+/// What a followup starts with. Empty for new positions; restored from the journal for resumed ones.
+#[derive(Debug, Default)]
+pub struct FollowupCarryover {
+	pub all_requested: HashMap<String, ProtocolOrders>,
+	pub all_fills: HashMap<Uuid, f64>,
+	pub manual_orders: HashMap<Uuid, ConceptualOrder>,
+}
+
 /// Internal representation of desired orders. The actual orders are synchronized to this, so any details of actual execution are mostly irrelevant.
 /// Thus these orders have no actual ID; only being tagged with what protocol spawned them.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 struct TargetOrders {
 	stop_orders_total_notional: f64,
 	normal_orders_total_notional: f64,
 	market_orders_total_notional: f64,
 	//total_usd: f64,
 	orders: Vec<ConceptualOrder>,
+	side: Side,
+	position_side: PositionSide,
+	tx_targets: mpsc::UnboundedSender<TargetUpdate>,
+	callback: PositionCallback,
 }
 impl TargetOrders {
-	//TODO!!!!!!!!!: after updating orders internally, send a channeled message with new state of target_orders right from here \
+	fn new(tx_targets: mpsc::UnboundedSender<TargetUpdate>, side: Side, position_side: PositionSide, callback: PositionCallback) -> Self {
+		Self {
+			stop_orders_total_notional: 0.0,
+			normal_orders_total_notional: 0.0,
+			market_orders_total_notional: 0.0,
+			orders: Vec::new(),
+			side,
+			position_side,
+			tx_targets,
+			callback,
+		}
+	}
+
 	// vec of actual orders can be created on the spot, as we don't care if we accidentially close exposure openned by a different order.
 	// If the distribution of orders to exact exchanges doesn't pertain after the start, there will just be a decision layer for whether we move an existing order in price, or open a new one on a different exchange.
 	// there are also some edge-cases where the order could be too small, and this should be handled on the exchange_api side.
 	// equally so, the maximum update frequency of orders set by exchange shall too be tracked by the execution algorithm.
 
-	// if we get an error because we did not pass the correct uuid from the last fill message, we just drop the task, as we will be forced to run with a correct value very soon.
-	/// Never fails, instead the errors are sent over the channel.
-	fn update_orders(&mut self, orders: Vec<ConceptualOrder>, sender: std::sync::mpsc::Sender<Vec<ConceptualOrder>>) {
-		for order in orders.into_iter() {
+	// if the hub refuses it because we did not pass the correct uuid from the last fill message, we just move on, as we will be forced to run with a correct value very soon.
+	/// Replaces the target with `orders` and hands it over to the execution layer, alongside the callback fills on them are to be reported through.
+	/// `last_fill` is the id of the last fill processed, `all_fills` what is known to be filled on each order.
+	/// Never fails, instead the errors are logged.
+	fn update_orders(&mut self, orders: Vec<ConceptualOrder>, last_fill: Option<Uuid>, all_fills: &HashMap<Uuid, f64>) {
+		self.stop_orders_total_notional = 0.0;
+		self.normal_orders_total_notional = 0.0;
+		self.market_orders_total_notional = 0.0;
+		for order in orders.iter() {
 			match order {
 				ConceptualOrder::StopMarket(_) => self.stop_orders_total_notional += order.notional(),
 				ConceptualOrder::Limit(_) => self.normal_orders_total_notional += order.notional(),
 				ConceptualOrder::Market(_) => self.market_orders_total_notional += order.notional(),
 			}
-			self.orders.push(order);
 		}
-		sender.send(self.orders.clone()).unwrap();
+		self.orders = orders;
+		let update = TargetUpdate {
+			orders: self.orders.clone(),
+			side: self.side.clone(),
+			position_side: self.position_side,
+			last_fill,
+			filled: self.orders.iter().filter_map(|o| all_fills.get(&o.id().uuid).map(|f| (o.id().uuid, *f))).collect(),
+			callback: self.callback.clone(),
+		};
+		if let Err(e) = self.tx_targets.send(update) {
+			error!("Execution layer is gone, target orders not delivered: {}", e);
+		}
 	}
-	//TODO!!!!!!!!!!!!!!!!: fill channel. Want to receive data on every fill alongside the protocol_order_id, which is required when sending the update_orders() request, defined right above this.
 }
 
-/// A thing we listen for fills through
-#[derive(Deebug, Hash, Clone)]
+/// What the execution layer reports on the orders of a position.
+#[derive(Debug, Clone)]
+pub enum ExecutionUpdate {
+	/// Sent under an id, which the position is to pass along with its next target orders.
+	Fill(Uuid, Fill),
+	/// Part of a market order the exchange would not fill within its slippage bound, or at all. It stays in the target, and is retried once that is sent again.
+	Unfilled { id: ProtocolOrderId, qty_notional: f64, why: String },
+}
+
+/// A thing we listen for fills through. Travels with every target-orders update, so the execution layer knows where to report.
+#[derive(Debug, Clone)]
 pub struct PositionCallback {
-	sender: std::sync::mpsc::Sender<Vec<(f64, ProtocolOrderId)>>, // stands for "this nominal qty filled on this protocol order"
-	position_uuid: Uuid,
+	sender: mpsc::UnboundedSender<ExecutionUpdate>,
+	pub position_uuid: Uuid,
+}
+impl PositionCallback {
+	pub fn new(sender: mpsc::UnboundedSender<ExecutionUpdate>, position_uuid: Uuid) -> Self {
+		Self { sender, position_uuid }
+	}
+
+	/// `fill.filled_notional` is cumulative over the protocol order, not over the exchange order expressing it.
+	/// Returns the id the fill was sent under.
+	pub fn send_fill(&self, fill: Fill) -> Result<Uuid> {
+		let fill_id = Uuid::new_v4();
+		self.send(ExecutionUpdate::Fill(fill_id, fill))?;
+		Ok(fill_id)
+	}
+
+	pub fn send_unfilled(&self, id: ProtocolOrderId, qty_notional: f64, why: String) -> Result<()> {
+		self.send(ExecutionUpdate::Unfilled { id, qty_notional, why })
+	}
+
+	fn send(&self, update: ExecutionUpdate) -> Result<()> {
+		self.sender
+			.send(update)
+			.map_err(|_| anyhow::anyhow!("Position {} no longer listens for fills", self.position_uuid))
+	}
 }
 
 impl PositionFollowup {
 	#[instrument]
-	pub async fn do_followup(acquired: PositionAcquisition, protocols: Vec<FollowupProtocol>) -> Result<Self> {
+	pub async fn do_followup(
+		acquired: PositionAcquisition,
+		protocols: Vec<FollowupProtocol>,
+		engine: EngineHandles,
+		journal: Journal,
+		carryover: FollowupCarryover,
+	) -> Result<PositionClosed> {
 		let mut counted_subtypes: HashMap<ProtocolType, usize> = HashMap::new();
 		for protocol in &protocols {
 			let subtype = protocol.get_subtype();
 			*counted_subtypes.entry(subtype).or_insert(0) += 1;
 		}
 
-		let (tx_orders, rx_orders) = std::sync::mpsc::channel::<ProtocolOrders>();
+		let (tx_orders, mut rx_orders) = mpsc::unbounded_channel::<ProtocolOrders>();
+		let (tx_fills, mut rx_fills) = mpsc::unbounded_channel::<ExecutionUpdate>();
+		let mut protocol_handles = Vec::new();
 		for protocol in protocols.clone() {
-			protocol.attach(tx_orders.clone(), &acquired.spec)?;
+			protocol_handles.push(protocol.attach(tx_orders.clone(), &acquired.spec)?);
 		}
+		let mut lifecycle = acquired.lifecycle.clone();
+		let mut pnl = acquired.pnl.clone();
+		let mut pnl_report = tokio::time::interval(std::time::Duration::from_secs(60));
 
-		let mut all_requested: HashMap<String, ProtocolOrders> = HashMap::new();
+		let FollowupCarryover {
+			mut all_requested,
+			mut all_fills,
+			mut manual_orders,
+		} = carryover;
 		let mut all_requested_unrolled: HashMap<String, Vec<ConceptualOrder>> = HashMap::new();
-		let mut closed_notional = 0.0;
-		let mut target_orders = TargetOrders::default();
+		let (carried_entered, mut closed_notional) = split_fills(&all_fills, &manual_orders, &acquired.spec.side);
+		// `acquired_notional` of a resumed position already includes scale-ins done before the restart
+		let base_entry_notional = acquired.acquired_notional - carried_entered;
+		let mut entry_notional = acquired.acquired_notional;
+		let mut closing_all = false;
+		let mut rx_control = crate::control::listen(&engine.positions_dir, acquired.spec.id)?;
+		let mut target_orders = TargetOrders::new(
+			engine.tx_targets.clone(),
+			acquired.spec.side.clone(),
+			PositionSide::of_position(engine.position_mode, &acquired.spec.side),
+			PositionCallback::new(tx_fills, acquired.spec.id),
+		);
+		let mut last_fill = acquired.last_fill;
+		let mut unfilled_retries: HashMap<Uuid, u32> = HashMap::new();
+		engine.books.follow(&acquired.spec.symbol());
+		// scale-ins at market are checked against the mark, which the risk refresh would only get to later
+		if let Err(e) = engine.risk.price_at_mark(&acquired.spec.symbol()).await {
+			warn!("Failed to price {} for risk checks: {e}", acquired.spec.symbol());
+		}
+		// closing is assumed to be at market, so at the taker rate
+		let exit_fee_rate = engine.fees.get(&acquired.spec.symbol()).await.taker;
+		let stop_expression = StopExpression::new(acquired.spec.stop_mode, engine.config.execution.disaster_stop_distance, &acquired.spec.symbol());
+		let mut stop_triggers = stop_expression.clone();
 
-		let mut all_fills: HashMap<Uuid, f64> = HashMap::new();
+		let mut recorded_target_orders: RecordBuffer<TargetOrderRecord> = RecordBuffer::new(engine.data_store.clone(), acquired.spec.symbol());
+		let mut recorded_fills: RecordBuffer<FillRecord> = RecordBuffer::new(engine.data_store.clone(), acquired.spec.symbol());
 
-		let mut update_unrolled = |update_on: String| {
+		let report_exposure = |left: f64| {
+			let signed = match acquired.spec.side {
+				Side::Buy => left,
+				Side::Sell => -left,
+			};
+			engine.exposure.set_position(
+				acquired.spec.id,
+				acquired.spec.symbol().to_string(),
+				PositionSide::of_position(engine.position_mode, &acquired.spec.side),
+				signed,
+			);
+		};
+		report_exposure(entry_notional - closed_notional);
+
+		// Protocols split what is not being closed by hand, so a manual close shrinks them right away, before it's even filled.
+		let controlled_notional = |entry_notional: f64, manual_orders: &HashMap<Uuid, ConceptualOrder>| {
+			entry_notional
+				- manual_orders
+					.values()
+					.filter(|o| o.side() != &acquired.spec.side)
+					.map(|o| o.notional())
+					.sum::<f64>()
+		};
+
+		let mut update_unrolled = |update_on: String, controlled_notional: f64| {
 			let protocol = FollowupProtocol::from_str(&update_on).unwrap();
 			let subtype = protocol.get_subtype();
 			let size_multiplier = 1.0 / *counted_subtypes.get(&subtype).unwrap() as f64;
-			let total_controlled_size = acquired.acquired_notional * size_multiplier;
+			let total_controlled_size = controlled_notional * size_multiplier;
 
 			let mut mask = all_requested[&update_on].empty_mask();
-			for (key, value) in mask {
-				if all_fills.contains_key(&key) {
-					mask.insert(key, *all_fills.get(&key).unwrap());
+			for (uuid, filled) in mask.iter_mut() {
+				if let Some(f) = all_fills.get(uuid) {
+					*filled = *f;
 				}
 			}
 			let order_batch = all_requested[&update_on].apply_mask(mask, total_controlled_size);
 			all_requested_unrolled.insert(update_on, order_batch);
 		};
 
-		let mut update_target_orders = || {
+		let mut update_target_orders = |left_to_close: f64, manual_pending: Vec<ConceptualOrder>, last_fill: Option<Uuid>, all_fills: &HashMap<Uuid, f64>| {
 			let mut market_orders = Vec::new();
 			let mut stop_orders = Vec::new();
 			let mut limit_orders = Vec::new();
+			let (manual_entries, manual_exits): (Vec<ConceptualOrder>, Vec<ConceptualOrder>) =
+				manual_pending.into_iter().partition(|o| o.side() == &acquired.spec.side);
+			market_orders.extend(manual_exits);
 			for (_key, value) in all_requested_unrolled {
 				value.into_iter().for_each(|o| match o {
 					ConceptualOrder::StopMarket(_) => stop_orders.push(o),
@@ -182,23 +493,37 @@ impl PositionFollowup {
 				});
 			}
 
-			let mut left_to_target_full_notional = acquired.acquired_notional - closed_notional;
+			let mut left_to_target_full_notional = left_to_close;
 			let (mut left_to_target_spot_notional, mut left_to_target_normal_notional) = (left_to_target_full_notional, left_to_target_full_notional);
 			let mut new_target_orders: Vec<ConceptualOrder> = Vec::new();
 
 			// orders should be all of the same conceptual type (no idea how to enforce it)
+			let frozen = engine.freeze.is_frozen();
 			let mut update_target_orders = |orders: Vec<ConceptualOrder>| {
 				for order in orders {
-					let notional = order.notional();
+					if order.side() == &acquired.spec.side {
+						if frozen {
+							warn!(?order, "Exposure-increasing order dropped, as the engine is frozen");
+							continue;
+						}
+						if let Err(e) = engine.risk.check_order(&order) {
+							warn!(?order, "Exposure-increasing order dropped: {}", e);
+							continue;
+						}
+					}
 					let compare_against = match order {
 						ConceptualOrder::StopMarket(_) => left_to_target_spot_notional,
 						ConceptualOrder::Limit(_) => left_to_target_normal_notional,
 						ConceptualOrder::Market(_) => left_to_target_full_notional,
 					};
 					let mut order = order.clone();
-					if notional > compare_against {
+					if order.notional() > compare_against {
 						order.cut_size(compare_against);
 					}
+					let notional = order.notional();
+					if notional <= 0.0 {
+						continue;
+					}
 					new_target_orders.push(order.clone());
 					match order {
 						ConceptualOrder::StopMarket(_) => left_to_target_spot_notional -= notional,
@@ -221,6 +546,10 @@ impl PositionFollowup {
 				}
 			};
 
+			// triggered synthetic stops are market orders by now
+			let (triggered_stops, mut stop_orders) = stop_expression.express(stop_orders);
+			market_orders.extend(triggered_stops);
+
 			//NB: market-like orders MUST be ran first!
 			update_target_orders(market_orders);
 
@@ -237,36 +566,426 @@ impl PositionFollowup {
 			update_target_orders(stop_orders);
 			update_target_orders(limit_orders);
 
-			target_orders.update_orders(new_target_orders);
+			// add to the position, so are not bounded by what's left of it
+			for order in manual_entries {
+				if frozen {
+					warn!(?order, "Manual scale-in dropped, as the engine is frozen");
+					continue;
+				}
+				if let Err(e) = engine.risk.check_order(&order) {
+					warn!(?order, "Manual scale-in dropped: {}", e);
+					continue;
+				}
+				new_target_orders.push(order);
+			}
+
+			recorded_target_orders.extend(TargetOrderRecord::snapshot(&new_target_orders));
+			if let Err(e) = journal.append(JournalEvent::TargetOrders { orders: new_target_orders.clone() }) {
+				error!("Failed to journal target orders: {}", e);
+			}
+			target_orders.update_orders(new_target_orders, last_fill, all_fills);
 		};
 
-		//TODO!: figure out abort when all closed.
 		loop {
 			select! {
 				Some(protocol_orders) = rx_orders.recv() => {
+					if let Err(e) = journal.append(JournalEvent::ProtocolOrders(protocol_orders.clone())) {
+						error!("Failed to journal protocol orders: {}", e);
+					}
+					if let Some(state) = protocols.iter().find(|p| p.to_string() == protocol_orders.produced_by).and_then(|p| p.internal_state()) {
+						if let Err(e) = journal.append(JournalEvent::ProtocolState { produced_by: protocol_orders.produced_by.clone(), state }) {
+							error!("Failed to journal protocol state: {}", e);
+						}
+					}
+					if closing_all {
+						continue;
+					}
 					all_requested.insert(protocol_orders.produced_by.clone(), protocol_orders.clone());
-					update_unrolled(protocol_orders.produced_by.clone());
-					update_target_orders();
+					update_unrolled(protocol_orders.produced_by.clone(), controlled_notional(entry_notional, &manual_orders));
+					update_target_orders(entry_notional - closed_notional, pending_manual_orders(&manual_orders, &all_fills), last_fill, &all_fills);
+				},
+				Some(update) = rx_fills.recv() => {
+					let (fill_id, fill) = match update {
+						ExecutionUpdate::Fill(fill_id, fill) => (fill_id, fill),
+						ExecutionUpdate::Unfilled { id, qty_notional, why } => {
+							// the rest stays in the target, but is only put to the exchange again once the target is sent
+							let retries = unfilled_retries.entry(id.uuid).or_insert(0);
+							*retries += 1;
+							match *retries <= engine.config.execution.unfilled_retries {
+								true => {
+									warn!(position_id = %acquired.spec.id, ?id, qty_notional, retry = *retries, "Market order partially unfilled, retrying: {}", why);
+									update_target_orders(entry_notional - closed_notional, pending_manual_orders(&manual_orders, &all_fills), last_fill, &all_fills);
+								}
+								false => error!(position_id = %acquired.spec.id, ?id, qty_notional, "Market order partially unfilled, out of retries: {}", why),
+							}
+							continue;
+						}
+					};
+					last_fill = Some(fill_id);
+					all_fills.insert(fill.id.uuid, fill.filled_notional);
+					let (entered, closed) = split_fills(&all_fills, &manual_orders, &acquired.spec.side);
+					entry_notional = base_entry_notional + entered;
+					closed_notional = closed;
+					report_exposure(entry_notional - closed_notional);
+					let is_entry = manual_orders.get(&fill.id.uuid).map(|o| o.side() == &acquired.spec.side).unwrap_or(false);
+					match is_entry {
+						true => pnl.record_entry(fill.last_qty, fill.last_price, fill.commission_usdt),
+						false => pnl.record_exit(&fill.id.produced_by, fill.last_qty, fill.last_price, fill.commission_usdt),
+					}
+					if let Err(e) = journal.append(JournalEvent::Fill(fill.clone())) {
+						error!("Failed to journal a fill: {}", e);
+					}
+					recorded_fills.extend([FillRecord {
+						t_ms: fill.t.timestamp_millis(),
+						produced_by: fill.id.produced_by.clone(),
+						order_uuid: fill.id.uuid,
+						price: Some(fill.last_price),
+						qty_notional: fill.last_qty,
+						commission_usdt: fill.commission_usdt,
+					}]);
+
+					if !is_entry && lifecycle.stage() == PositionStage::Following {
+						lifecycle.transition(PositionStage::Closing, &journal)?;
+					}
+					let pending_manual = pending_manual_orders(&manual_orders, &all_fills);
+					// a partial close done, so less leverage is needed
+					if fill.id.produced_by == MANUAL && !is_entry && !pending_manual.iter().any(|o| o.id().uuid == fill.id.uuid) {
+						// what's left of this and other engine positions on the symbol, as the exchange's may not be updated by the fill yet
+						let symbol = acquired.spec.symbol().to_string();
+						let remaining: f64 = engine.exposure.by_leg().into_iter().filter(|((s, _), _)| *s == symbol).map(|(_, qty)| qty.abs()).sum();
+						let (config, asset, remaining_usdt) = (engine.config.clone(), acquired.spec.asset.clone(), remaining * fill.last_price);
+						tokio::spawn(async move {
+							if let Err(e) = crate::margin::fit(&config, &asset, remaining_usdt).await {
+								warn!("Failed to re-adjust leverage after a partial close: {}", e);
+							}
+						});
+					}
+					// exchange's rounding of quantities makes exact equality unlikely
+					if closed_notional >= entry_notional * (1.0 - 1e-6) && !pending_manual.iter().any(|o| o.side() == &acquired.spec.side) {
+						break;
+					}
+					// sizes of all protocols follow the size of the position
+					for produced_by in all_requested.keys().cloned().collect::<Vec<_>>() {
+						update_unrolled(produced_by, controlled_notional(entry_notional, &manual_orders));
+					}
+					update_target_orders(entry_notional - closed_notional, pending_manual, last_fill, &all_fills);
+				},
+				Some((command, reply)) = rx_control.recv() => {
+					info!(position_id = %acquired.spec.id, ?command, "Received a manual command");
+					let open_notional = entry_notional - closed_notional;
+					let mut new_manual = Vec::new();
+					let answer: ControlReply = match command {
+						_ if closing_all => Err("Already closing everything".to_owned()),
+						ControlCommand::Resize { fraction } if fraction == 0.0 => Err("Resizing by 0 does nothing".to_owned()),
+						ControlCommand::Resize { fraction } if fraction > -1.0 => {
+							let side = match (fraction > 0.0, &acquired.spec.side) {
+								(true, side) => side.clone(),
+								(false, Side::Buy) => Side::Sell,
+								(false, Side::Sell) => Side::Buy,
+							};
+							let order = manual_market(&acquired.spec, side, open_notional * fraction.abs());
+							let refusal = match fraction > 0.0 {
+								true if engine.freeze.is_frozen() => Some("Engine is frozen".to_owned()),
+								true => engine.risk.check_order(&order).err().map(|e| e.to_string()),
+								false => None,
+							};
+							let refusal = match refusal {
+								None if fraction > 0.0 => match binance::futures_mark_price(&acquired.spec.asset).await {
+									Ok(mark) => crate::margin::adjust(&engine.config, &acquired.spec.asset, order.notional() * mark)
+										.await
+										.err()
+										.map(|e| format!("Could not fit leverage to the new size: {}", e)),
+									Err(e) => Some(e.to_string()),
+								},
+								refusal => refusal,
+							};
+							match refusal {
+								Some(refusal) => Err(refusal),
+								None => {
+									let answer = format!("{} {:.6} at market, {:.6} of {:.6} open", order.side(), order.notional(), open_notional, entry_notional);
+									new_manual.push(order);
+									Ok(answer)
+								}
+							}
+						}
+						ControlCommand::Resize { .. } | ControlCommand::CloseAll => {
+							closing_all = true;
+							for handle in &protocol_handles {
+								handle.abort();
+							}
+							all_requested.clear();
+							all_requested_unrolled.clear();
+							// scale-ins not yet filled are abandoned, at what they got to
+							for order in pending_manual_orders(&manual_orders, &all_fills) {
+								if order.side() == &acquired.spec.side {
+									let mut abandoned = manual_orders[&order.id().uuid].clone();
+									abandoned.cut_size(*all_fills.get(&order.id().uuid).unwrap_or(&0.0));
+									new_manual.push(abandoned);
+								}
+							}
+							let already_closing: f64 = pending_manual_orders(&manual_orders, &all_fills)
+								.iter()
+								.filter(|o| o.side() != &acquired.spec.side)
+								.map(|o| o.notional())
+								.sum();
+							let closing_side = match acquired.spec.side {
+								Side::Buy => Side::Sell,
+								Side::Sell => Side::Buy,
+							};
+							if open_notional - already_closing > 0.0 {
+								new_manual.push(manual_market(&acquired.spec, closing_side, open_notional - already_closing));
+							}
+							Ok(format!("Closing all {:.6} at market", open_notional))
+						}
+					};
+					for order in new_manual {
+						if let Err(e) = journal.append(JournalEvent::ManualOrder { order: order.clone() }) {
+							error!("Failed to journal a manual order: {}", e);
+						}
+						manual_orders.insert(order.id().uuid, order);
+					}
+					if answer.is_ok() {
+						for produced_by in all_requested.keys().cloned().collect::<Vec<_>>() {
+							update_unrolled(produced_by, controlled_notional(entry_notional, &manual_orders));
+						}
+						update_target_orders(entry_notional - closed_notional, pending_manual_orders(&manual_orders, &all_fills), last_fill, &all_fills);
+					}
+					let _ = reply.send(answer);
+				},
+				_ = stop_triggers.next_trigger() => {
+					if closing_all {
+						continue;
+					}
+					info!(position_id = %acquired.spec.id, "Synthetic stop hit, closing at market");
+					update_target_orders(entry_notional - closed_notional, pending_manual_orders(&manual_orders, &all_fills), last_fill, &all_fills);
 				},
-				Some((protocol_order_id, filled_notional)) = rx_fills.recv() => {
-					all_fills.insert(protocol_order_id.uuid, filled_notional);
-					update_unrolled(protocol_order_id.produced_by.clone());
-					update_target_orders();
+				_ = pnl_report.tick() => {
+					match binance::futures_mark_price(&acquired.spec.asset).await {
+						Ok(mark) => info!(
+							position_id = %acquired.spec.id,
+							avg_entry_price = ?pnl.avg_entry_price(),
+							realised_usdt = pnl.realised_usdt,
+							unrealised_usdt = pnl.unrealised_usdt(mark),
+							fees_usdt = pnl.fees_usdt,
+							net_unrealised_usdt = pnl.net_unrealised_usdt(mark, exit_fee_rate),
+							break_even_price = ?pnl.break_even_price(exit_fee_rate),
+							"PnL"
+						),
+						Err(e) => warn!("Failed to fetch mark price: {}", e),
+					}
 				},
 				// This happens if all channels are closed.
-				else => break,
+				else => anyhow::bail!("All protocols of the position stopped before it was closed"),
 			}
 		}
 
-		Ok(Self {
-			_acquisition: acquired,
-			protocols_spec: protocols,
-			closed_notional,
+		for handle in protocol_handles {
+			handle.abort();
+		}
+		drop(rx_control);
+		let _ = std::fs::remove_file(crate::control::socket_path(&engine.positions_dir, acquired.spec.id));
+		// whatever is left of the protocols' orders must not outlive the position
+		target_orders.update_orders(Vec::new(), last_fill, &all_fills);
+		lifecycle.transition(PositionStage::Closed, &journal)?;
+		engine.exposure.remove_position(&acquired.spec.id);
+
+		let mut acquired = acquired;
+		acquired.acquired_notional = entry_notional;
+		let closed = PositionClosed {
+			t_closed: lifecycle.entered_at(PositionStage::Closed).unwrap(),
+			pnl,
+			_followup: Self {
+				_acquisition: acquired,
+				protocols_spec: protocols,
+				closed_notional,
+			},
+			lifecycle,
+		};
+		journal.append(JournalEvent::Closed { record: closed.record() })?;
+		Ok(closed)
+	}
+}
+
+/// `produced_by` of hand-issued orders.
+pub const MANUAL: &str = "manual";
+/// `produced_by` of the order a position is entered with.
+pub const ACQUISITION: &str = "acquisition";
+
+fn manual_market(spec: &PositionSpec, side: Side, qty_notional: f64) -> ConceptualOrder {
+	ConceptualOrder::Market(ConceptualMarket {
+		id: ProtocolOrderId::new(MANUAL.to_owned(), Uuid::new_v4()),
+		maximum_slippage_percent: 1.0,
+		symbol: spec.symbol(),
+		side,
+		qty_notional,
+	})
+}
+
+/// Manual orders with what's left of them to fill.
+fn pending_manual_orders(manual_orders: &HashMap<Uuid, ConceptualOrder>, fills: &HashMap<Uuid, f64>) -> Vec<ConceptualOrder> {
+	manual_orders
+		.values()
+		.filter_map(|o| {
+			let left = o.notional() - fills.get(&o.id().uuid).unwrap_or(&0.0);
+			(left > o.notional() * 1e-6).then(|| {
+				let mut o = o.clone();
+				o.cut_size(left);
+				o
+			})
 		})
+		.collect()
+}
+
+/// Filled notional of manual scale-ins, and of everything else.
+fn split_fills(fills: &HashMap<Uuid, f64>, manual_orders: &HashMap<Uuid, ConceptualOrder>, side: &Side) -> (f64, f64) {
+	let (mut entered, mut closed) = (0.0, 0.0);
+	for (uuid, filled) in fills {
+		match manual_orders.get(uuid).map(|o| o.side() == side).unwrap_or(false) {
+			true => entered += filled,
+			false => closed += filled,
+		}
+	}
+	(entered, closed)
+}
+
+/// Distributes what is found on the exchange between the resumed positions.
+/// Any drift from the journaled exposure on a leg is split pro-rata to the journaled sizes of the positions on it. Returned values are unsigned, in the direction of each position.
+pub async fn split_exchange_exposure(config: &AppConfig, replays: &[Replayed], mode: PositionMode) -> Result<HashMap<Uuid, f64>> {
+	let exchange_positions = binance::get_futures_positions(config.binance.read_key.clone(), config.binance.read_secret.clone()).await?;
+
+	let signed = |side: &Side, notional: f64| match side {
+		Side::Buy => notional,
+		Side::Sell => -notional,
+	};
+	let journaled: Vec<(&Replayed, f64)> = replays
+		.iter()
+		.map(|r| (r, r.acquired_notional.unwrap_or(0.0) - r.closed_notional()))
+		.collect();
+
+	let mut split = HashMap::new();
+	let leg = |r: &Replayed| (r.spec.symbol().to_string(), PositionSide::of_position(mode, &r.spec.side));
+	for (replayed, notional) in &journaled {
+		let on_leg = journaled.iter().filter(|(r, _)| leg(r) == leg(replayed));
+		let journaled_signed: f64 = on_leg.clone().map(|(r, n)| signed(&r.spec.side, *n)).sum();
+		let journaled_abs: f64 = on_leg.map(|(_, n)| n.abs()).sum();
+		let drift = exchange_positions.get(&leg(replayed)).copied().unwrap_or(0.0) - journaled_signed;
+
+		let share = if journaled_abs == 0.0 { 0.0 } else { notional.abs() / journaled_abs };
+		let own_signed = signed(&replayed.spec.side, *notional) + drift * share;
+		split.insert(replayed.spec.id, signed(&replayed.spec.side, own_signed).max(0.0));
+	}
+	Ok(split)
+}
+
+impl PositionFollowup {
+	/// Picks up a position from its journal after the engine was restarted.
+	/// `exchange_notional` is this position's share of what is held on the exchange, as determined by `split_exchange_exposure`. It takes precedence over the journal; if it is zero, there is nothing left to follow and `None` is returned.
+	pub async fn resume(config: &AppConfig, replayed: Replayed, exchange_notional: f64, engine: EngineHandles) -> Result<Option<PositionClosed>> {
+		let spec = replayed.spec.clone();
+		match replayed.lifecycle.stage() {
+			PositionStage::Following | PositionStage::Closing => {}
+			PositionStage::Closed => return Ok(None),
+			stage => {
+				warn!(position_id = %spec.id, ?stage, "Position died before it was acquired; not resuming");
+				return Ok(None);
+			}
+		}
+		let journaled_acquired = match replayed.acquired_notional {
+			Some(n) => n,
+			None => {
+				warn!(position_id = %spec.id, "Position died before acquisition was recorded; not resuming");
+				return Ok(None);
+			}
+		};
+		let journaled_notional = journaled_acquired - replayed.closed_notional();
+
+		if exchange_notional == 0.0 {
+			info!(position_id = %spec.id, journaled_notional, "Nothing left on the exchange; marking the position closed");
+			let journal = Journal::open(&config.positions_dir, spec.id)?;
+			let mut lifecycle = replayed.lifecycle.clone();
+			if lifecycle.stage() == PositionStage::Following {
+				lifecycle.transition(PositionStage::Closing, &journal)?;
+			}
+			lifecycle.transition(PositionStage::Closed, &journal)?;
+			let record = ClosedRecord {
+				spec,
+				acquired_notional: journaled_acquired,
+				// whatever we did not see being closed, was closed while we were down
+				closed_notional: journaled_acquired,
+				// exits we did not see are not in here
+				pnl: replayed.pnl.clone(),
+				t_closed: lifecycle.entered_at(PositionStage::Closed).unwrap(),
+				lifecycle,
+			};
+			journal.append(JournalEvent::Closed { record })?;
+			return Ok(None);
+		}
+		if (exchange_notional - journaled_notional).abs() > journaled_notional * 0.001 {
+			warn!(
+				position_id = %spec.id,
+				journaled_notional,
+				exchange_notional,
+				"Exposure on the exchange drifted from the journal while we were down; adopting the exchange's"
+			);
+		}
+
+		let journal = Journal::open(&config.positions_dir, spec.id)?;
+		journal.append(JournalEvent::Resumed { exchange_notional })?;
+
+		let protocols = crate::protocols::interpret_followup_specs(replayed.followup_specs.clone())?;
+		for protocol in &protocols {
+			if let Some(state) = replayed.protocol_states.get(&protocol.to_string()) {
+				protocol.restore_state(state.clone())?;
+			}
+		}
+
+		crate::data_store::spawn_market_recorder(engine.data_store.clone(), spec.symbol());
+		let acquired = PositionAcquisition {
+			spec,
+			target_notional: journaled_acquired,
+			acquired_notional: exchange_notional + replayed.closed_notional(),
+			lifecycle: replayed.lifecycle.clone(),
+			pnl: replayed.pnl.clone(),
+			last_fill: None,
+			protocols_spec: None,
+		};
+		let carryover = FollowupCarryover {
+			all_requested: replayed.protocol_orders,
+			all_fills: replayed.fills,
+			manual_orders: replayed.manual_orders,
+		};
+		Self::do_followup(acquired, protocols, engine, journal, carryover).await.map(Some)
+	}
+}
+
+#[derive(Debug)]
+pub struct PositionClosed {
+	_followup: PositionFollowup,
+	lifecycle: Lifecycle,
+	pnl: PositionPnl,
+	t_closed: DateTime<Utc>,
+}
+impl PositionClosed {
+	pub fn record(&self) -> ClosedRecord {
+		let acquisition = &self._followup._acquisition;
+		ClosedRecord {
+			spec: acquisition.spec.clone(),
+			acquired_notional: acquisition.acquired_notional,
+			closed_notional: self._followup.closed_notional,
+			lifecycle: self.lifecycle.clone(),
+			pnl: self.pnl.clone(),
+			t_closed: self.t_closed,
+		}
 	}
 }
 
-//pub struct PositionClosed {
-//	_followup: PositionFollowup,
-//	t_closed: DateTime<Utc>,
-//}
+/// Final result of a position, as it is written to the journal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClosedRecord {
+	pub spec: PositionSpec,
+	pub acquired_notional: f64,
+	pub closed_notional: f64,
+	pub lifecycle: Lifecycle,
+	pub pnl: PositionPnl,
+	pub t_closed: DateTime<Utc>,
+}
@@ -2,9 +2,10 @@ mod trailing_stop;
 use crate::api::order_types::{ConceptualOrder, ConceptualOrderPercents};
 use crate::positions::PositionSpec;
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::str::FromStr;
-use std::sync::mpsc;
+use tokio::sync::mpsc;
 use tracing::error;
 pub use trailing_stop::TrailingStopWrapper;
 use uuid::Uuid;
@@ -21,9 +22,16 @@ pub enum ProtocolType {
 
 pub trait Protocol {
 	type Params;
-	fn attach(&self, tx_orders: mpsc::Sender<ProtocolOrders>, position_spec: &crate::positions::PositionSpec) -> anyhow::Result<()>;
+	/// Returns handle to the spawned task, which is aborted once the position is closed.
+	fn attach(&self, tx_orders: mpsc::UnboundedSender<ProtocolOrders>, position_spec: &crate::positions::PositionSpec) -> anyhow::Result<tokio::task::JoinHandle<()>>;
 	fn update_params(&self, params: &Self::Params) -> anyhow::Result<()>;
 	fn get_subtype(&self) -> ProtocolType;
+	/// Distance from entry to the first stop the protocol places, as a fraction of the entry price. `None` if it places no stops.
+	fn initial_stop_distance(&self) -> Option<f64>;
+	/// Snapshot of whatever the protocol accumulated while running, for it to be journaled. `None` if there is nothing to carry over.
+	fn internal_state(&self) -> Option<serde_json::Value>;
+	/// Must be called before `attach`, for the protocol to continue from the provided state instead of starting anew.
+	fn restore_state(&self, state: serde_json::Value) -> anyhow::Result<()>;
 }
 
 /// possibly will implement Iterator on this, because all additional methods seem to want it.
@@ -42,7 +50,7 @@ impl FollowupProtocols {
 		different_types
 	}
 
-	pub fn attach_all(&self, tx_orders: mpsc::Sender<ProtocolOrders>, spec: &PositionSpec) -> anyhow::Result<()> {
+	pub fn attach_all(&self, tx_orders: mpsc::UnboundedSender<ProtocolOrders>, spec: &PositionSpec) -> anyhow::Result<()> {
 		for ts in &self.trailing_stop {
 			ts.attach(tx_orders.clone(), spec)?;
 		}
@@ -64,8 +72,15 @@ impl FollowupProtocol {
 		}
 	}
 }
+impl std::fmt::Display for FollowupProtocol {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			FollowupProtocol::TrailingStop(ts) => write!(f, "{}", ts),
+		}
+	}
+}
 impl FollowupProtocol {
-	pub fn attach(&self, tx_orders: mpsc::Sender<ProtocolOrders>, position_spec: &crate::positions::PositionSpec) -> anyhow::Result<()> {
+	pub fn attach(&self, tx_orders: mpsc::UnboundedSender<ProtocolOrders>, position_spec: &crate::positions::PositionSpec) -> anyhow::Result<tokio::task::JoinHandle<()>> {
 		match self {
 			FollowupProtocol::TrailingStop(ts) => ts.attach(tx_orders, position_spec),
 		}
@@ -82,6 +97,24 @@ impl FollowupProtocol {
 			FollowupProtocol::TrailingStop(ts) => ts.get_subtype(),
 		}
 	}
+
+	pub fn initial_stop_distance(&self) -> Option<f64> {
+		match self {
+			FollowupProtocol::TrailingStop(ts) => ts.initial_stop_distance(),
+		}
+	}
+
+	pub fn internal_state(&self) -> Option<serde_json::Value> {
+		match self {
+			FollowupProtocol::TrailingStop(ts) => ts.internal_state(),
+		}
+	}
+
+	pub fn restore_state(&self, state: serde_json::Value) -> anyhow::Result<()> {
+		match self {
+			FollowupProtocol::TrailingStop(ts) => ts.restore_state(state),
+		}
+	}
 }
 
 pub fn interpret_followup_specs(protocol_specs: Vec<String>) -> Result<Vec<FollowupProtocol>> {
@@ -96,7 +129,7 @@ pub fn interpret_followup_specs(protocol_specs: Vec<String>) -> Result<Vec<Follo
 
 /// Wrapper around Orders, which allows for updating the target after a partial fill, without making a new request to the protocol.
 ///NB: the protocol itself must internally uphold the equality of ids attached to orders to corresponding fields of ProtocolOrders, as well as to ensure that all possible orders the protocol can ether request are initialized in every ProtocolOrders instance it outputs.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProtocolOrders {
 	pub produced_by: String,
 	fields: HashMap<Uuid, Option<ConceptualOrderPercents>>,
@@ -108,8 +141,8 @@ impl ProtocolOrders {
 
 	pub fn empty_mask(&self) -> HashMap<Uuid, f64> {
 		let mut mask = HashMap::new();
-		for (key, _value) in self.fields {
-			mask.insert(key, 0.0);
+		for key in self.fields.keys() {
+			mask.insert(*key, 0.0);
 		}
 		mask
 	}
@@ -125,11 +158,11 @@ impl ProtocolOrders {
 					let filled = *filled_mask.get(uuid).unwrap_or(&0.0);
 
 					if filled > exact_order.notional() * 0.99 {
-						total_offset += filled - exact_order.notional();
+						total_offset += (filled - exact_order.notional()).max(0.0);
 						return None;
 					}
 
-					exact_order.cut_size(filled);
+					exact_order.cut_size(exact_order.notional() - filled);
 					Some(exact_order)
 				} else {
 					None
@@ -137,28 +170,25 @@ impl ProtocolOrders {
 			})
 			.collect();
 
+		// Overfilled orders eat into the rest, evenly; orders smaller than their share are dropped altogether.
 		orders.sort_by(|a, b| b.notional().partial_cmp(&a.notional()).unwrap_or(std::cmp::Ordering::Equal));
-		let mut l = orders.len();
-		for i in (0..l).rev() {
-			if orders[i].notional() < total_offset / l as f64 {
-				orders.remove(i);
-				total_offset -= orders[i].notional();
-				l -= 1;
+		while total_offset > 0.0 && !orders.is_empty() {
+			let share = total_offset / orders.len() as f64;
+			let smallest = orders.last().unwrap().notional();
+			if smallest <= share {
+				total_offset -= smallest;
+				orders.pop();
 			} else {
-				// if reached this once, all following elements will also eval to true, so the total_offset is constant now.
-				orders[i].cut_size(total_offset);
+				for order in orders.iter_mut() {
+					order.cut_size(order.notional() - share);
+				}
+				total_offset = 0.0;
 			}
 		}
-		if orders.len() == 0 {
+		if total_offset > 0.0 {
 			error!("Missed by {total_offset}");
 		}
 
 		orders
 	}
 }
-
-#[derive(Debug, Hash, Clone)]
-pub struct ProtocolOrderId {
-	produced_by: String,
-	uuid: Uuid,
-}
@@ -7,18 +7,24 @@ use crate::positions::PositionSpec;
 use crate::protocols::{Protocol, ProtocolOrders, ProtocolType};
 use anyhow::Result;
 use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{collections::HashMap, str::FromStr};
+use tokio::sync::mpsc;
 use tokio_tungstenite::connect_async;
+use tracing::warn;
 use uuid::Uuid;
 use v_utils::macros::CompactFormat;
 use v_utils::trades::Side;
 
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
 #[derive(Debug, Clone)]
 pub struct TrailingStopWrapper {
 	params: Arc<Mutex<TrailingStop>>,
+	state: Arc<Mutex<Option<TrailingStopState>>>,
 }
 impl FromStr for TrailingStopWrapper {
 	type Err = anyhow::Error;
@@ -27,15 +33,30 @@ impl FromStr for TrailingStopWrapper {
 		let ts = TrailingStop::from_str(&spec)?;
 		Ok(Self {
 			params: Arc::new(Mutex::new(ts)),
+			state: Arc::new(Mutex::new(None)),
 		})
 	}
 }
 
+/// Everything needed to pick up where we left off after a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrailingStopState {
+	stop_market_uuid: Uuid,
+	top: f64,
+	bottom: f64,
+}
+
+impl std::fmt::Display for TrailingStopWrapper {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", self.params.lock().unwrap())
+	}
+}
+
 impl Protocol for TrailingStopWrapper {
 	type Params = TrailingStop;
 
 	/// Requested orders are being sent over the mspc with uuid of the protocol on each batch, as we want to replace the previous requested batch if any.
-	fn attach(&self, tx_orders: mpsc::Sender<ProtocolOrders>, position_spec: &PositionSpec) -> Result<()> {
+	fn attach(&self, tx_orders: mpsc::UnboundedSender<ProtocolOrders>, position_spec: &PositionSpec) -> Result<tokio::task::JoinHandle<()>> {
 		let symbol = Symbol {
 			base: position_spec.asset.clone(),
 			quote: "USDT".to_owned(),
@@ -44,11 +65,13 @@ impl Protocol for TrailingStopWrapper {
 		let address = format!("wss://fstream.binance.com/ws/{}@aggTrade", symbol.to_string().to_lowercase());
 
 		let params = self.params.clone();
+		let state = self.state.clone();
+		let restored = state.lock().unwrap().clone();
 		let position_spec = position_spec.clone();
 
 		// a thing that uniquely marks all the semantic orders of the grid the protocol may want to place.
 		let mut order_mask: HashMap<Uuid, Option<ConceptualOrderPercents>> = HashMap::new();
-		let stop_market_uuid = Uuid::new_v4();
+		let stop_market_uuid = restored.as_ref().map(|s| s.stop_market_uuid).unwrap_or_else(Uuid::new_v4);
 		order_mask.insert(stop_market_uuid.clone(), None);
 
 		macro_rules! send_orders {
@@ -72,24 +95,68 @@ impl Protocol for TrailingStopWrapper {
 			}};
 		}
 
-		tokio::spawn(async move {
-			let price = binance::futures_price(&symbol.base).await.unwrap();
-			let mut top: f64 = price;
-			let mut bottom: f64 = price;
+		let handle = tokio::spawn(async move {
 			let side = position_spec.side.clone();
+			let (mut top, mut bottom) = match &restored {
+				Some(s) => {
+					// the previous run could have died before the target was synchronized, so request it again right away.
+					match side {
+						Side::Buy => send_orders!(s.top - s.top * params.lock().unwrap().percent.abs(), Side::Sell),
+						Side::Sell => send_orders!(s.bottom + s.bottom * params.lock().unwrap().percent.abs(), Side::Buy),
+					}
+					(s.top, s.bottom)
+				}
+				None => {
+					let mut backoff = Duration::from_secs(1);
+					let price = loop {
+						match binance::futures_price(&symbol.base).await {
+							Ok(price) => break price,
+							Err(e) => {
+								warn!(%symbol, "Trailing stop failed to fetch the initial price, retrying in {:?}: {}", backoff, e);
+								tokio::time::sleep(backoff).await;
+								backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+							}
+						}
+					};
+					(price, price)
+				}
+			};
+			let save_state = |top: f64, bottom: f64| {
+				*state.lock().unwrap() = Some(TrailingStopState { stop_market_uuid, top, bottom });
+			};
+			save_state(top, bottom);
 
 			let url = url::Url::parse(&address).unwrap();
-			let (ws_stream, _) = connect_async(url).await.expect("Failed to connect");
-			let (_, mut read) = ws_stream.split();
-
-			while let Some(msg) = read.next().await {
-				let data = msg.unwrap().into_data();
-				match serde_json::from_slice::<Value>(&data) {
-					Ok(json) => {
-						if let Some(price_str) = json.get("p") {
-							let price: f64 = price_str.as_str().unwrap().parse().unwrap();
+			let mut backoff = Duration::from_secs(1);
+			loop {
+				let (ws_stream, _) = match connect_async(url.clone()).await {
+					Ok(s) => s,
+					Err(e) => {
+						warn!(%symbol, "Trailing stop failed to connect to the price feed, retrying in {:?}: {}", backoff, e);
+						tokio::time::sleep(backoff).await;
+						backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+						continue;
+					}
+				};
+				backoff = Duration::from_secs(1);
+				let (_, mut read) = ws_stream.split();
+
+				while let Some(msg) = read.next().await {
+					let data = match msg {
+						Ok(msg) => msg.into_data(),
+						Err(e) => {
+							warn!(%symbol, "Trailing stop lost the price feed, reconnecting: {}", e);
+							break;
+						}
+					};
+					match serde_json::from_slice::<Value>(&data) {
+						Ok(json) => {
+							let Some(price) = json.get("p").and_then(|p| p.as_str()).and_then(|p| p.parse::<f64>().ok()) else {
+								continue;
+							};
 							if price < bottom {
 								bottom = price;
+								save_state(top, bottom);
 								match side {
 									Side::Buy => {}
 									Side::Sell => {
@@ -100,24 +167,25 @@ impl Protocol for TrailingStopWrapper {
 							}
 							if price > top {
 								top = price;
+								save_state(top, bottom);
 								match side {
 									Side::Buy => {
 										let target_price = price - price * params.lock().unwrap().percent.abs();
-										send_orders!(target_price, Side::Buy);
+										send_orders!(target_price, Side::Sell);
 									}
 									Side::Sell => {}
 								}
 							}
 						}
-					}
-					Err(e) => {
-						println!("Failed to parse message as JSON: {}", e);
+						Err(e) => {
+							warn!("Failed to parse message as JSON: {}", e);
+						}
 					}
 				}
 			}
 		});
 
-		Ok(())
+		Ok(handle)
 	}
 
 	fn update_params(&self, params: &TrailingStop) -> Result<()> {
@@ -127,6 +195,21 @@ impl Protocol for TrailingStopWrapper {
 	fn get_subtype(&self) -> ProtocolType {
 		ProtocolType::Momentum
 	}
+
+	/// Starts trailing from the entry.
+	fn initial_stop_distance(&self) -> Option<f64> {
+		Some(self.params.lock().unwrap().percent.abs())
+	}
+
+	fn internal_state(&self) -> Option<Value> {
+		self.state.lock().unwrap().as_ref().map(|s| serde_json::to_value(s).unwrap())
+	}
+
+	fn restore_state(&self, state: Value) -> Result<()> {
+		let state: TrailingStopState = serde_json::from_value(state)?;
+		*self.state.lock().unwrap() = Some(state);
+		Ok(())
+	}
 }
 
 #[derive(Debug, Clone, CompactFormat)]
@@ -0,0 +1,479 @@
+use crate::api::binance::{self, FuturesPositionResponse, PositionSide};
+use crate::config::AppConfig;
+use anyhow::Result;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Held on one leg by a single position.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Holding {
+	symbol: String,
+	position_side: PositionSide,
+	/// In base asset, negative for shorts, same as `positionAmt` on binance.
+	signed_notional: f64,
+}
+
+/// What the engine believes it holds on the exchange. Positions report into it, reconciler checks it against the exchange.
+/// Every process running positions out of the same `positions_dir` shares its part under `<positions_dir>/exposure/`, so that each sees what all of them hold:
+/// a file per position, kept until the position is removed, and a file of the orders of each process.
+#[derive(Debug, Clone, Default)]
+pub struct ExposureBook {
+	positions: Arc<Mutex<HashMap<Uuid, Holding>>>,
+	orders: Arc<Mutex<HashSet<i64>>>,
+	/// `None` keeps it to this process.
+	shared: Option<Arc<SharedExposure>>,
+}
+impl ExposureBook {
+	pub fn new(positions_dir: &Path) -> Result<Self> {
+		let dir = positions_dir.join("exposure");
+		std::fs::create_dir_all(&dir)?;
+		let orders_path = dir.join(format!("orders-{}.json", Uuid::new_v4()));
+		Ok(Self {
+			shared: Some(Arc::new(SharedExposure { dir, orders_path })),
+			..Default::default()
+		})
+	}
+
+	/// `signed_notional` is in base asset, negative for shorts, same as `positionAmt` on binance.
+	pub fn set_position(&self, position_id: Uuid, symbol: String, position_side: PositionSide, signed_notional: f64) {
+		let holding = Holding {
+			symbol,
+			position_side,
+			signed_notional,
+		};
+		if let Some(shared) = &self.shared {
+			if let Err(e) = shared.write(&shared.position_path(position_id), &holding) {
+				warn!(%position_id, "Failed to share the exposure of the position: {}", e);
+			}
+		}
+		self.positions.lock().unwrap().insert(position_id, holding);
+	}
+
+	pub fn remove_position(&self, position_id: &Uuid) {
+		if let Some(shared) = &self.shared {
+			let _ = std::fs::remove_file(shared.position_path(*position_id));
+		}
+		self.positions.lock().unwrap().remove(position_id);
+	}
+
+	pub fn register_order(&self, order_id: i64) {
+		let mut orders = self.orders.lock().unwrap();
+		orders.insert(order_id);
+		self.share_orders(&orders);
+	}
+
+	pub fn forget_order(&self, order_id: &i64) {
+		let mut orders = self.orders.lock().unwrap();
+		orders.remove(order_id);
+		self.share_orders(&orders);
+	}
+
+	fn share_orders(&self, orders: &HashSet<i64>) {
+		if let Some(shared) = &self.shared {
+			if let Err(e) = shared.write(&shared.orders_path, orders) {
+				warn!("Failed to share the orders of the process: {}", e);
+			}
+		}
+	}
+
+	/// Of all processes; this one's own part as it is in memory.
+	fn holdings(&self) -> Vec<Holding> {
+		let own = self.positions.lock().unwrap().clone();
+		let mut holdings: HashMap<Uuid, Holding> = match &self.shared {
+			Some(shared) => shared.positions(),
+			None => HashMap::new(),
+		};
+		holdings.extend(own);
+		holdings.into_values().collect()
+	}
+
+	/// Net of all legs.
+	pub fn by_symbol(&self) -> HashMap<String, f64> {
+		let mut map = HashMap::new();
+		for holding in self.holdings() {
+			*map.entry(holding.symbol).or_insert(0.0) += holding.signed_notional;
+		}
+		map
+	}
+
+	/// Same keys as `binance::get_futures_positions`.
+	pub fn by_leg(&self) -> HashMap<(String, PositionSide), f64> {
+		let mut map = HashMap::new();
+		for holding in self.holdings() {
+			*map.entry((holding.symbol, holding.position_side)).or_insert(0.0) += holding.signed_notional;
+		}
+		map
+	}
+
+	/// Signed notionals of the positions, of any process, holding something on the leg.
+	pub fn on_leg(&self, symbol: &str, position_side: PositionSide) -> Vec<f64> {
+		self.holdings()
+			.into_iter()
+			.filter(|h| h.symbol == symbol && h.position_side == position_side && h.signed_notional != 0.0)
+			.map(|h| h.signed_notional)
+			.collect()
+	}
+
+	/// Placed by any of the processes.
+	pub fn orders(&self) -> HashSet<i64> {
+		let mut orders = self.orders.lock().unwrap().clone();
+		if let Some(shared) = &self.shared {
+			orders.extend(shared.orders());
+		}
+		orders
+	}
+}
+
+/// Files of `ExposureBook`. The file of the process' orders goes with it; ones of positions stay for as long as they are open, so that they are still accounted for if the process dies.
+#[derive(Debug)]
+struct SharedExposure {
+	dir: PathBuf,
+	orders_path: PathBuf,
+}
+impl SharedExposure {
+	fn position_path(&self, position_id: Uuid) -> PathBuf {
+		self.dir.join(format!("{position_id}.json"))
+	}
+
+	/// Through a rename, so that readers never see half of it.
+	fn write(&self, path: &Path, value: &impl Serialize) -> Result<()> {
+		let tmp = path.with_extension("tmp");
+		std::fs::write(&tmp, serde_json::to_vec(value)?)?;
+		std::fs::rename(&tmp, path)?;
+		Ok(())
+	}
+
+	fn files(&self, of_orders: bool) -> Vec<PathBuf> {
+		let Ok(entries) = std::fs::read_dir(&self.dir) else { return Vec::new() };
+		entries
+			.filter_map(|e| e.ok().map(|e| e.path()))
+			.filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+			.filter(|p| p.file_name().unwrap().to_string_lossy().starts_with("orders-") == of_orders)
+			.collect()
+	}
+
+	fn positions(&self) -> HashMap<Uuid, Holding> {
+		self.files(false)
+			.into_iter()
+			.filter_map(|path| {
+				let position_id = path.file_stem()?.to_str()?.parse().ok()?;
+				let holding = serde_json::from_slice(&std::fs::read(&path).ok()?).ok()?;
+				Some((position_id, holding))
+			})
+			.collect()
+	}
+
+	fn orders(&self) -> HashSet<i64> {
+		self.files(true)
+			.into_iter()
+			.filter_map(|path| serde_json::from_slice::<HashSet<i64>>(&std::fs::read(path).ok()?).ok())
+			.flatten()
+			.collect()
+	}
+}
+impl Drop for SharedExposure {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_file(&self.orders_path);
+	}
+}
+
+/// While frozen, nothing that would increase exposure is allowed through. Backed by a file in `positions_dir`, so that it survives restarts and is shared by all running instances.
+#[derive(Debug, Clone)]
+pub struct Freeze {
+	path: PathBuf,
+}
+impl Freeze {
+	pub fn new(positions_dir: &Path) -> Self {
+		Self {
+			path: positions_dir.join("FROZEN"),
+		}
+	}
+
+	pub fn is_frozen(&self) -> bool {
+		self.path.exists()
+	}
+
+	pub fn reason(&self) -> Option<String> {
+		std::fs::read_to_string(&self.path).ok()
+	}
+
+	/// Appends the reason if already frozen, so that nothing is lost until a human looks at it.
+	pub fn freeze(&self, reason: &str) -> Result<()> {
+		use std::io::Write;
+		let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+		writeln!(file, "{} {}", Utc::now().to_rfc3339(), reason)?;
+		Ok(())
+	}
+
+	pub fn unfreeze(&self) -> Result<()> {
+		match std::fs::remove_file(&self.path) {
+			Ok(()) => Ok(()),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+			Err(e) => Err(e.into()),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DriftPolicy {
+	/// Accept the exchange's state as the new baseline; the differing exposure is considered not ours.
+	Adopt,
+	/// Stop all exposure-increasing actions until a human runs `unfreeze`, at which point the drift is adopted.
+	Freeze,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ReconciliationConfig {
+	pub interval_s: u64,
+	pub on_drift: DriftPolicy,
+}
+impl Default for ReconciliationConfig {
+	fn default() -> Self {
+		Self {
+			interval_s: 30,
+			on_drift: DriftPolicy::Freeze,
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub enum Drift {
+	Position {
+		symbol: String,
+		position_side: PositionSide,
+		expected: f64,
+		actual: f64,
+	},
+	UnknownOrder { symbol: String, order_id: i64, side: String, qty: String },
+}
+
+/// Periodically compares the `ExposureBook` with the exchange, for as long as the process lives.
+pub fn spawn(config: AppConfig, exposure: ExposureBook, freeze: Freeze) -> tokio::task::JoinHandle<()> {
+	tokio::spawn(async move {
+		let mut reconciler = Reconciler::new(config.reconciliation.on_drift.clone(), exposure, freeze);
+		let mut interval = tokio::time::interval(Duration::from_secs(config.reconciliation.interval_s));
+		loop {
+			interval.tick().await;
+			if let Err(e) = reconciler.reconcile(&config).await {
+				warn!("Reconciliation failed: {}", e);
+			}
+		}
+	})
+}
+
+struct Reconciler {
+	policy: DriftPolicy,
+	exposure: ExposureBook,
+	freeze: Freeze,
+	/// Exposure that was found on the exchange and accepted as not being ours.
+	adopted_positions: HashMap<(String, PositionSide), f64>,
+	adopted_orders: HashSet<i64>,
+	/// Unknown on the last pass. An order can show up on the exchange before its placement returns, so it only drifted if it still is on the next one.
+	unknown_orders: HashSet<i64>,
+	/// Off on the last pass. Same as for orders, a fill reaches the exchange before it reaches us.
+	mismatched_legs: HashSet<(String, PositionSide)>,
+	was_frozen: bool,
+}
+impl Reconciler {
+	fn new(policy: DriftPolicy, exposure: ExposureBook, freeze: Freeze) -> Self {
+		Self {
+			policy,
+			exposure,
+			freeze,
+			adopted_positions: HashMap::new(),
+			adopted_orders: HashSet::new(),
+			unknown_orders: HashSet::new(),
+			mismatched_legs: HashSet::new(),
+			was_frozen: false,
+		}
+	}
+
+	async fn reconcile(&mut self, config: &AppConfig) -> Result<()> {
+		let (key, secret) = (config.binance.read_key.clone(), config.binance.read_secret.clone());
+		let (actual_positions, open_orders) = tokio::try_join!(
+			binance::get_futures_positions(key.clone(), secret.clone()),
+			binance::get_futures_open_orders(key, secret),
+		)?;
+
+		let drifts = self.drifts(&actual_positions, open_orders);
+
+		// a human lifting the freeze is them accepting whatever the exchange has at that point.
+		let is_frozen = self.freeze.is_frozen();
+		let manually_resolved = self.was_frozen && !is_frozen;
+		if !is_frozen && !manually_resolved && !drifts.is_empty() && matches!(self.policy, DriftPolicy::Freeze) {
+			info!("Freezing all exposure-increasing actions until `unfreeze` is ran");
+		}
+
+		for drift in drifts {
+			warn!(?drift, policy = ?self.policy, manually_resolved, "Exchange drifted from what the engine believes");
+			match (&self.policy, manually_resolved) {
+				(DriftPolicy::Adopt, _) | (DriftPolicy::Freeze, true) => match drift {
+					Drift::Position {
+						symbol,
+						position_side,
+						expected,
+						actual,
+					} => {
+						*self.adopted_positions.entry((symbol, position_side)).or_insert(0.0) += actual - expected;
+					}
+					Drift::UnknownOrder { order_id, .. } => {
+						self.adopted_orders.insert(order_id);
+					}
+				},
+				(DriftPolicy::Freeze, false) =>
+					if !is_frozen {
+						self.freeze.freeze(&format!("{:?}", drift))?;
+					},
+			}
+		}
+		self.was_frozen = self.freeze.is_frozen();
+		Ok(())
+	}
+
+	fn drifts(&mut self, actual_positions: &HashMap<(String, PositionSide), f64>, open_orders: Vec<FuturesPositionResponse>) -> Vec<Drift> {
+		let mut drifts = Vec::new();
+		let believed = self.exposure.by_leg();
+		let legs: HashSet<&(String, PositionSide)> = believed.keys().chain(actual_positions.keys()).chain(self.adopted_positions.keys()).collect();
+		let mut mismatched_legs = HashSet::new();
+		for leg in legs {
+			let expected = believed.get(leg).unwrap_or(&0.0) + self.adopted_positions.get(leg).unwrap_or(&0.0);
+			let actual = *actual_positions.get(leg).unwrap_or(&0.0);
+			if (actual - expected).abs() <= 1e-9_f64.max(expected.abs() * 1e-6) {
+				continue;
+			}
+			mismatched_legs.insert(leg.clone());
+			if self.mismatched_legs.contains(leg) {
+				drifts.push(Drift::Position {
+					symbol: leg.0.clone(),
+					position_side: leg.1,
+					expected,
+					actual,
+				});
+			}
+		}
+		self.mismatched_legs = mismatched_legs;
+
+		let known_orders = self.exposure.orders();
+		let mut unknown_orders = HashSet::new();
+		for order in open_orders {
+			if known_orders.contains(&order.orderId) || self.adopted_orders.contains(&order.orderId) {
+				continue;
+			}
+			unknown_orders.insert(order.orderId);
+			if self.unknown_orders.contains(&order.orderId) {
+				drifts.push(Drift::UnknownOrder {
+					symbol: order.symbol,
+					order_id: order.orderId,
+					side: order.side,
+					qty: order.origQty,
+				});
+			}
+		}
+		self.unknown_orders = unknown_orders;
+		drifts
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn open_order(order_id: i64) -> FuturesPositionResponse {
+		serde_json::from_value(serde_json::json!({
+			"clientOrderId": "x",
+			"cumQty": "0",
+			"cumQuote": "0",
+			"executedQty": "0",
+			"orderId": order_id,
+			"avgPrice": "0.00000",
+			"origQty": "0.010",
+			"price": "0",
+			"reduceOnly": true,
+			"side": "SELL",
+			"positionSide": "BOTH",
+			"status": "NEW",
+			"stopPrice": "60000",
+			"closePosition": false,
+			"symbol": "BTCUSDT",
+			"timeInForce": "GTC",
+			"type": "STOP_MARKET",
+			"origType": "STOP_MARKET",
+			"updateTime": 1700000000000_i64,
+			"workingType": "CONTRACT_PRICE",
+			"priceProtect": false,
+			"priceMatch": "NONE",
+			"selfTradePreventionMode": "NONE"
+		}))
+		.unwrap()
+	}
+
+	fn reconciler(exposure: ExposureBook) -> Reconciler {
+		Reconciler::new(DriftPolicy::Freeze, exposure, Freeze::new(&std::env::temp_dir()))
+	}
+
+	#[test]
+	fn registered_orders_are_not_drift() {
+		let exposure = ExposureBook::default();
+		let mut reconciler = reconciler(exposure.clone());
+		exposure.register_order(1);
+		for _ in 0..2 {
+			assert!(reconciler.drifts(&HashMap::new(), vec![open_order(1)]).is_empty());
+		}
+	}
+
+	#[test]
+	fn positions_drift_once_off_twice() {
+		let exposure = ExposureBook::default();
+		let mut reconciler = reconciler(exposure.clone());
+		let leg = ("BTCUSDT".to_owned(), PositionSide::Both);
+		exposure.set_position(Uuid::new_v4(), leg.0.clone(), leg.1, 0.01);
+
+		// fill that has not reached us yet
+		let actual = HashMap::from([(leg.clone(), 0.02)]);
+		assert!(reconciler.drifts(&actual, Vec::new()).is_empty());
+		assert!(reconciler.drifts(&HashMap::from([(leg.clone(), 0.01)]), Vec::new()).is_empty());
+
+		assert!(reconciler.drifts(&actual, Vec::new()).is_empty());
+		let drifts = reconciler.drifts(&actual, Vec::new());
+		assert!(matches!(drifts.as_slice(), [Drift::Position { expected, actual, .. }] if *expected == 0.01 && *actual == 0.02));
+	}
+
+	#[test]
+	fn exposure_is_shared_between_processes() {
+		let dir = std::env::temp_dir().join(format!("discretionary_engine_exposure_{}", Uuid::new_v4()));
+		let (ours, theirs) = (ExposureBook::new(&dir).unwrap(), ExposureBook::new(&dir).unwrap());
+		let (position_id, leg) = (Uuid::new_v4(), ("BTCUSDT".to_owned(), PositionSide::Both));
+		theirs.set_position(position_id, leg.0.clone(), leg.1, -0.01);
+		theirs.register_order(1);
+		ours.set_position(Uuid::new_v4(), leg.0.clone(), leg.1, 0.03);
+
+		assert_eq!(ours.by_leg().get(&leg), Some(&0.02));
+		assert!(ours.orders().contains(&1));
+		let mut reconciler = reconciler(ours.clone());
+		for _ in 0..2 {
+			assert!(reconciler.drifts(&HashMap::from([(leg.clone(), 0.02)]), vec![open_order(1)]).is_empty());
+		}
+
+		theirs.remove_position(&position_id);
+		drop(theirs);
+		assert_eq!(ours.by_leg().get(&leg), Some(&0.03));
+		assert!(!ours.orders().contains(&1));
+		let _ = std::fs::remove_dir_all(&dir);
+	}
+
+	#[test]
+	fn unknown_orders_drift_once_seen_twice() {
+		let mut reconciler = reconciler(ExposureBook::default());
+		assert!(reconciler.drifts(&HashMap::new(), vec![open_order(1)]).is_empty());
+		let drifts = reconciler.drifts(&HashMap::new(), vec![open_order(1)]);
+		assert!(matches!(drifts.as_slice(), [Drift::UnknownOrder { order_id: 1, .. }]));
+	}
+}
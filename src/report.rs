@@ -0,0 +1,219 @@
+use crate::data_store::DataStore;
+use crate::journal::{Journal, JournalEvent};
+use crate::positions::{ClosedRecord, PositionStage};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::path::Path;
+use v_utils::trades::Side;
+
+#[derive(Clone, Debug, clap::ValueEnum)]
+pub enum ReportFormat {
+	Csv,
+	Json,
+}
+
+/// One row of the trade journal. Flat, so that it maps onto csv as is.
+#[derive(Debug, Clone, Serialize)]
+pub struct TradeRow {
+	pub position_id: String,
+	pub symbol: String,
+	pub side: String,
+	pub size_notional: f64,
+	pub entry_time: Option<DateTime<Utc>>,
+	pub entry_price: Option<f64>,
+	pub exit_time: DateTime<Utc>,
+	pub exit_price: Option<f64>,
+	/// `;`-separated
+	pub protocols: String,
+	/// Protocol that closed the biggest part of the position, or `external` if most of it was closed outside of the engine.
+	pub exit_reason: String,
+	pub realised_usdt: f64,
+	pub fees_usdt: f64,
+	pub net_usdt: f64,
+	/// Max adverse excursion, as a fraction of the entry price. Only available if trades over the period were recorded.
+	pub mae: Option<f64>,
+	/// Max favourable excursion, as a fraction of the entry price.
+	pub mfe: Option<f64>,
+}
+
+/// Every position in `positions_dir` that has reached `Closed`, with the protocols it was followed by.
+pub fn closed_positions(positions_dir: &Path) -> Result<Vec<(ClosedRecord, Vec<String>)>> {
+	let mut closed = Vec::new();
+	for entry in std::fs::read_dir(positions_dir)? {
+		let path = entry?.path();
+		if path.extension().map(|e| e != "jsonl").unwrap_or(true) {
+			continue;
+		}
+		let entries = Journal::read(&path)?;
+		let protocols = entries
+			.iter()
+			.find_map(|e| match &e.event {
+				JournalEvent::Protocols { followup } => Some(followup.clone()),
+				_ => None,
+			})
+			.unwrap_or_default();
+		if let Some(record) = entries.into_iter().rev().find_map(|e| match e.event {
+			JournalEvent::Closed { record } => Some(record),
+			_ => None,
+		}) {
+			closed.push((record, protocols));
+		}
+	}
+	closed.sort_by_key(|(r, _)| r.t_closed);
+	Ok(closed)
+}
+
+pub fn trade_row(record: &ClosedRecord, protocols: &[String], data_store: &DataStore) -> Result<TradeRow> {
+	let pnl = &record.pnl;
+	let entry_time = record.lifecycle.entered_at(PositionStage::Following);
+	let entry_price = pnl.avg_entry_price();
+	let exit_value: f64 = pnl.by_protocol.values().map(|p| p.exit_value_usdt).sum();
+	let exit_price = (pnl.closed_notional > 0.0).then(|| exit_value / pnl.closed_notional);
+
+	let unseen_closed = record.acquired_notional - pnl.closed_notional;
+	let exit_reason = match pnl.by_protocol.iter().max_by(|a, b| a.1.closed_notional.total_cmp(&b.1.closed_notional)) {
+		Some((produced_by, p)) if p.closed_notional >= unseen_closed => produced_by.clone(),
+		_ => "external".to_owned(),
+	};
+
+	let (mut mae, mut mfe) = (None, None);
+	if let (Some(entry_time), Some(entry_price)) = (entry_time, entry_price) {
+		if let Some((low, high)) = data_store.trade_price_range(&record.spec.symbol(), entry_time.timestamp_millis(), record.t_closed.timestamp_millis())? {
+			let (adverse, favourable) = match record.spec.side {
+				Side::Buy => (entry_price - low, high - entry_price),
+				Side::Sell => (high - entry_price, entry_price - low),
+			};
+			mae = Some(adverse.max(0.0) / entry_price);
+			mfe = Some(favourable.max(0.0) / entry_price);
+		}
+	}
+
+	Ok(TradeRow {
+		position_id: record.spec.id.to_string(),
+		symbol: record.spec.symbol().to_string(),
+		side: record.spec.side.to_string(),
+		size_notional: record.acquired_notional,
+		entry_time,
+		entry_price,
+		exit_time: record.t_closed,
+		exit_price,
+		protocols: protocols.join(";"),
+		exit_reason,
+		realised_usdt: pnl.realised_usdt,
+		fees_usdt: pnl.fees_usdt,
+		net_usdt: pnl.net_realised_usdt(),
+		mae,
+		mfe,
+	})
+}
+
+pub fn write_report(rows: &[TradeRow], format: ReportFormat, out: impl std::io::Write) -> Result<()> {
+	match format {
+		ReportFormat::Csv => {
+			let mut writer = csv::Writer::from_writer(out);
+			for row in rows {
+				writer.serialize(row)?;
+			}
+			writer.flush()?;
+		}
+		ReportFormat::Json => serde_json::to_writer_pretty(out, rows)?,
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::data_store::TradeRecord;
+	use crate::pnl::PositionPnl;
+	use crate::positions::{Lifecycle, PositionSpec};
+	use crate::stops::StopMode;
+	use chrono::TimeZone;
+	use uuid::Uuid;
+
+	const T0: i64 = 1_700_000_000_000;
+
+	fn t(ms: i64) -> DateTime<Utc> {
+		Utc.timestamp_millis_opt(T0 + ms).unwrap()
+	}
+
+	/// Entered 2.0 at 100 at `t(1000)`, closed at `t(5000)` by `exits` of `(produced_by, qty, price)`.
+	fn record(side: Side, exits: &[(&str, f64, f64)]) -> ClosedRecord {
+		let mut pnl = PositionPnl::new(side.clone());
+		pnl.record_entry(2.0, 100.0, 0.1);
+		for (produced_by, qty, price) in exits {
+			pnl.record_exit(produced_by, *qty, *price, 0.1);
+		}
+		ClosedRecord {
+			spec: PositionSpec::new("BTC".to_owned(), side, 200.0, StopMode::Native),
+			acquired_notional: 2.0,
+			closed_notional: 2.0,
+			lifecycle: Lifecycle::from_transitions(vec![(PositionStage::Pending, t(0)), (PositionStage::Following, t(1000)), (PositionStage::Closed, t(5000))]),
+			pnl,
+			t_closed: t(5000),
+		}
+	}
+
+	fn data_store() -> DataStore {
+		DataStore::new(&std::env::temp_dir().join(format!("discretionary_engine_report_{}", Uuid::new_v4()))).unwrap()
+	}
+
+	#[test]
+	fn exit_is_put_on_the_protocol_that_closed_the_most() {
+		let store = data_store();
+		let row = trade_row(&record(Side::Buy, &[("ts:p1", 0.5, 110.0), ("sl:p1", 1.5, 95.0)]), &[], &store).unwrap();
+		assert_eq!(row.exit_reason, "sl:p1");
+		assert_eq!(row.exit_price, Some((0.5 * 110.0 + 1.5 * 95.0) / 2.0));
+	}
+
+	#[test]
+	fn exit_is_external_if_the_engine_did_not_see_most_of_it() {
+		let store = data_store();
+		let row = trade_row(&record(Side::Buy, &[("ts:p1", 0.5, 110.0)]), &[], &store).unwrap();
+		assert_eq!(row.exit_reason, "external");
+		let row = trade_row(&record(Side::Buy, &[]), &[], &store).unwrap();
+		assert_eq!(row.exit_reason, "external");
+		assert_eq!(row.exit_price, None);
+	}
+
+	#[test]
+	fn excursions_are_taken_against_the_side() {
+		let store = data_store();
+		let trade = |ms: i64, price: f64| TradeRecord {
+			t_ms: T0 + ms,
+			price,
+			qty: 1.0,
+			is_buyer_maker: false,
+		};
+		let symbol = record(Side::Buy, &[]).spec.symbol();
+		assert!(trade_row(&record(Side::Buy, &[]), &[], &store).unwrap().mae.is_none(), "nothing recorded yet");
+		// before the entry and after the close, so not counted
+		store.write(&symbol, vec![trade(500, 50.0), trade(2000, 90.0), trade(3000, 120.0), trade(6000, 200.0)]).unwrap();
+
+		let long = trade_row(&record(Side::Buy, &[]), &[], &store).unwrap();
+		assert_eq!((long.mae, long.mfe), (Some(0.1), Some(0.2)));
+		let short = trade_row(&record(Side::Sell, &[]), &[], &store).unwrap();
+		assert_eq!((short.mae, short.mfe), (Some(0.2), Some(0.1)));
+	}
+
+	#[test]
+	fn only_closed_positions_are_reported() {
+		let dir = std::env::temp_dir().join(format!("discretionary_engine_report_{}", Uuid::new_v4()));
+		std::fs::create_dir_all(&dir).unwrap();
+		// its data directory is next to the journals
+		DataStore::new(&dir).unwrap();
+
+		let closed = record(Side::Buy, &[("ts:p1", 2.0, 110.0)]);
+		let journal = Journal::create(&dir, &closed.spec).unwrap();
+		journal.append(JournalEvent::Protocols { followup: vec!["ts:p1".to_owned()] }).unwrap();
+		journal.append(JournalEvent::Closed { record: closed.clone() }).unwrap();
+		let open = record(Side::Sell, &[]);
+		Journal::create(&dir, &open.spec).unwrap().append(JournalEvent::Protocols { followup: vec![] }).unwrap();
+
+		let reported = closed_positions(&dir).unwrap();
+		assert_eq!(reported.len(), 1);
+		assert_eq!(reported[0].0.spec.id, closed.spec.id);
+		assert_eq!(reported[0].1, vec!["ts:p1".to_owned()]);
+	}
+}
@@ -0,0 +1,400 @@
+use crate::api::order_types::ConceptualOrder;
+use crate::api::{self, binance, Symbol};
+use crate::config::AppConfig;
+use crate::control::{self, ControlCommand};
+use crate::reconciler::{ExposureBook, Freeze};
+use anyhow::{bail, Result};
+use chrono::{NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Account-level guards. A limit that is not set is not enforced; all values are in USDT.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RiskLimits {
+	pub max_notional_per_asset: Option<f64>,
+	pub max_gross_notional: Option<f64>,
+	/// Gross notional over equity.
+	pub max_leverage: Option<f64>,
+	/// Drop of equity since the start of the UTC day, unrealised included. Deposits and withdrawals are not told apart.
+	pub max_daily_loss: Option<f64>,
+	/// On breach of `max_daily_loss`, close all positions too, instead of only cancelling entry orders.
+	pub flatten_on_daily_loss: bool,
+	pub check_interval_s: u64,
+}
+impl Default for RiskLimits {
+	fn default() -> Self {
+		Self {
+			max_notional_per_asset: None,
+			max_gross_notional: None,
+			max_leverage: None,
+			max_daily_loss: None,
+			flatten_on_daily_loss: false,
+			check_interval_s: 10,
+		}
+	}
+}
+
+#[derive(Debug, Default)]
+struct RiskSnapshot {
+	equity: Option<f64>,
+	/// Mark prices of everything the engine holds, by exchange symbol.
+	prices: HashMap<String, f64>,
+	daily_loss: f64,
+	daily_loss_breached: bool,
+}
+
+/// Checks exposure increases against `RiskLimits`, using the state from the last `refresh`.
+#[derive(Debug, Clone)]
+pub struct RiskGuard {
+	limits: RiskLimits,
+	exposure: ExposureBook,
+	freeze: Freeze,
+	day_start_path: PathBuf,
+	snapshot: Arc<Mutex<RiskSnapshot>>,
+}
+impl RiskGuard {
+	pub fn new(config: &AppConfig, exposure: ExposureBook, freeze: Freeze) -> Self {
+		Self {
+			limits: config.risk.clone(),
+			exposure,
+			freeze,
+			day_start_path: config.positions_dir.join("day_start_equity.json"),
+			snapshot: Arc::default(),
+		}
+	}
+
+	/// `symbol` as on the exchange, ex: `BTCUSDT`.
+	pub fn check_increase_usdt(&self, symbol: &str, increase_usdt: f64) -> Result<()> {
+		let snapshot = self.snapshot.lock().unwrap();
+		if snapshot.daily_loss_breached {
+			bail!("Daily loss limit breached ({:.2} USDT lost today)", snapshot.daily_loss);
+		}
+
+		let mut held: HashMap<String, f64> = HashMap::new();
+		// legs of a symbol in hedge mode are margined separately, so they add up
+		for ((s, _), qty) in self.exposure.by_leg() {
+			match snapshot.prices.get(&s) {
+				Some(price) => {
+					*held.entry(s).or_insert(0.0) += qty.abs() * price;
+				}
+				// only happens for a position opened since the last refresh
+				None => warn!(symbol = s, "No price to value held exposure at, ignoring it"),
+			}
+		}
+		let asset_after = held.get(symbol).unwrap_or(&0.0) + increase_usdt;
+		let gross_after = held.values().sum::<f64>() + increase_usdt;
+
+		if let Some(max) = self.limits.max_notional_per_asset {
+			if asset_after > max {
+				bail!("{} notional would be {:.2} USDT, over the limit of {:.2}", symbol, asset_after, max);
+			}
+		}
+		if let Some(max) = self.limits.max_gross_notional {
+			if gross_after > max {
+				bail!("Gross notional would be {:.2} USDT, over the limit of {:.2}", gross_after, max);
+			}
+		}
+		if let Some(max) = self.limits.max_leverage {
+			match snapshot.equity {
+				Some(equity) if equity > 0.0 =>
+					if gross_after / equity > max {
+						bail!("Leverage would be {:.2}, over the limit of {:.2}", gross_after / equity, max);
+					},
+				_ => bail!("Equity is not known yet, can't check leverage"),
+			}
+		}
+		Ok(())
+	}
+
+	/// Resting orders are valued at their price, market ones at the last known mark; see `price_at_mark` for symbols `refresh` has not got to yet.
+	pub fn check_order(&self, order: &ConceptualOrder) -> Result<()> {
+		let symbol = order.symbol().to_string();
+		let price = match order.price() {
+			Ok(p) => p,
+			Err(_) => match self.snapshot.lock().unwrap().prices.get(&symbol) {
+				Some(p) => *p,
+				None => bail!("No price for {} to check the order against", symbol),
+			},
+		};
+		self.check_increase_usdt(&symbol, order.notional() * price)
+	}
+
+	/// Prices the symbol right away, so that orders on it can be checked before the next `refresh`.
+	pub async fn price_at_mark(&self, symbol: &Symbol) -> Result<()> {
+		let mark = binance::futures_mark_price(&symbol.base).await?;
+		self.snapshot.lock().unwrap().prices.insert(symbol.to_string(), mark);
+		Ok(())
+	}
+
+	pub async fn refresh(&self, config: &AppConfig) -> Result<()> {
+		let mut prices = HashMap::new();
+		for symbol in self.exposure.by_symbol().into_keys() {
+			let asset = symbol.strip_suffix("USDT").unwrap_or(&symbol).to_owned();
+			prices.insert(symbol, binance::futures_mark_price(&asset).await?);
+		}
+		let (balance, unrealised) = tokio::try_join!(
+			api::compile_total_balance(config.clone()),
+			binance::get_futures_unrealised_pnl(config.binance.read_key.clone(), config.binance.read_secret.clone()),
+		)?;
+		if let Some(daily_loss) = self.update(balance + unrealised, prices)? {
+			self.on_daily_loss_breach(config, daily_loss).await?;
+		}
+		Ok(())
+	}
+
+	/// Takes in what `refresh` fetched. Returns the day's loss if it has just breached the limit.
+	fn update(&self, equity: f64, prices: HashMap<String, f64>) -> Result<Option<f64>> {
+		let daily_loss = day_start_equity(&self.day_start_path, equity)? - equity;
+		let mut snapshot = self.snapshot.lock().unwrap();
+		let breached = self.limits.max_daily_loss.map(|max| daily_loss > max).unwrap_or(false);
+		let newly_breached = breached && !snapshot.daily_loss_breached;
+		snapshot.equity = Some(equity);
+		// ones priced by `price_at_mark` are kept until held
+		snapshot.prices.extend(prices);
+		snapshot.daily_loss = daily_loss;
+		snapshot.daily_loss_breached = breached;
+		Ok(newly_breached.then_some(daily_loss))
+	}
+
+	async fn on_daily_loss_breach(&self, config: &AppConfig, daily_loss: f64) -> Result<()> {
+		error!(daily_loss, "Daily loss limit breached");
+		self.freeze.freeze(&format!("Daily loss of {:.2} USDT breached the limit", daily_loss))?;
+		cancel_entry_orders(config).await?;
+		if self.limits.flatten_on_daily_loss {
+			flatten_all(config).await?;
+		}
+		Ok(())
+	}
+}
+
+/// Refreshes the guard for as long as the process lives.
+pub fn spawn(config: AppConfig, guard: RiskGuard) -> tokio::task::JoinHandle<()> {
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(Duration::from_secs(config.risk.check_interval_s));
+		loop {
+			interval.tick().await;
+			if let Err(e) = guard.refresh(&config).await {
+				warn!("Refreshing risk state failed: {}", e);
+			}
+		}
+	})
+}
+
+#[derive(Serialize, Deserialize)]
+struct DayStart {
+	date: NaiveDate,
+	equity: f64,
+}
+
+/// Persisted, so that restarting the engine does not reset the day's loss.
+fn day_start_equity(path: &Path, equity_now: f64) -> Result<f64> {
+	let today = Utc::now().date_naive();
+	if let Some(day_start) = std::fs::read_to_string(path).ok().and_then(|s| serde_json::from_str::<DayStart>(&s).ok()) {
+		if day_start.date == today {
+			return Ok(day_start.equity);
+		}
+	}
+	std::fs::write(path, serde_json::to_string(&DayStart { date: today, equity: equity_now })?)?;
+	Ok(equity_now)
+}
+
+/// Entry orders are all that are neither stops nor reduce-only. Stops can't be told by the flag alone: it is not sent in hedge mode, nor on one-way legs shared by opposite positions.
+pub async fn cancel_entry_orders(config: &AppConfig) -> Result<()> {
+	let (key, secret) = (config.binance.full_key.clone(), config.binance.full_secret.clone());
+	let mut failed = Vec::new();
+	for order in binance::get_futures_open_orders(key.clone(), secret.clone()).await? {
+		if order.reduceOnly.as_bool() == Some(true) || order.closePosition.as_bool() == Some(true) || order.r#type.starts_with("STOP") {
+			continue;
+		}
+		match binance::cancel_futures_order(key.clone(), secret.clone(), order.symbol.clone(), order.orderId).await {
+			Ok(()) => info!(symbol = order.symbol, order_id = order.orderId, "Cancelled entry order"),
+			Err(e) => failed.push(format!("{} {}: {}", order.symbol, order.orderId, e)),
+		}
+	}
+	if !failed.is_empty() {
+		bail!("Failed to cancel some entry orders:\n{}", failed.join("\n"));
+	}
+	Ok(())
+}
+
+/// How long `flatten_all` waits for followed positions to close themselves.
+const CLOSE_FOLLOWED_WAIT: Duration = Duration::from_secs(30);
+
+/// Has every followed position market out by itself, as they would otherwise keep re-placing their orders, and closes of their own on top of `flatten_all`'s would overshoot.
+async fn close_followed(config: &AppConfig) {
+	let mut followed = control::followed(&config.positions_dir).await;
+	for position_id in &followed {
+		if let Err(e) = control::send(&config.positions_dir, *position_id, &ControlCommand::CloseAll).await {
+			warn!(%position_id, "Failed to have the position close itself: {}", e);
+		}
+	}
+	let deadline = tokio::time::Instant::now() + CLOSE_FOLLOWED_WAIT;
+	while !followed.is_empty() && tokio::time::Instant::now() < deadline {
+		tokio::time::sleep(Duration::from_millis(500)).await;
+		let followed_now = control::followed(&config.positions_dir).await;
+		followed.retain(|id| followed_now.contains(id));
+	}
+	if !followed.is_empty() {
+		warn!(?followed, "Positions are still closing, flattening under them");
+	}
+}
+
+/// Closes followed positions through their control sockets, then cancels every open order and closes every position on the account, engine's or not. Keeps going past failures, to do as much as possible.
+pub async fn flatten_all(config: &AppConfig) -> Result<()> {
+	close_followed(config).await;
+	let (key, secret) = (config.binance.full_key.clone(), config.binance.full_secret.clone());
+	let (positions, open_orders) = tokio::try_join!(
+		binance::get_futures_positions(key.clone(), secret.clone()),
+		binance::get_futures_open_orders(key.clone(), secret.clone()),
+	)?;
+
+	let mut failed = Vec::new();
+	let symbols: HashSet<String> = open_orders.into_iter().map(|o| o.symbol).collect();
+	for symbol in symbols {
+		if let Err(e) = binance::cancel_all_futures_orders(key.clone(), secret.clone(), symbol.clone()).await {
+			failed.push(format!("cancelling orders on {}: {}", symbol, e));
+		}
+	}
+	for ((symbol, position_side), position_amt) in positions {
+		if position_amt == 0.0 {
+			continue;
+		}
+		match binance::close_futures_position(key.clone(), secret.clone(), symbol.clone(), position_side, position_amt).await {
+			Ok(order_id) => info!(symbol, %position_side, position_amt, order_id, "Closed position"),
+			Err(e) => failed.push(format!("closing {} {} {}: {}", position_amt, symbol, position_side, e)),
+		}
+	}
+	if !failed.is_empty() {
+		bail!("Failed to flatten everything:\n{}", failed.join("\n"));
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::binance::PositionSide;
+	use crate::api::order_types::{ConceptualLimit, ConceptualMarket, ProtocolOrderId};
+	use uuid::Uuid;
+	use v_utils::trades::Side;
+
+	fn guard(limits: RiskLimits) -> RiskGuard {
+		let mut config = AppConfig::test();
+		config.positions_dir = std::env::temp_dir().join(format!("discretionary_engine_risk_{}", Uuid::new_v4()));
+		std::fs::create_dir_all(&config.positions_dir).unwrap();
+		config.risk = limits;
+		let exposure = ExposureBook::default();
+		exposure.set_position(Uuid::new_v4(), "BTCUSDT".to_owned(), PositionSide::Long, 0.1);
+		exposure.set_position(Uuid::new_v4(), "BTCUSDT".to_owned(), PositionSide::Short, -0.05);
+		exposure.set_position(Uuid::new_v4(), "ETHUSDT".to_owned(), PositionSide::Both, 1.0);
+		let guard = RiskGuard::new(&config, exposure, Freeze::new(&config.positions_dir));
+		// 0.15 BTC at 60000 and 1 ETH at 3000, so 9000 + 3000 held
+		let prices = HashMap::from([("BTCUSDT".to_owned(), 60000.0), ("ETHUSDT".to_owned(), 3000.0)]);
+		guard.update(10000.0, prices).unwrap();
+		guard
+	}
+
+	fn market(base: &str, qty_notional: f64) -> ConceptualOrder {
+		ConceptualOrder::Market(ConceptualMarket {
+			id: ProtocolOrderId::new("test".to_owned(), Uuid::new_v4()),
+			maximum_slippage_percent: 1.0,
+			symbol: format!("{base}-USDT-BinanceFutures").parse().unwrap(),
+			side: Side::Buy,
+			qty_notional,
+		})
+	}
+
+	#[test]
+	fn increases_are_checked_against_every_limit() {
+		let per_asset = guard(RiskLimits {
+			max_notional_per_asset: Some(10000.0),
+			..Default::default()
+		});
+		assert!(per_asset.check_increase_usdt("BTCUSDT", 1000.0).is_ok());
+		assert!(per_asset.check_increase_usdt("BTCUSDT", 1001.0).is_err());
+		assert!(per_asset.check_increase_usdt("ETHUSDT", 7000.0).is_ok());
+
+		let gross = guard(RiskLimits {
+			max_gross_notional: Some(15000.0),
+			..Default::default()
+		});
+		assert!(gross.check_increase_usdt("SOLUSDT", 3000.0).is_ok());
+		assert!(gross.check_increase_usdt("SOLUSDT", 3001.0).is_err());
+
+		let leverage = guard(RiskLimits {
+			max_leverage: Some(2.0),
+			..Default::default()
+		});
+		assert!(leverage.check_increase_usdt("SOLUSDT", 8000.0).is_ok());
+		assert!(leverage.check_increase_usdt("SOLUSDT", 8001.0).is_err());
+	}
+
+	#[test]
+	fn leverage_is_not_guessed_without_equity() {
+		let guard = guard(RiskLimits {
+			max_leverage: Some(2.0),
+			..Default::default()
+		});
+		guard.snapshot.lock().unwrap().equity = None;
+		assert!(guard.check_increase_usdt("SOLUSDT", 1.0).is_err());
+	}
+
+	#[test]
+	fn orders_are_valued_at_their_price_or_the_mark() {
+		let guard = guard(RiskLimits {
+			max_notional_per_asset: Some(10000.0),
+			..Default::default()
+		});
+		let limit = |price: f64| {
+			ConceptualOrder::Limit(ConceptualLimit {
+				id: ProtocolOrderId::new("test".to_owned(), Uuid::new_v4()),
+				symbol: "BTC-USDT-BinanceFutures".parse().unwrap(),
+				side: Side::Buy,
+				price,
+				qty_notional: 0.02,
+				limit_only: false,
+			})
+		};
+		assert!(guard.check_order(&limit(50000.0)).is_ok());
+		assert!(guard.check_order(&limit(60000.0)).is_err());
+		assert!(guard.check_order(&market("BTC", 0.016)).is_ok());
+		assert!(guard.check_order(&market("BTC", 0.017)).is_err());
+
+		// not held, so unknown to `refresh` until priced at the mark
+		assert!(guard.check_order(&market("SOL", 1.0)).is_err());
+		guard.snapshot.lock().unwrap().prices.insert("SOLUSDT".to_owned(), 150.0);
+		assert!(guard.check_order(&market("SOL", 1.0)).is_ok());
+		guard.update(10000.0, HashMap::new()).unwrap();
+		assert!(guard.check_order(&market("SOL", 1.0)).is_ok(), "the mark is kept through refreshes");
+	}
+
+	#[test]
+	fn daily_loss_is_counted_from_the_start_of_day_equity() {
+		let guard = guard(RiskLimits {
+			max_daily_loss: Some(500.0),
+			..Default::default()
+		});
+		// the day started at 10000, when `guard` first updated
+		assert_eq!(guard.update(9600.0, HashMap::new()).unwrap(), None);
+		assert!(guard.check_increase_usdt("BTCUSDT", 1.0).is_ok());
+
+		assert_eq!(guard.update(9400.0, HashMap::new()).unwrap(), Some(600.0));
+		assert!(guard.check_increase_usdt("BTCUSDT", 1.0).is_err());
+		assert_eq!(guard.update(9300.0, HashMap::new()).unwrap(), None, "breach is only reported once");
+
+		// a restart keeps the day's start
+		let restarted = RiskGuard {
+			snapshot: Arc::default(),
+			..guard.clone()
+		};
+		assert_eq!(restarted.update(9300.0, HashMap::new()).unwrap(), Some(700.0));
+
+		assert_eq!(guard.update(9900.0, HashMap::new()).unwrap(), None);
+		assert!(guard.check_increase_usdt("BTCUSDT", 1.0).is_ok(), "recovering lifts the refusal");
+	}
+}
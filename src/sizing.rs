@@ -0,0 +1,117 @@
+use crate::protocols::{FollowupProtocol, ProtocolType};
+use anyhow::{bail, Result};
+use v_utils::trades::Side;
+
+/// How the size of a new position is determined. Sign of the value gives the side in all of them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SizingMode {
+	/// Fraction of the total balance.
+	BalanceFraction(f64),
+	/// Fixed notional, in USDT.
+	Usdt(f64),
+	/// Fraction of the total balance to be lost if the initial stop is hit.
+	Risk(f64),
+}
+impl SizingMode {
+	fn signed(&self) -> f64 {
+		match self {
+			SizingMode::BalanceFraction(v) | SizingMode::Usdt(v) | SizingMode::Risk(v) => *v,
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct Sizing {
+	pub side: Side,
+	pub size_usdt: f64,
+	/// Fraction of the entry price to the initial stop, if any protocol places one.
+	pub stop_distance: Option<f64>,
+	/// What hitting the initial stop would cost, fees and slippage aside.
+	pub risk_usdt: Option<f64>,
+}
+impl std::fmt::Display for Sizing {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{} {:.2} USDT", self.side, self.size_usdt)?;
+		match (self.stop_distance, self.risk_usdt) {
+			(Some(distance), Some(risk)) => write!(f, ", initial stop {:.2}% away, risking {:.2} USDT", distance * 100.0, risk),
+			_ => write!(f, ", no initial stop"),
+		}
+	}
+}
+
+/// Stops of the `SL` protocols if there are any, otherwise of all protocols. The closest one is what gets hit first.
+pub fn initial_stop_distance(protocols: &[FollowupProtocol]) -> Option<f64> {
+	let closest = |sl_only: bool| {
+		protocols
+			.iter()
+			.filter(|p| !sl_only || p.get_subtype() == ProtocolType::SL)
+			.filter_map(|p| p.initial_stop_distance())
+			.fold(None, |acc: Option<f64>, d| Some(acc.map_or(d, |a| a.min(d))))
+	};
+	closest(true).or_else(|| closest(false))
+}
+
+pub fn compute(mode: SizingMode, total_balance: f64, protocols: &[FollowupProtocol]) -> Result<Sizing> {
+	let side = match mode.signed() {
+		v if v > 0.0 => Side::Buy,
+		v if v < 0.0 => Side::Sell,
+		_ => bail!("Size must be non-zero"),
+	};
+	let stop_distance = initial_stop_distance(protocols);
+	let size_usdt = match mode {
+		SizingMode::BalanceFraction(fraction) => fraction.abs() * total_balance,
+		SizingMode::Usdt(usdt) => usdt.abs(),
+		SizingMode::Risk(risk) => match stop_distance {
+			Some(distance) if distance > 0.0 => risk.abs() * total_balance / distance,
+			_ => bail!("Risk-based sizing requires a followup protocol that places an initial stop"),
+		},
+	};
+	Ok(Sizing {
+		side,
+		size_usdt,
+		stop_distance,
+		risk_usdt: stop_distance.map(|d| size_usdt * d),
+	})
+}
+
+/// Accepts both `0.5%` and `0.005`.
+pub fn parse_fraction(s: &str) -> Result<f64, String> {
+	let (number, divisor) = match s.trim().strip_suffix('%') {
+		Some(n) => (n, 100.0),
+		None => (s.trim(), 1.0),
+	};
+	number.parse::<f64>().map(|v| v / divisor).map_err(|e| format!("{s}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn fractions() {
+		assert_eq!(parse_fraction("0.005"), Ok(0.005));
+		assert_eq!(parse_fraction("0.5%"), Ok(0.005));
+		assert_eq!(parse_fraction(" -2% "), Ok(-0.02));
+		assert!(parse_fraction("%").is_err());
+		assert!(parse_fraction("half").is_err());
+	}
+
+	#[test]
+	fn sign_gives_the_side() {
+		let sizing = compute(SizingMode::Usdt(-100.0), 1000.0, &[]).unwrap();
+		assert_eq!((sizing.side, sizing.size_usdt), (Side::Sell, 100.0));
+		let sizing = compute(SizingMode::BalanceFraction(0.25), 1000.0, &[]).unwrap();
+		assert_eq!((sizing.side, sizing.size_usdt), (Side::Buy, 250.0));
+		assert!(compute(SizingMode::Usdt(0.0), 1000.0, &[]).is_err());
+	}
+
+	#[test]
+	fn risk_is_sized_off_the_initial_stop() {
+		assert!(compute(SizingMode::Risk(0.01), 1000.0, &[]).is_err());
+
+		let protocols = [FollowupProtocol::from_str("ts:p0.02").unwrap()];
+		let sizing = compute(SizingMode::Risk(0.01), 1000.0, &protocols).unwrap();
+		assert!((sizing.size_usdt - 500.0).abs() < 1e-9);
+		assert!((sizing.risk_usdt.unwrap() - 10.0).abs() < 1e-9);
+	}
+}
@@ -0,0 +1,219 @@
+use crate::api::order_types::{ConceptualMarket, ConceptualOrder, ConceptualStopMarket};
+use crate::api::Symbol;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_tungstenite::connect_async;
+use tracing::{info, warn};
+use uuid::Uuid;
+use v_utils::trades::Side;
+
+/// How the stops requested by protocols are expressed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum StopMode {
+	/// Rested on the exchange as STOP_MARKET. Protects even if the engine dies, but shows our levels.
+	#[default]
+	Native,
+	/// Watched by the engine, which sends a market order itself once the price crosses the stop.
+	Synthetic,
+	/// Synthetic, plus a native one further away for if the engine is not around to fire it.
+	Hybrid,
+}
+
+/// Expresses stop orders of one position according to its `StopMode`. Clones share the state.
+#[derive(Clone, Debug)]
+pub struct StopExpression {
+	mode: StopMode,
+	/// How much further, as a fraction of the price, the native disaster stop of `Hybrid` is.
+	disaster_distance: f64,
+	price: Option<watch::Receiver<Option<f64>>>,
+	/// Side and price of the synthetic stops, by uuid of the protocol order.
+	watched: Arc<Mutex<HashMap<Uuid, (Side, f64)>>>,
+	/// Once crossed, a stop stays triggered until it is filled, even if the price comes back.
+	triggered: Arc<Mutex<HashSet<Uuid>>>,
+}
+impl StopExpression {
+	/// Starts following the price of `symbol`, unless `mode` is `Native`.
+	pub fn new(mode: StopMode, disaster_distance: f64, symbol: &Symbol) -> Self {
+		Self {
+			mode,
+			disaster_distance,
+			price: (mode != StopMode::Native).then(|| spawn_price_feed(symbol)),
+			watched: Arc::default(),
+			triggered: Arc::default(),
+		}
+	}
+
+	/// Splits the protocols' stops into market orders of the triggered synthetic ones, and what is to rest on the exchange.
+	pub fn express(&self, stops: Vec<ConceptualOrder>) -> (Vec<ConceptualOrder>, Vec<ConceptualOrder>) {
+		if self.mode == StopMode::Native {
+			return (Vec::new(), stops);
+		}
+		let mut watched = self.watched.lock().unwrap();
+		let triggered = self.triggered.lock().unwrap();
+		watched.clear();
+
+		let (mut market, mut native) = (Vec::new(), Vec::new());
+		for order in stops {
+			let ConceptualOrder::StopMarket(stop) = order else {
+				native.push(order);
+				continue;
+			};
+			// the disaster stop goes with the trigger, as sharing the id with the market close, their fills could not be told apart
+			if triggered.contains(&stop.id.uuid) {
+				market.push(ConceptualOrder::Market(ConceptualMarket {
+					id: stop.id.clone(),
+					maximum_slippage_percent: stop.maximum_slippage_percent,
+					symbol: stop.symbol.clone(),
+					side: stop.side.clone(),
+					qty_notional: stop.qty_notional,
+				}));
+				continue;
+			}
+			watched.insert(stop.id.uuid, (stop.side.clone(), stop.price));
+			if self.mode == StopMode::Hybrid {
+				let price = match stop.side {
+					Side::Sell => stop.price * (1.0 - self.disaster_distance),
+					Side::Buy => stop.price * (1.0 + self.disaster_distance),
+				};
+				native.push(ConceptualOrder::StopMarket(ConceptualStopMarket { price, ..stop }));
+			}
+		}
+		(market, native)
+	}
+
+	/// Resolves once the price crosses any of the watched stops; never, if there is nothing to watch. Cancel-safe.
+	pub async fn next_trigger(&mut self) {
+		let Some(price) = self.price.as_mut() else {
+			return std::future::pending().await;
+		};
+		loop {
+			if price.changed().await.is_err() {
+				return std::future::pending().await;
+			}
+			let Some(p) = *price.borrow_and_update() else { continue };
+			let mut watched = self.watched.lock().unwrap();
+			let crossed: Vec<Uuid> = watched
+				.iter()
+				.filter(|(_, (side, stop))| match side {
+					Side::Sell => p <= *stop,
+					Side::Buy => p >= *stop,
+				})
+				.map(|(uuid, _)| *uuid)
+				.collect();
+			if crossed.is_empty() {
+				continue;
+			}
+			for uuid in crossed {
+				info!(%uuid, price = p, "Synthetic stop triggered");
+				watched.remove(&uuid);
+				self.triggered.lock().unwrap().insert(uuid);
+			}
+			return;
+		}
+	}
+}
+
+/// Last trade price, for as long as anyone is listening. Reconnects on its own.
+fn spawn_price_feed(symbol: &Symbol) -> watch::Receiver<Option<f64>> {
+	let (tx, rx) = watch::channel(None);
+	let address = format!("wss://fstream.binance.com/ws/{}@aggTrade", symbol.to_string().to_lowercase());
+	tokio::spawn(async move {
+		while !tx.is_closed() {
+			let (ws_stream, _) = match connect_async(url::Url::parse(&address).unwrap()).await {
+				Ok(s) => s,
+				Err(e) => {
+					warn!("Price feed for synthetic stops failed to connect: {}", e);
+					tokio::time::sleep(Duration::from_secs(1)).await;
+					continue;
+				}
+			};
+			let (_, mut read) = ws_stream.split();
+			while let Some(msg) = read.next().await {
+				let Ok(msg) = msg else { break };
+				let price = serde_json::from_slice::<Value>(&msg.into_data())
+					.ok()
+					.and_then(|json| json.get("p").and_then(|p| p.as_str()).and_then(|p| p.parse::<f64>().ok()));
+				if let Some(price) = price {
+					if tx.send(Some(price)).is_err() {
+						return;
+					}
+				}
+			}
+			warn!("Price feed for synthetic stops disconnected, reconnecting");
+		}
+	});
+	rx
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::order_types::ProtocolOrderId;
+
+	fn expression(mode: StopMode) -> StopExpression {
+		StopExpression {
+			mode,
+			disaster_distance: 0.05,
+			price: None,
+			watched: Arc::default(),
+			triggered: Arc::default(),
+		}
+	}
+
+	fn stop(uuid: Uuid) -> ConceptualOrder {
+		ConceptualOrder::StopMarket(ConceptualStopMarket {
+			id: ProtocolOrderId::new("test".to_owned(), uuid),
+			maximum_slippage_percent: 1.0,
+			symbol: "BTC-USDT-BinanceFutures".parse().unwrap(),
+			side: Side::Sell,
+			price: 60_000.0,
+			qty_notional: 0.01,
+		})
+	}
+
+	#[test]
+	fn native_rests_as_is() {
+		let (market, native) = expression(StopMode::Native).express(vec![stop(Uuid::new_v4())]);
+		assert!(market.is_empty());
+		assert_eq!(native.len(), 1);
+	}
+
+	#[test]
+	fn synthetic_is_only_watched() {
+		let e = expression(StopMode::Synthetic);
+		let uuid = Uuid::new_v4();
+		let (market, native) = e.express(vec![stop(uuid)]);
+		assert!(market.is_empty() && native.is_empty());
+		assert_eq!(e.watched.lock().unwrap().get(&uuid).map(|(_, p)| *p), Some(60_000.0));
+	}
+
+	#[test]
+	fn hybrid_rests_a_disaster_stop_further_out() {
+		let (market, native) = expression(StopMode::Hybrid).express(vec![stop(Uuid::new_v4())]);
+		assert!(market.is_empty());
+		let [ConceptualOrder::StopMarket(disaster)] = native.as_slice() else {
+			panic!("expected one stop, got {native:?}");
+		};
+		assert!((disaster.price - 57_000.0).abs() < 1e-6);
+	}
+
+	#[test]
+	fn triggered_hybrid_closes_at_market_without_the_disaster_stop() {
+		let e = expression(StopMode::Hybrid);
+		let uuid = Uuid::new_v4();
+		e.triggered.lock().unwrap().insert(uuid);
+		let (market, native) = e.express(vec![stop(uuid)]);
+		let [ConceptualOrder::Market(close)] = market.as_slice() else {
+			panic!("expected one market order, got {market:?}");
+		};
+		assert_eq!(close.id.uuid, uuid);
+		assert!(native.is_empty());
+		assert!(e.watched.lock().unwrap().is_empty());
+	}
+}
@@ -9,3 +9,12 @@ pub fn init_subscriber() {
 
 	set_global_default(subscriber).expect("Failed to set subscriber");
 }
+
+/// Asks on stdin; anything but `y` is a no.
+pub fn confirm(prompt: &str) -> bool {
+	use std::io::Write;
+	print!("{prompt} [y/N] ");
+	let _ = std::io::stdout().flush();
+	let mut answer = String::new();
+	std::io::stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
+}